@@ -0,0 +1,313 @@
+//Decision latency while the admin API runs heavy reads and the query log keeps growing,
+//run with `cargo bench -p hmdl-db --bench concurrent_load`.
+//
+//Compares the old layout, one pool with an exclusive lock doing everything, with the
+//WAL layout where decisions read from their own pool and one task batches the writes.
+//HMDL_BENCH_SECS sets how long each layout runs for, 10 seconds if unset.
+
+use chrono::Utc;
+use hmdl_db::{
+    dao::{domains, memberships},
+    DatabaseHandle,
+};
+use sqlx::{
+    query,
+    sqlite::{
+        SqliteConnectOptions, SqliteLockingMode::Exclusive, SqlitePoolOptions,
+        SqliteSynchronous::Normal,
+    },
+    Error, SqlitePool,
+};
+use std::{
+    env, fs,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+const CLIENTS: usize = 20;
+const DOMAINS: usize = 5_000;
+const LOGGED_QUERIES: usize = 200_000;
+const DECIDERS: usize = 8;
+const ADMIN_READERS: usize = 2;
+const BATCH_SIZE: usize = 500;
+
+const LOG_QUERY: &str = r#"
+    INSERT INTO query_log (
+        query_time, client_ip, domain_name, record_type, decision, dnssec_status, response_code
+    ) VALUES (
+        ?1, ?2, ?3, 'A', 'Allow', 'Unchecked', 0
+    )
+"#;
+//What the query log page and the tunnel analyzer ask for, a scan of the whole log
+const ADMIN_REPORT: &str = r#"
+    SELECT client_ip, domain_name, count(*) AS hits
+    FROM query_log
+    GROUP BY client_ip, domain_name
+    ORDER BY hits DESC
+    LIMIT 100
+"#;
+
+#[derive(Clone, Copy, Debug)]
+enum Layout {
+    Exclusive,
+    Wal,
+}
+
+struct Report {
+    decisions: Vec<Duration>,
+    admin_reports: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let run_for = Duration::from_secs(
+        env::var("HMDL_BENCH_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10),
+    );
+
+    println!(
+        "{} deciders and {} admin readers for {:?}, {} logged queries to start",
+        DECIDERS, ADMIN_READERS, run_for, LOGGED_QUERIES
+    );
+    println!(
+        "{:<10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+        "layout", "decisions", "p50", "p99", "max", "reports"
+    );
+
+    for layout in [Layout::Exclusive, Layout::Wal] {
+        let path =
+            env::temp_dir().join(format!("hmdl-bench-{}-{:?}.db", std::process::id(), layout));
+        let path_str = path.to_string_lossy().to_string();
+
+        let mut report = run(layout, &path_str, run_for).await?;
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path_str, suffix));
+        }
+
+        report.decisions.sort();
+        let percentile = |p: usize| {
+            report
+                .decisions
+                .get(report.decisions.len() * p / 100)
+                .copied()
+                .unwrap_or_default()
+        };
+        println!(
+            "{:<10} {:>10} {:>10.2?} {:>10.2?} {:>10.2?} {:>8}",
+            format!("{:?}", layout),
+            report.decisions.len(),
+            percentile(50),
+            percentile(99),
+            report.decisions.last().copied().unwrap_or_default(),
+            report.admin_reports
+        );
+    }
+
+    Ok(())
+}
+
+async fn run(layout: Layout, path: &str, run_for: Duration) -> Result<Report, Error> {
+    let writer = DatabaseHandle::create(path).await?;
+    seed(&writer).await?;
+
+    //The old layout, every read and write shares the one locked pool
+    let (reader, writer) = match layout {
+        Layout::Exclusive => {
+            writer.close().await;
+            let con_opts = SqliteConnectOptions::from_str(&format!("sqlite://{}", path))?
+                .foreign_keys(true)
+                .locking_mode(Exclusive)
+                .shared_cache(true)
+                .synchronous(Normal);
+            let pool = SqlitePoolOptions::new()
+                .min_connections(2)
+                .connect_with(con_opts)
+                .await?;
+            (pool.clone(), pool)
+        }
+        Layout::Wal => (DatabaseHandle::create_reader(path).await?, writer),
+    };
+
+    let deadline = Instant::now() + run_for;
+    let (queue, batches) = mpsc::channel(10_000);
+    let batch_writer =
+        matches!(layout, Layout::Wal).then(|| tokio::spawn(write_batches(writer.clone(), batches)));
+
+    let deciders: Vec<_> = (0..DECIDERS)
+        .map(|n| {
+            let (reader, writer) = (reader.clone(), writer.clone());
+            let queue = matches!(layout, Layout::Wal).then(|| queue.clone());
+            tokio::spawn(async move {
+                let mut timings = Vec::new();
+                let mut i = n;
+                while Instant::now() < deadline {
+                    let client = format!("192.168.1.{}", i % CLIENTS);
+                    let domain = format!("www.site{}.example.", i % DOMAINS);
+                    let started = Instant::now();
+                    decide(&reader, &writer, queue.as_ref(), client, domain).await?;
+                    timings.push(started.elapsed());
+                    i += DECIDERS;
+                }
+                Ok::<_, Error>(timings)
+            })
+        })
+        .collect();
+    drop(queue);
+
+    let admins: Vec<_> = (0..ADMIN_READERS)
+        .map(|_| {
+            let reader = reader.clone();
+            tokio::spawn(async move {
+                let mut reports = 0;
+                while Instant::now() < deadline {
+                    query(ADMIN_REPORT).fetch_all(&reader).await?;
+                    reports += 1;
+                }
+                Ok::<_, Error>(reports)
+            })
+        })
+        .collect();
+
+    let mut report = Report {
+        decisions: Vec::new(),
+        admin_reports: 0,
+    };
+    for decider in deciders {
+        report
+            .decisions
+            .extend(decider.await.expect("decider panicked")?);
+    }
+    for admin in admins {
+        report.admin_reports += admin.await.expect("admin reader panicked")?;
+    }
+    if let Some(batch_writer) = batch_writer {
+        batch_writer.await.expect("writer panicked")?;
+    }
+
+    reader.close().await;
+    writer.close().await;
+    Ok(report)
+}
+
+/// The decider's own DAO calls plus the query log, the old layout writes inline before
+/// answering and the WAL layout only reads and hands the writes to the queue
+async fn decide(
+    reader: &SqlitePool,
+    writer: &SqlitePool,
+    queue: Option<&mpsc::Sender<(String, String)>>,
+    client: String,
+    domain: String,
+) -> Result<(), Error> {
+    let name = domains::resolve(reader, &domain).await?;
+
+    if queue.is_none() {
+        domains::touch(writer, &name, Utc::now(), &client).await?;
+    }
+
+    memberships::blocks(reader, &name, &client).await?;
+
+    match queue {
+        Some(queue) => {
+            let _ = queue.try_send((client, name));
+        }
+        None => {
+            query(LOG_QUERY)
+                .bind(Utc::now())
+                .bind(&client)
+                .bind(&name)
+                .execute(writer)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_batches(
+    writer: SqlitePool,
+    mut batches: mpsc::Receiver<(String, String)>,
+) -> Result<(), Error> {
+    while let Some(first) = batches.recv().await {
+        let mut tx = writer.begin().await?;
+        let mut next = Some(first);
+        let mut written = 0;
+        while let Some((client, domain)) = next.take() {
+            domains::touch(&mut *tx, &domain, Utc::now(), &client).await?;
+            query(LOG_QUERY)
+                .bind(Utc::now())
+                .bind(&client)
+                .bind(&domain)
+                .execute(&mut tx)
+                .await?;
+            written += 1;
+            if written < BATCH_SIZE {
+                next = batches.try_recv().ok();
+            }
+        }
+        tx.commit().await?;
+    }
+
+    Ok(())
+}
+
+/// Half the clients are in a group that blocks every tenth domain
+async fn seed(pool: &SqlitePool) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+    let now = Utc::now();
+
+    query("INSERT INTO client_groups (name) VALUES ('kids')")
+        .execute(&mut tx)
+        .await?;
+    query("INSERT INTO domain_groups (name) VALUES ('blocked')")
+        .execute(&mut tx)
+        .await?;
+    query("INSERT INTO groups_applied VALUES ('kids', 'blocked')")
+        .execute(&mut tx)
+        .await?;
+
+    for c in 0..CLIENTS {
+        let name = format!("client{}", c);
+        query("INSERT INTO clients (name, ip, mac) VALUES (?1, ?2, ?3)")
+            .bind(&name)
+            .bind(format!("192.168.1.{}", c))
+            .bind(format!("00:00:00:00:00:{:02x}", c))
+            .execute(&mut tx)
+            .await?;
+        if c % 2 == 0 {
+            query("INSERT INTO client_group_member VALUES (?1, 'kids')")
+                .bind(&name)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+
+    for d in 0..DOMAINS {
+        let name = format!("site{}.example.", d);
+        query("INSERT INTO known_domains (name, last_seen, last_client) VALUES (?1, ?2, 'seed')")
+            .bind(&name)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+        if d % 10 == 0 {
+            query("INSERT INTO domain_group_member VALUES (?1, 'blocked', true, NULL)")
+                .bind(&name)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+
+    for q in 0..LOGGED_QUERIES {
+        query(LOG_QUERY)
+            .bind(now)
+            .bind(format!("192.168.1.{}", q % CLIENTS))
+            .bind(format!("site{}.example.", q % DOMAINS))
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await
+}
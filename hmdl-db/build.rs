@@ -1,4 +1,4 @@
-use sqlx::sqlite::SqliteLockingMode::Exclusive;
+use sqlx::sqlite::SqliteJournalMode::Wal;
 use sqlx::sqlite::SqliteSynchronous::Normal;
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use std::{env, str::FromStr};
@@ -16,8 +16,7 @@ async fn main() {
         .unwrap()
         .create_if_missing(true)
         .foreign_keys(true)
-        .locking_mode(Exclusive)
-        .shared_cache(true)
+        .journal_mode(Wal)
         .synchronous(Normal);
 
     let pool_opts = SqlitePoolOptions::new().min_connections(2);
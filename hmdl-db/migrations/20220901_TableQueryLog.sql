@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS query_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    query_time DATETIME NOT NULL,
+    client_ip text NOT NULL,
+    domain_name text NOT NULL,
+    record_type text NOT NULL,
+    decision text NOT NULL,
+    dnssec_status text NOT NULL,
+    response_code INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS query_log_time ON query_log (query_time);
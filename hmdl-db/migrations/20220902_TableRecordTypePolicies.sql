@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS record_type_policies (
+    client_group_name TEXT NOT NULL,
+    record_type TEXT NOT NULL,
+    action TEXT NOT NULL,
+    PRIMARY KEY (client_group_name, record_type),
+    FOREIGN KEY(client_group_name) REFERENCES client_groups(name) ON DELETE CASCADE ON UPDATE CASCADE
+);
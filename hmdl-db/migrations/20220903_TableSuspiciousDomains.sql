@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS suspicious_domains (
+    name text NOT NULL,
+    score FLOAT NOT NULL,
+    reason text NOT NULL,
+    first_flagged DATETIME NOT NULL,
+    last_flagged DATETIME NOT NULL,
+    status text NOT NULL DEFAULT 'PENDING',
+    PRIMARY KEY (name)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    changed_at DATETIME NOT NULL,
+    user_name text NOT NULL,
+    action text NOT NULL,
+    target text NOT NULL,
+    before_json text,
+    after_json text
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user ON audit_log (user_name);
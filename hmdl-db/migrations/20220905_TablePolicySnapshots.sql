@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS policy_snapshots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    taken_at DATETIME NOT NULL,
+    reason text NOT NULL,
+    policy_json text NOT NULL
+);
@@ -1,2 +1,14 @@
+pub mod acme_persist;
+pub mod audit;
+pub mod client_groups;
+pub mod clients;
+pub mod domain_groups;
+pub mod domains;
+pub mod groups_applied;
+pub mod memberships;
+pub mod policy;
+pub mod record_type_policies;
 pub mod roles;
+pub mod settings;
+pub mod snapshots;
 pub mod users;
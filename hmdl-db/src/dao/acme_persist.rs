@@ -0,0 +1,59 @@
+use sqlx::{query, sqlite::SqliteQueryResult};
+
+/// What acme-lib asks to keep, account keys and certificates by its own key names
+pub async fn find(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    key: &str,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT acme_value
+        FROM acme_persist
+        WHERE acme_key = ?1
+        "#,
+        key
+    )
+    .map(|x| x.acme_value)
+    .fetch_optional(exec)
+    .await
+}
+
+pub async fn upsert(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    key: &str,
+    value: &[u8],
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO acme_persist (acme_key, acme_value) VALUES (?1, ?2)
+        ON CONFLICT (acme_key) DO UPDATE SET acme_value = ?2
+        "#,
+        key,
+        value
+    )
+    .execute(exec)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DatabaseHandle;
+
+    #[tokio::test]
+    async fn test_acme_persist() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        assert_eq!(find(&pool, "acct_privkey").await?, None);
+
+        upsert(&pool, "acct_privkey", b"first").await?;
+        upsert(&pool, "acct_privkey", b"second").await?;
+        upsert(&pool, "crt_hmdl.example.com", b"cert").await?;
+
+        assert_eq!(find(&pool, "acct_privkey").await?, Some(b"second".to_vec()));
+        assert_eq!(
+            find(&pool, "crt_hmdl.example.com").await?,
+            Some(b"cert".to_vec())
+        );
+        Ok(())
+    }
+}
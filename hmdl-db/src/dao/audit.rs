@@ -0,0 +1,140 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{query, query_as};
+
+/// A change an admin made, `before` and `after` are whatever the change touched
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub changed_at: NaiveDateTime,
+    pub user_name: String,
+    pub action: String,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// What is about to be recorded, `action` reads like `client.delete`
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub action: &'static str,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl Change {
+    pub fn new(action: &'static str, target: impl Into<String>) -> Self {
+        Self {
+            action,
+            target: target.into(),
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn before(mut self, before: impl Serialize) -> Self {
+        self.before = serde_json::to_value(before).ok().filter(|v| !v.is_null());
+        self
+    }
+
+    pub fn after(mut self, after: impl Serialize) -> Self {
+        self.after = serde_json::to_value(after).ok().filter(|v| !v.is_null());
+        self
+    }
+}
+
+/// Narrows what `find_recent` returns, entries come newest first
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub user_name: Option<String>,
+    pub action: Option<String>,
+    /// Only entries older than this id, for paging
+    pub before_id: Option<i64>,
+    pub limit: Option<u32>,
+}
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+struct AuditRow {
+    id: i64,
+    changed_at: NaiveDateTime,
+    user_name: String,
+    action: String,
+    target: String,
+    before_json: Option<String>,
+    after_json: Option<String>,
+}
+
+impl From<AuditRow> for AuditEntry {
+    fn from(row: AuditRow) -> Self {
+        let parse = |json: Option<String>| json.and_then(|j| serde_json::from_str(&j).ok());
+        Self {
+            id: row.id,
+            changed_at: row.changed_at,
+            user_name: row.user_name,
+            action: row.action,
+            target: row.target,
+            before: parse(row.before_json),
+            after: parse(row.after_json),
+        }
+    }
+}
+
+pub async fn record(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    user_name: &str,
+    change: &Change,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now().naive_utc();
+    let before = change.before.as_ref().map(Value::to_string);
+    let after = change.after.as_ref().map(Value::to_string);
+
+    query!(
+        r#"
+        INSERT INTO audit_log (changed_at, user_name, action, target, before_json, after_json)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        "#,
+        now,
+        user_name,
+        change.action,
+        change.target,
+        before,
+        after
+    )
+    .execute(exec)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn find_recent(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    filter: &AuditFilter,
+) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let rows = query_as!(
+        AuditRow,
+        r#"
+        SELECT id, changed_at, user_name, action, target, before_json, after_json
+        FROM audit_log
+        WHERE
+            (?1 IS NULL OR user_name = ?1)
+            AND (?2 IS NULL OR action = ?2)
+            AND (?3 IS NULL OR id < ?3)
+        ORDER BY id DESC
+        LIMIT ?4
+        "#,
+        filter.user_name,
+        filter.action,
+        filter.before_id,
+        limit
+    )
+    .fetch_all(exec)
+    .await?;
+
+    Ok(rows.into_iter().map(AuditEntry::from).collect())
+}
@@ -0,0 +1,142 @@
+use sqlx::{query, sqlite::SqliteQueryResult};
+
+pub async fn find_all(exec: impl sqlx::SqliteExecutor<'_>) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT name
+        FROM client_groups
+        ORDER BY name
+        "#
+    )
+    .map(|x| x.name)
+    .fetch_all(exec)
+    .await
+}
+
+/// The domain groups applied to a client group
+pub async fn find_domain_groups(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT domain_group_name
+        FROM groups_applied
+        WHERE client_group_name = ?1
+        "#,
+        name
+    )
+    .map(|x| x.domain_group_name)
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn create(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO client_groups (name) VALUES (?1)
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn create_if_missing(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO client_groups (name) VALUES (?1)
+        ON CONFLICT(name) DO NOTHING
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM client_groups where name = ?1
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn rename(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+    new_name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        UPDATE client_groups
+        SET name = ?1
+        WHERE name = ?2
+        "#,
+        new_name,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::{
+        clients::{self, Client},
+        domain_groups, groups_applied,
+    };
+    use crate::DatabaseHandle;
+
+    #[tokio::test]
+    async fn test_client_groups() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+
+        create(&mut conn, "kids").await?;
+        create_if_missing(&mut conn, "kids").await?;
+        create_if_missing(&mut conn, "adults").await?;
+        assert!(create(&mut conn, "kids").await.is_err());
+        assert_eq!(find_all(&mut conn).await?, vec!["adults", "kids"]);
+
+        let tablet = Client {
+            name: "tablet".to_string(),
+            ip: "192.168.1.10".to_string(),
+            mac: "aa:aa:aa:aa:aa:aa".to_string(),
+        };
+        clients::upsert(&mut conn, &tablet).await?;
+        clients::set_group(&mut conn, "tablet", "kids").await?;
+        domain_groups::create(&mut conn, "games").await?;
+        groups_applied::create(&mut conn, "kids", "games").await?;
+
+        //Members and applied groups follow a rename
+        rename(&mut conn, "kids", "children").await?;
+        assert_eq!(
+            clients::find_group(&mut conn, "tablet").await?,
+            Some("children".to_string())
+        );
+        assert_eq!(
+            find_domain_groups(&mut conn, "children").await?,
+            vec!["games"]
+        );
+
+        delete(&mut conn, "children").await?;
+        assert_eq!(clients::find_group(&mut conn, "tablet").await?, None);
+        assert!(groups_applied::find_all(&mut conn).await?.is_empty());
+        assert_eq!(find_all(&mut conn).await?, vec!["adults"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, sqlite::SqliteQueryResult, Acquire, SqliteConnection};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, sqlx::FromRow)]
+pub struct Client {
+    pub name: String,
+    pub ip: String,
+    pub mac: String,
+}
+
+/// Clients that aren't in any group yet
+pub async fn find_uncategorized(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<Vec<Client>, sqlx::Error> {
+    query_as!(
+        Client,
+        r#"
+        SELECT name, ip, mac
+        FROM clients
+        EXCEPT
+        SELECT name, ip, mac
+        FROM clients
+            INNER JOIN client_group_member on client_group_member.client_name = clients.name
+        ORDER BY name
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_all(exec: impl sqlx::SqliteExecutor<'_>) -> Result<Vec<Client>, sqlx::Error> {
+    query_as!(
+        Client,
+        r#"
+        SELECT name, ip, mac
+        FROM clients
+        ORDER BY name
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_by_group(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    group_name: &str,
+) -> Result<Vec<Client>, sqlx::Error> {
+    query_as!(
+        Client,
+        r#"
+        SELECT name, ip, mac
+        FROM clients
+        INNER JOIN 
+        client_group_member ON client_group_member.client_name = clients.name
+        WHERE group_name = ?1
+        ORDER BY name
+        "#,
+        group_name
+    )
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_by_name(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Option<Client>, sqlx::Error> {
+    query_as!(
+        Client,
+        r#"
+        SELECT name, ip, mac
+        FROM clients
+        WHERE name = ?1
+        "#,
+        name
+    )
+    .fetch_optional(exec)
+    .await
+}
+
+pub async fn find_group(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT group_name
+        FROM client_group_member
+        WHERE client_name = ?1
+        "#,
+        name
+    )
+    .map(|x| x.group_name)
+    .fetch_optional(exec)
+    .await
+}
+
+/// Adds a client or refreshes the address of one we already know by name
+pub async fn upsert(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    client: &Client,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO clients (
+            name, ip, mac
+        ) VALUES (
+            ?1, ?2, ?3
+        ) ON CONFLICT(name) DO UPDATE SET
+            ip=?2,
+            mac=?3
+        "#,
+        client.name,
+        client.ip,
+        client.mac
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM clients where name = ?1
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn update(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+    client: &Client,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        UPDATE clients
+        SET name = ?1,
+            ip = ?2,
+            mac = ?3
+        WHERE name = ?4
+        "#,
+        client.name,
+        client.ip,
+        client.mac,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn remove_from_group(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM client_group_member
+        WHERE client_name = ?1 
+        "#,
+        name,
+    )
+    .execute(exec)
+    .await
+}
+
+/// A client is only ever in one group so this replaces any existing membership
+pub async fn set_group(
+    conn: &mut SqliteConnection,
+    name: &str,
+    group_name: &str,
+) -> Result<(), sqlx::Error> {
+    let mut tran = conn.begin().await?;
+
+    remove_from_group(&mut tran, name).await?;
+
+    query!(
+        r#"
+        INSERT INTO client_group_member(
+            client_name,
+            group_name
+        )
+        VALUES (
+            ?1,
+            ?2
+        )
+        "#,
+        name,
+        group_name,
+    )
+    .execute(&mut tran)
+    .await?;
+
+    tran.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::client_groups;
+    use crate::DatabaseHandle;
+
+    fn client(name: &str, ip: &str, mac: &str) -> Client {
+        Client {
+            name: name.to_string(),
+            ip: ip.to_string(),
+            mac: mac.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+        let laptop = client("laptop", "192.168.1.20", "bb:bb:bb:bb:bb:bb");
+
+        upsert(
+            &mut conn,
+            &client("tablet", "192.168.1.10", "aa:aa:aa:aa:aa:aa"),
+        )
+        .await?;
+        upsert(
+            &mut conn,
+            &client("tablet", "192.168.1.11", "aa:aa:aa:aa:aa:aa"),
+        )
+        .await?;
+        upsert(&mut conn, &laptop).await?;
+        assert_eq!(
+            find_by_name(&mut conn, "tablet").await?.map(|c| c.ip),
+            Some("192.168.1.11".to_string())
+        );
+
+        client_groups::create(&mut conn, "kids").await?;
+        client_groups::create(&mut conn, "adults").await?;
+        set_group(&mut conn, "tablet", "kids").await?;
+        set_group(&mut conn, "tablet", "adults").await?;
+        assert_eq!(
+            find_group(&mut conn, "tablet").await?,
+            Some("adults".to_string())
+        );
+        assert!(find_by_group(&mut conn, "kids").await?.is_empty());
+        assert_eq!(find_uncategorized(&mut conn).await?, vec![laptop.clone()]);
+
+        //Renaming a client keeps its group
+        let ipad = client("ipad", "192.168.1.11", "aa:aa:aa:aa:aa:aa");
+        update(&mut conn, "tablet", &ipad).await?;
+        assert_eq!(find_by_group(&mut conn, "adults").await?, vec![ipad]);
+
+        remove_from_group(&mut conn, "ipad").await?;
+        assert_eq!(find_group(&mut conn, "ipad").await?, None);
+
+        delete(&mut conn, "ipad").await?;
+        assert_eq!(find_all(&mut conn).await?, vec![laptop]);
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, sqlite::SqliteQueryResult};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, sqlx::FromRow)]
+pub struct DomainGroup {
+    pub name: String,
+    /// Same default as the column so policy files can leave it out
+    #[serde(default = "new_model_status")]
+    pub model_status: String,
+}
+
+fn new_model_status() -> String {
+    "NEW".to_string()
+}
+
+pub async fn find_all(exec: impl sqlx::SqliteExecutor<'_>) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT name
+        FROM domain_groups
+        ORDER BY name
+        "#
+    )
+    .map(|x| x.name)
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_by_name(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Option<DomainGroup>, sqlx::Error> {
+    query_as!(
+        DomainGroup,
+        r#"
+        SELECT name, model_status
+        FROM domain_groups
+        WHERE name = ?1
+        "#,
+        name
+    )
+    .fetch_optional(exec)
+    .await
+}
+
+pub async fn find_domains(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT domain_name
+        FROM domain_group_member
+        WHERE group_name = ?1
+        ORDER BY domain_name
+        "#,
+        name
+    )
+    .map(|x| x.domain_name)
+    .fetch_all(exec)
+    .await
+}
+
+/// Only the domains an admin put in the group, not the ones the model learned
+pub async fn find_manual_domains(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT domain_name
+        FROM domain_group_member
+        WHERE group_name = ?1 AND manually_set = true
+        ORDER BY domain_name
+        "#,
+        name
+    )
+    .map(|x| x.domain_name)
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn create(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO domain_groups (name) VALUES (?1)
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+/// Creates the group or brings its model status in line
+pub async fn upsert(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    group: &DomainGroup,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO domain_groups (name, model_status) VALUES (?1, ?2)
+        ON CONFLICT(name) DO UPDATE SET model_status = ?2
+        "#,
+        group.name,
+        group.model_status
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM domain_groups where name = ?1
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn update(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+    group: &DomainGroup,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        UPDATE domain_groups
+        SET name = ?1,
+            model_status = ?2
+        WHERE name = ?3
+        "#,
+        group.name,
+        group.model_status,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::domains;
+    use crate::DatabaseHandle;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_domain_groups() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+
+        create(&mut conn, "games").await?;
+        assert_eq!(
+            find_by_name(&mut conn, "games").await?,
+            Some(DomainGroup {
+                name: "games".to_string(),
+                model_status: new_model_status(),
+            })
+        );
+
+        let trained = DomainGroup {
+            name: "games".to_string(),
+            model_status: "TRAINED".to_string(),
+        };
+        upsert(&mut conn, &trained).await?;
+        assert_eq!(find_by_name(&mut conn, "games").await?, Some(trained));
+
+        for domain in ["chess.example.", "puzzles.example."] {
+            domains::create_if_missing(&mut conn, domain, Utc::now(), "test").await?;
+        }
+        domains::set_group(&mut conn, "chess.example.", "games").await?;
+        //The model doesn't have a DAO of its own yet
+        query!(
+            r#"
+            INSERT INTO domain_group_member (domain_name, group_name, manually_set)
+            VALUES ('puzzles.example.', 'games', false)
+            "#
+        )
+        .execute(&mut conn)
+        .await?;
+
+        assert_eq!(
+            find_domains(&mut conn, "games").await?,
+            vec!["chess.example.", "puzzles.example."]
+        );
+        assert_eq!(
+            find_manual_domains(&mut conn, "games").await?,
+            vec!["chess.example."]
+        );
+
+        let renamed = DomainGroup {
+            name: "fun".to_string(),
+            model_status: "TRAINED".to_string(),
+        };
+        update(&mut conn, "games", &renamed).await?;
+        assert_eq!(find_domains(&mut conn, "fun").await?.len(), 2);
+
+        delete(&mut conn, "fun").await?;
+        assert!(find_all(&mut conn).await?.is_empty());
+        assert_eq!(
+            domains::find_group(&mut conn, "chess.example.").await?,
+            None
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, sqlite::SqliteQueryResult, Acquire, SqliteConnection};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, sqlx::FromRow)]
+pub struct Domain {
+    pub name: String,
+    pub last_seen: NaiveDateTime,
+    pub last_client: String,
+}
+
+/// Domains nobody has put in a group by hand
+pub async fn find_uncategorized(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<Vec<Domain>, sqlx::Error> {
+    query_as!(
+        Domain,
+        r#"
+        SELECT name, last_seen, last_client
+        FROM known_domains
+        EXCEPT
+        SELECT name, last_seen, last_client
+        FROM known_domains
+            INNER JOIN domain_group_member on domain_group_member.domain_name = known_domains.name
+        WHERE 
+            manually_set = true
+        ORDER BY name
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_all_names(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT name
+        FROM known_domains
+        "#
+    )
+    .map(|x| x.name)
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_by_name(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Option<Domain>, sqlx::Error> {
+    query_as!(
+        Domain,
+        r#"
+        SELECT name, last_seen, last_client
+        FROM known_domains
+        WHERE name = ?1
+        "#,
+        name
+    )
+    .fetch_optional(exec)
+    .await
+}
+
+pub async fn find_group(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT group_name
+        FROM domain_group_member
+        WHERE domain_name = ?1
+        ORDER BY manually_set DESC
+        "#,
+        name
+    )
+    .map(|x| x.group_name)
+    .fetch_optional(exec)
+    .await
+}
+
+/// Subdomains are tracked under the shortest known suffix, so `cdn.www.example.com.` goes
+/// to `example.com.` even when `www.example.com.` is known too. A name with no known
+/// suffix is tracked as itself. This is the name rules are looked up by.
+pub async fn resolve(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<String, sqlx::Error> {
+    query_scalar!(
+        r#"
+        WITH RECURSIVE
+            known(depth, domain_exists, domain_nm) AS (
+                VALUES (0, 1, ?1)
+                UNION ALL
+                SELECT
+                    k.depth+1,
+                    EXISTS(
+                        SELECT 1
+                        FROM known_domains kn
+                        WHERE kn.name=substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1)
+                    ),
+                    substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1)
+                FROM
+                known k
+                WHERE length(substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1)) > 1
+                and substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1) != k.domain_nm
+        )
+        SELECT
+            domain_nm as "name!: String"
+        FROM
+            known
+        WHERE
+            domain_exists = 1
+        ORDER BY
+            depth desc
+        LIMIT 1
+        "#,
+        name
+    )
+    .fetch_one(exec)
+    .await
+}
+
+/// Records a sighting, adding the domain if it is new
+pub async fn touch(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+    last_seen: DateTime<Utc>,
+    last_client: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO known_domains (
+            name, last_seen, last_client
+        ) VALUES (
+            ?1, ?2, ?3
+        ) ON CONFLICT(name) DO UPDATE SET
+            last_seen=?2,
+            last_client=?3
+        "#,
+        name,
+        last_seen,
+        last_client
+    )
+    .execute(exec)
+    .await
+}
+
+/// Adds a domain nobody has queried yet, one that is already known is left as it is
+pub async fn create_if_missing(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+    last_seen: DateTime<Utc>,
+    last_client: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO known_domains (name, last_seen, last_client) VALUES (?1, ?2, ?3)
+        ON CONFLICT(name) DO NOTHING
+        "#,
+        name,
+        last_seen,
+        last_client
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM known_domains where name = ?1
+        "#,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn update(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+    domain: &Domain,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        UPDATE known_domains
+        SET name = ?1,
+            last_seen = ?2,
+            last_client = ?3
+        WHERE name = ?4
+        "#,
+        domain.name,
+        domain.last_seen,
+        domain.last_client,
+        name
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn remove_from_group(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    name: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM domain_group_member
+        WHERE domain_name = ?1 
+        "#,
+        name,
+    )
+    .execute(exec)
+    .await
+}
+
+/// A domain is only ever in one group so this replaces any existing membership, the
+/// assignment is marked as manual so it wins over anything learned.
+pub async fn set_group(
+    conn: &mut SqliteConnection,
+    name: &str,
+    group_name: &str,
+) -> Result<(), sqlx::Error> {
+    let mut tran = conn.begin().await?;
+
+    remove_from_group(&mut tran, name).await?;
+
+    query!(
+        r#"
+        INSERT INTO domain_group_member(
+            domain_name,
+            group_name,
+            manually_set
+        )
+        VALUES (
+            ?1,
+            ?2,
+            true
+        )
+        "#,
+        name,
+        group_name,
+    )
+    .execute(&mut tran)
+    .await?;
+
+    tran.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::domain_groups;
+    use crate::DatabaseHandle;
+
+    #[tokio::test]
+    async fn test_domains() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+        let seen = Utc::now();
+
+        //Unknown names stand for themselves, subdomains fold into the shortest known suffix
+        assert_eq!(
+            resolve(&mut conn, "www.example.com.").await?,
+            "www.example.com."
+        );
+        touch(&mut conn, "example.com.", seen, "192.168.1.10").await?;
+        touch(&mut conn, "www.example.com.", seen, "192.168.1.10").await?;
+        assert_eq!(
+            resolve(&mut conn, "cdn.www.example.com.").await?,
+            "example.com."
+        );
+        assert_eq!(
+            resolve(&mut conn, "www.example.com.").await?,
+            "example.com."
+        );
+        delete(&mut conn, "www.example.com.").await?;
+
+        touch(&mut conn, "example.com.", seen, "192.168.1.20").await?;
+        create_if_missing(&mut conn, "example.com.", seen, "policy-import").await?;
+        create_if_missing(&mut conn, "games.example.", seen, "policy-import").await?;
+        assert_eq!(
+            find_by_name(&mut conn, "example.com.")
+                .await?
+                .map(|d| d.last_client),
+            Some("192.168.1.20".to_string())
+        );
+        assert_eq!(
+            find_all_names(&mut conn).await?.len(),
+            find_uncategorized(&mut conn).await?.len()
+        );
+
+        domain_groups::create(&mut conn, "games").await?;
+        set_group(&mut conn, "games.example.", "games").await?;
+        assert_eq!(
+            find_group(&mut conn, "games.example.").await?,
+            Some("games".to_string())
+        );
+        let uncategorized: Vec<String> = find_uncategorized(&mut conn)
+            .await?
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        assert_eq!(uncategorized, vec!["example.com."]);
+
+        remove_from_group(&mut conn, "games.example.").await?;
+        assert_eq!(find_group(&mut conn, "games.example.").await?, None);
+
+        delete(&mut conn, "example.com.").await?;
+        assert_eq!(find_by_name(&mut conn, "example.com.").await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, sqlite::SqliteQueryResult};
+
+/// A domain group whose domains are blocked for a client group
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, sqlx::FromRow)]
+pub struct GroupApplied {
+    pub client_group_name: String,
+    pub domain_group_name: String,
+}
+
+pub async fn find_all(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<Vec<GroupApplied>, sqlx::Error> {
+    query_as!(
+        GroupApplied,
+        r#"
+        SELECT client_group_name, domain_group_name
+        FROM groups_applied
+        ORDER BY client_group_name, domain_group_name
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn create(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    client_group: &str,
+    domain_group: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO groups_applied (client_group_name, domain_group_name) VALUES (?1, ?2)
+        "#,
+        client_group,
+        domain_group
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn create_if_missing(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    client_group: &str,
+    domain_group: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO groups_applied (client_group_name, domain_group_name) VALUES (?1, ?2)
+        ON CONFLICT(client_group_name, domain_group_name) DO NOTHING
+        "#,
+        client_group,
+        domain_group
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    client_group: &str,
+    domain_group: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM groups_applied 
+        WHERE
+            client_group_name = ?1
+            and domain_group_name = ?2
+        "#,
+        client_group,
+        domain_group
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete_all(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!("DELETE FROM groups_applied").execute(exec).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::{client_groups, domain_groups};
+    use crate::DatabaseHandle;
+
+    #[tokio::test]
+    async fn test_groups_applied() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+
+        client_groups::create(&mut conn, "kids").await?;
+        domain_groups::create(&mut conn, "games").await?;
+        domain_groups::create(&mut conn, "social").await?;
+
+        //Both groups have to exist
+        assert!(create(&mut conn, "kids", "missing").await.is_err());
+
+        create(&mut conn, "kids", "social").await?;
+        create_if_missing(&mut conn, "kids", "social").await?;
+        create_if_missing(&mut conn, "kids", "games").await?;
+        let applied = |domain_group: &str| GroupApplied {
+            client_group_name: "kids".to_string(),
+            domain_group_name: domain_group.to_string(),
+        };
+        assert_eq!(
+            find_all(&mut conn).await?,
+            vec![applied("games"), applied("social")]
+        );
+
+        delete(&mut conn, "kids", "games").await?;
+        assert_eq!(find_all(&mut conn).await?, vec![applied("social")]);
+
+        delete_all(&mut conn).await?;
+        assert!(find_all(&mut conn).await?.is_empty());
+        Ok(())
+    }
+}
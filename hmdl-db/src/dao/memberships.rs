@@ -0,0 +1,167 @@
+//Lookups across the member tables, putting a single client or domain in a group lives
+//with `clients` and `domains`
+
+use sqlx::{query, query_as, sqlite::SqliteQueryResult};
+
+/// A client group by the IP its client was last seen on
+#[derive(Clone, Debug, Eq, PartialEq, sqlx::FromRow)]
+pub struct ClientIpGroup {
+    pub ip: String,
+    pub group_name: String,
+}
+
+pub async fn find_client_groups_by_ip(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<Vec<ClientIpGroup>, sqlx::Error> {
+    query_as!(
+        ClientIpGroup,
+        r#"
+        SELECT clients.ip, client_group_member.group_name
+        FROM clients
+        INNER JOIN client_group_member ON client_group_member.client_name = clients.name
+        ORDER BY clients.ip, client_group_member.group_name
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+/// A domain in no group at all is new and blocked for everyone, otherwise it is blocked
+/// for the clients in a client group its domain group is applied to. The domain doesn't
+/// have to be in `known_domains` yet.
+pub async fn blocks(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    domain_name: &str,
+    client_ip: &str,
+) -> Result<bool, sqlx::Error> {
+    let blocked = query!(
+        r#"
+        SELECT
+            coalesce(
+                (
+                    SELECT 1
+                    WHERE NOT EXISTS(
+                        SELECT 1
+                        FROM domain_group_member
+                        WHERE domain_name = ?1
+                    )
+                ),
+                (
+                    SELECT 1
+                    FROM domain_group_member
+                    INNER JOIN groups_applied ON groups_applied.domain_group_name = domain_group_member.group_name
+                    INNER JOIN client_group_member ON groups_applied.client_group_name = client_group_member.group_name
+                    INNER JOIN clients ON clients.name = client_group_member.client_name
+                    WHERE domain_group_member.domain_name=?1
+                    and clients.ip = ?2
+                )
+            ) as block
+        "#,
+        domain_name,
+        client_ip
+    )
+    .fetch_one(exec)
+    .await?;
+
+    Ok(blocked.block == Some(1))
+}
+
+/// Whether an admin put the domain in its group, rather than the model
+pub async fn is_manually_set(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    domain_name: &str,
+) -> Result<bool, sqlx::Error> {
+    let row = query!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1
+            FROM domain_group_member
+            WHERE domain_name = ?1
+            and manually_set = true
+        ) as manual
+        "#,
+        domain_name
+    )
+    .fetch_one(exec)
+    .await?;
+
+    Ok(row.manual == 1)
+}
+
+pub async fn clear_clients(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!("DELETE FROM client_group_member")
+        .execute(exec)
+        .await
+}
+
+/// Learned memberships are left for the model
+pub async fn clear_manual_domains(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!("DELETE FROM domain_group_member WHERE manually_set = true")
+        .execute(exec)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::{
+        client_groups,
+        clients::{self, Client},
+        domain_groups, domains, groups_applied,
+    };
+    use crate::DatabaseHandle;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_memberships() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+
+        for (name, ip, mac) in [
+            ("tablet", "192.168.1.10", "aa:aa:aa:aa:aa:aa"),
+            ("laptop", "192.168.1.20", "bb:bb:bb:bb:bb:bb"),
+        ] {
+            let client = Client {
+                name: name.to_string(),
+                ip: ip.to_string(),
+                mac: mac.to_string(),
+            };
+            clients::upsert(&mut conn, &client).await?;
+        }
+        client_groups::create(&mut conn, "kids").await?;
+        clients::set_group(&mut conn, "tablet", "kids").await?;
+
+        domain_groups::create(&mut conn, "games").await?;
+        domains::create_if_missing(&mut conn, "games.example.", Utc::now(), "test").await?;
+        domains::set_group(&mut conn, "games.example.", "games").await?;
+
+        //Brand new and ungrouped domains are blocked for everyone
+        assert!(blocks(&mut conn, "new.example.", "192.168.1.20").await?);
+        assert!(!blocks(&mut conn, "games.example.", "192.168.1.10").await?);
+
+        groups_applied::create(&mut conn, "kids", "games").await?;
+        assert!(blocks(&mut conn, "games.example.", "192.168.1.10").await?);
+        assert!(!blocks(&mut conn, "games.example.", "192.168.1.20").await?);
+
+        assert_eq!(
+            find_client_groups_by_ip(&mut conn).await?,
+            vec![ClientIpGroup {
+                ip: "192.168.1.10".to_string(),
+                group_name: "kids".to_string(),
+            }]
+        );
+
+        assert!(is_manually_set(&mut conn, "games.example.").await?);
+        clear_manual_domains(&mut conn).await?;
+        assert!(!is_manually_set(&mut conn, "games.example.").await?);
+        assert!(blocks(&mut conn, "games.example.", "192.168.1.20").await?);
+
+        clear_clients(&mut conn).await?;
+        assert!(find_client_groups_by_ip(&mut conn).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, SqliteConnection};
+use std::{collections::HashSet, hash::Hash};
+
+use super::client_groups;
+use super::clients::{self, Client};
+use super::domain_groups::{self, DomainGroup};
+use super::domains;
+use super::groups_applied::{self, GroupApplied};
+use super::memberships;
+use super::record_type_policies::{self, RecordTypePolicy};
+use super::settings;
+
+/// Recorded as the last client on domains that only exist because a policy named them
+const IMPORT_CLIENT: &str = "policy-import";
+
+/// Everything an admin has decided about groups, in a form that can move between installs
+/// or be kept in git. `hmdl-policy.example.yaml` documents the layout.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Policy {
+    pub client_groups: Vec<ClientGroupPolicy>,
+    pub domain_groups: Vec<DomainGroupPolicy>,
+    pub groups_applied: Vec<GroupApplied>,
+    #[serde(default)]
+    pub record_type_policies: Vec<RecordTypePolicy>,
+    /// Left alone on import when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<PolicySettings>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ClientGroupPolicy {
+    pub name: String,
+    pub clients: Vec<Client>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DomainGroupPolicy {
+    #[serde(flatten)]
+    pub group: DomainGroup,
+    pub domains: Vec<String>,
+}
+
+/// The install's settings minus the Cloudflare API token, which stays out of any file
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct PolicySettings {
+    pub application_domain: String,
+    pub acme_email: String,
+}
+
+/// Domains only show up under the group an admin put them in, learned memberships are
+/// left for the model to rebuild.
+pub async fn export(conn: &mut SqliteConnection) -> Result<Policy, sqlx::Error> {
+    let mut policy = Policy::default();
+
+    for name in client_groups::find_all(&mut *conn).await? {
+        let clients = clients::find_by_group(&mut *conn, &name).await?;
+        policy
+            .client_groups
+            .push(ClientGroupPolicy { name, clients });
+    }
+
+    for name in domain_groups::find_all(&mut *conn).await? {
+        if let Some(group) = domain_groups::find_by_name(&mut *conn, &name).await? {
+            let domains = domain_groups::find_manual_domains(&mut *conn, &name).await?;
+            policy
+                .domain_groups
+                .push(DomainGroupPolicy { group, domains });
+        }
+    }
+
+    policy.groups_applied = groups_applied::find_all(&mut *conn).await?;
+    policy.record_type_policies = record_type_policies::find_all(&mut *conn).await?;
+
+    policy.settings = settings::find(&mut *conn).await?.map(|s| PolicySettings {
+        application_domain: s.application_domain,
+        acme_email: s.acme_email,
+    });
+
+    Ok(policy)
+}
+
+/// Merges a policy into the database, anything it doesn't mention is left alone. Either
+/// all of it goes in or none of it does.
+pub async fn import(conn: &mut SqliteConnection, policy: &Policy) -> Result<(), sqlx::Error> {
+    let mut tran = conn.begin().await?;
+
+    for group in &policy.client_groups {
+        client_groups::create_if_missing(&mut tran, &group.name).await?;
+
+        for client in &group.clients {
+            clients::upsert(&mut tran, client).await?;
+            clients::set_group(&mut tran, &client.name, &group.name).await?;
+        }
+    }
+
+    let timestamp = Utc::now();
+    for group in &policy.domain_groups {
+        domain_groups::upsert(&mut tran, &group.group).await?;
+
+        for domain in &group.domains {
+            domains::create_if_missing(&mut tran, domain, timestamp, IMPORT_CLIENT).await?;
+            domains::set_group(&mut tran, domain, &group.group.name).await?;
+        }
+    }
+
+    for applied in &policy.groups_applied {
+        groups_applied::create_if_missing(
+            &mut tran,
+            &applied.client_group_name,
+            &applied.domain_group_name,
+        )
+        .await?;
+    }
+
+    for record_type_policy in &policy.record_type_policies {
+        record_type_policies::upsert(&mut tran, record_type_policy).await?;
+    }
+
+    // Only an install that has been through setup has settings to change
+    if let Some(new_settings) = &policy.settings {
+        settings::update_domain_and_email(
+            &mut tran,
+            &new_settings.application_domain,
+            &new_settings.acme_email,
+        )
+        .await?;
+    }
+
+    tran.commit().await
+}
+
+/// Makes the database match the policy, groups it doesn't mention are dropped along with
+/// their memberships and so are manual domain assignments it doesn't list. Learned
+/// memberships in the groups that stay are kept.
+pub async fn replace(conn: &mut SqliteConnection, policy: &Policy) -> Result<(), sqlx::Error> {
+    let mut tran = conn.begin().await?;
+
+    let keep: HashSet<&str> = policy
+        .client_groups
+        .iter()
+        .map(|g| g.name.as_str())
+        .collect();
+    for name in client_groups::find_all(&mut tran).await? {
+        if !keep.contains(name.as_str()) {
+            client_groups::delete(&mut tran, &name).await?;
+        }
+    }
+
+    let keep: HashSet<&str> = policy
+        .domain_groups
+        .iter()
+        .map(|g| g.group.name.as_str())
+        .collect();
+    for name in domain_groups::find_all(&mut tran).await? {
+        if !keep.contains(name.as_str()) {
+            domain_groups::delete(&mut tran, &name).await?;
+        }
+    }
+
+    memberships::clear_clients(&mut tran).await?;
+    memberships::clear_manual_domains(&mut tran).await?;
+    groups_applied::delete_all(&mut tran).await?;
+    record_type_policies::delete_all(&mut tran).await?;
+
+    import(&mut tran, policy).await?;
+
+    tran.commit().await
+}
+
+/// Something that sits in a group, either a client or a domain
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Membership {
+    pub name: String,
+    pub group: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Changes<T> {
+    pub added: Vec<T>,
+    pub removed: Vec<T>,
+}
+
+/// What it would take to go from one policy to another
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PolicyDiff {
+    pub client_groups: Changes<String>,
+    pub client_members: Changes<Membership>,
+    pub domain_groups: Changes<String>,
+    pub domain_members: Changes<Membership>,
+    pub groups_applied: Changes<GroupApplied>,
+    pub record_type_policies: Changes<RecordTypePolicy>,
+    pub settings: Changes<PolicySettings>,
+}
+
+impl PolicyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.client_groups.added.is_empty()
+            && self.client_groups.removed.is_empty()
+            && self.client_members.added.is_empty()
+            && self.client_members.removed.is_empty()
+            && self.domain_groups.added.is_empty()
+            && self.domain_groups.removed.is_empty()
+            && self.domain_members.added.is_empty()
+            && self.domain_members.removed.is_empty()
+            && self.groups_applied.added.is_empty()
+            && self.groups_applied.removed.is_empty()
+            && self.record_type_policies.added.is_empty()
+            && self.record_type_policies.removed.is_empty()
+            && self.settings.added.is_empty()
+            && self.settings.removed.is_empty()
+    }
+}
+
+pub fn diff(from: &Policy, to: &Policy) -> PolicyDiff {
+    let client_groups =
+        |p: &Policy| -> Vec<String> { p.client_groups.iter().map(|g| g.name.clone()).collect() };
+    let client_members = |p: &Policy| -> Vec<Membership> {
+        p.client_groups
+            .iter()
+            .flat_map(|g| {
+                g.clients.iter().map(|c| Membership {
+                    name: c.name.clone(),
+                    group: g.name.clone(),
+                })
+            })
+            .collect()
+    };
+    let domain_groups = |p: &Policy| -> Vec<String> {
+        p.domain_groups
+            .iter()
+            .map(|g| g.group.name.clone())
+            .collect()
+    };
+    let domain_members = |p: &Policy| -> Vec<Membership> {
+        p.domain_groups
+            .iter()
+            .flat_map(|g| {
+                g.domains.iter().map(|d| Membership {
+                    name: d.clone(),
+                    group: g.group.name.clone(),
+                })
+            })
+            .collect()
+    };
+
+    PolicyDiff {
+        client_groups: changes(client_groups(from), client_groups(to)),
+        client_members: changes(client_members(from), client_members(to)),
+        domain_groups: changes(domain_groups(from), domain_groups(to)),
+        domain_members: changes(domain_members(from), domain_members(to)),
+        groups_applied: changes(from.groups_applied.clone(), to.groups_applied.clone()),
+        record_type_policies: changes(
+            from.record_type_policies.clone(),
+            to.record_type_policies.clone(),
+        ),
+        // Settings missing from `to` are left alone rather than removed
+        settings: match &to.settings {
+            Some(_) => changes(
+                from.settings.iter().cloned().collect(),
+                to.settings.iter().cloned().collect(),
+            ),
+            None => changes(Vec::new(), Vec::new()),
+        },
+    }
+}
+
+/// Keeps the order things were exported in so the output reads the same as the policy
+fn changes<T: Clone + Eq + Hash>(from: Vec<T>, to: Vec<T>) -> Changes<T> {
+    let from_set: HashSet<&T> = from.iter().collect();
+    let to_set: HashSet<&T> = to.iter().collect();
+
+    Changes {
+        added: to
+            .iter()
+            .filter(|t| !from_set.contains(t))
+            .cloned()
+            .collect(),
+        removed: from
+            .iter()
+            .filter(|t| !to_set.contains(t))
+            .cloned()
+            .collect(),
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, sqlite::SqliteQueryResult};
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize, sqlx::FromRow)]
+pub struct RecordTypePolicy {
+    pub client_group_name: String,
+    pub record_type: String,
+    pub action: String,
+}
+
+pub async fn find_all(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<Vec<RecordTypePolicy>, sqlx::Error> {
+    query_as!(
+        RecordTypePolicy,
+        r#"
+        SELECT client_group_name, record_type, action
+        FROM record_type_policies
+        ORDER BY client_group_name, record_type
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+/// The actions every group the client at `client_ip` is in applies to `record_type`
+pub async fn find_actions(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    client_ip: &str,
+    record_type: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    query!(
+        r#"
+        SELECT record_type_policies.action
+        FROM record_type_policies
+        INNER JOIN client_group_member ON client_group_member.group_name = record_type_policies.client_group_name
+        INNER JOIN clients ON clients.name = client_group_member.client_name
+        WHERE clients.ip = ?1
+        and record_type_policies.record_type = ?2
+        "#,
+        client_ip,
+        record_type
+    )
+    .map(|x| x.action)
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn upsert(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    policy: &RecordTypePolicy,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO record_type_policies (client_group_name, record_type, action) VALUES (?1, ?2, ?3)
+        ON CONFLICT(client_group_name, record_type) DO UPDATE SET action = ?3
+        "#,
+        policy.client_group_name,
+        policy.record_type,
+        policy.action
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    client_group: &str,
+    record_type: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        DELETE FROM record_type_policies
+        WHERE
+            client_group_name = ?1
+            and record_type = ?2
+        "#,
+        client_group,
+        record_type
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn delete_all(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!("DELETE FROM record_type_policies")
+        .execute(exec)
+        .await
+}
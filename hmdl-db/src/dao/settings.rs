@@ -0,0 +1,125 @@
+use sqlx::{query, query_as, sqlite::SqliteQueryResult};
+
+/// The single row written by the installer, there is none until setup has been saved
+#[derive(Clone, Debug, Eq, PartialEq, sqlx::FromRow)]
+pub struct Settings {
+    pub application_domain: String,
+    pub cloudflare_api_token: String,
+    pub acme_email: String,
+    /// Set once the HTTPS server has come up, setup is only complete after that
+    pub https_started_once: bool,
+}
+
+pub async fn find(exec: impl sqlx::SqliteExecutor<'_>) -> Result<Option<Settings>, sqlx::Error> {
+    query_as!(
+        Settings,
+        r#"
+        SELECT application_domain, cloudflare_api_token, acme_email, https_started_once
+        FROM hmdl_settings
+        WHERE lock_column == true
+        "#
+    )
+    .fetch_optional(exec)
+    .await
+}
+
+/// Creates the row or replaces what the installer asked for, `https_started_once` is kept
+pub async fn save(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    application_domain: &str,
+    cloudflare_api_token: &str,
+    acme_email: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        INSERT INTO hmdl_settings (
+            application_domain,
+            cloudflare_api_token,
+            acme_email,
+            https_started_once,
+            lock_column
+        ) VALUES (
+            ?1,
+            ?2,
+            ?3,
+            false,
+            true
+        ) ON CONFLICT (lock_column)
+        DO UPDATE
+        SET
+            application_domain = ?1,
+            cloudflare_api_token = ?2,
+            acme_email = ?3
+        "#,
+        application_domain,
+        cloudflare_api_token,
+        acme_email
+    )
+    .execute(exec)
+    .await
+}
+
+/// Only touches an install that has been through setup
+pub async fn update_domain_and_email(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    application_domain: &str,
+    acme_email: &str,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        UPDATE hmdl_settings
+        SET application_domain = ?1, acme_email = ?2
+        WHERE lock_column == true
+        "#,
+        application_domain,
+        acme_email
+    )
+    .execute(exec)
+    .await
+}
+
+pub async fn mark_https_started(
+    exec: impl sqlx::SqliteExecutor<'_>,
+) -> Result<SqliteQueryResult, sqlx::Error> {
+    query!(
+        r#"
+        UPDATE hmdl_settings
+        SET https_started_once=true
+        WHERE lock_column=true
+        "#
+    )
+    .execute(exec)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DatabaseHandle;
+
+    #[tokio::test]
+    async fn test_settings() -> Result<(), sqlx::Error> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        assert_eq!(find(&pool).await?, None);
+
+        //Nothing to update before setup
+        update_domain_and_email(&pool, "hmdl.example.com", "admin@example.com").await?;
+        assert_eq!(find(&pool).await?, None);
+
+        save(&pool, "hmdl.example.com", "token", "admin@example.com").await?;
+        mark_https_started(&pool).await?;
+        save(&pool, "dns.example.com", "new-token", "admin@example.com").await?;
+        update_domain_and_email(&pool, "dns.example.com", "dns@example.com").await?;
+
+        assert_eq!(
+            find(&pool).await?,
+            Some(Settings {
+                application_domain: "dns.example.com".to_string(),
+                cloudflare_api_token: "new-token".to_string(),
+                acme_email: "dns@example.com".to_string(),
+                https_started_once: true,
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, SqliteConnection};
+use thiserror::Error;
+
+use super::policy::{self, Policy};
+
+/// A copy of the policy as it stood at `taken_at`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, sqlx::FromRow)]
+pub struct Snapshot {
+    pub id: i64,
+    pub taken_at: NaiveDateTime,
+    /// Why it was taken, `scheduled`, `manual` or the bulk change that was about to happen
+    pub reason: String,
+}
+
+/// Exports the current policy and keeps it, returns the new snapshot's id. Settings are
+/// left out so a rollback only ever touches groups.
+pub async fn take(conn: &mut SqliteConnection, reason: &str) -> Result<i64, SnapshotError> {
+    let policy = Policy {
+        settings: None,
+        ..policy::export(&mut *conn).await?
+    };
+    let policy_json = serde_json::to_string(&policy)?;
+    let now = Utc::now().naive_utc();
+
+    let result = query!(
+        r#"
+        INSERT INTO policy_snapshots (taken_at, reason, policy_json) VALUES (?1, ?2, ?3)
+        "#,
+        now,
+        reason,
+        policy_json
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.last_insert_rowid())
+}
+
+/// Newest first
+pub async fn find_all(exec: impl sqlx::SqliteExecutor<'_>) -> Result<Vec<Snapshot>, sqlx::Error> {
+    query_as!(
+        Snapshot,
+        r#"
+        SELECT id, taken_at, reason
+        FROM policy_snapshots
+        ORDER BY id DESC
+        "#
+    )
+    .fetch_all(exec)
+    .await
+}
+
+pub async fn find_policy(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    id: i64,
+) -> Result<Option<Policy>, SnapshotError> {
+    let policy_json = query!(
+        r#"
+        SELECT policy_json
+        FROM policy_snapshots
+        WHERE id = ?1
+        "#,
+        id
+    )
+    .map(|x| x.policy_json)
+    .fetch_optional(exec)
+    .await?;
+
+    match policy_json {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+/// Drops all but the newest `keep` snapshots
+pub async fn prune(exec: impl sqlx::SqliteExecutor<'_>, keep: u32) -> Result<u64, sqlx::Error> {
+    let result = query!(
+        r#"
+        DELETE FROM policy_snapshots
+        WHERE id NOT IN (
+            SELECT id FROM policy_snapshots ORDER BY id DESC LIMIT ?1
+        )
+        "#,
+        keep
+    )
+    .execute(exec)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::{
+        client_groups,
+        clients::{self, Client},
+        domain_groups, domains,
+        groups_applied::{self, GroupApplied},
+    };
+    use crate::DatabaseHandle;
+
+    //The model doesn't have a DAO of its own yet
+    async fn learn(
+        conn: &mut SqliteConnection,
+        domain: &str,
+        group: &str,
+    ) -> Result<(), sqlx::Error> {
+        domains::create_if_missing(&mut *conn, domain, Utc::now(), "test").await?;
+        query!(
+            r#"
+            INSERT INTO domain_group_member (domain_name, group_name, manually_set)
+            VALUES (?1, ?2, false)
+            "#,
+            domain,
+            group
+        )
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Kids get games blocked, chess was put there by hand and puzzles was learned
+    async fn setup(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        client_groups::create(&mut *conn, "kids").await?;
+        let tablet = Client {
+            name: "tablet".to_string(),
+            ip: "192.168.1.10".to_string(),
+            mac: "aa:aa:aa:aa:aa:aa".to_string(),
+        };
+        clients::upsert(&mut *conn, &tablet).await?;
+        clients::set_group(&mut *conn, "tablet", "kids").await?;
+
+        domain_groups::create(&mut *conn, "games").await?;
+        domains::create_if_missing(&mut *conn, "chess.example.", Utc::now(), "test").await?;
+        domains::set_group(&mut *conn, "chess.example.", "games").await?;
+        learn(&mut *conn, "puzzles.example.", "games").await?;
+
+        groups_applied::create(&mut *conn, "kids", "games").await?;
+        Ok(())
+    }
+
+    /// Everything an admin might do between the snapshot and the rollback
+    async fn mutate(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+        client_groups::create(&mut *conn, "adults").await?;
+        clients::set_group(&mut *conn, "tablet", "adults").await?;
+
+        domain_groups::create(&mut *conn, "social").await?;
+        domains::set_group(&mut *conn, "chess.example.", "social").await?;
+        domains::create_if_missing(&mut *conn, "video.example.", Utc::now(), "test").await?;
+        domains::set_group(&mut *conn, "video.example.", "games").await?;
+
+        groups_applied::delete_all(&mut *conn).await?;
+        groups_applied::create(&mut *conn, "adults", "social").await?;
+
+        //Learned after the snapshot, a rollback isn't about the model's work
+        learn(&mut *conn, "cards.example.", "games").await
+    }
+
+    #[tokio::test]
+    async fn test_rollback() -> Result<(), SnapshotError> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+
+        setup(&mut conn).await?;
+        let id = take(&mut conn, "manual").await?;
+        assert_eq!(find_all(&mut conn).await?.len(), 1);
+
+        mutate(&mut conn).await?;
+        let snapshot = find_policy(&mut conn, id)
+            .await?
+            .expect("the snapshot exists");
+
+        let current = policy::export(&mut conn).await?;
+        let diff = policy::diff(&current, &snapshot);
+        assert_eq!(diff.client_groups.removed, vec!["adults"]);
+        assert_eq!(diff.domain_groups.removed, vec!["social"]);
+        assert_eq!(
+            diff.groups_applied.added,
+            vec![GroupApplied {
+                client_group_name: "kids".to_string(),
+                domain_group_name: "games".to_string(),
+            }]
+        );
+
+        policy::replace(&mut conn, &snapshot).await?;
+
+        assert_eq!(client_groups::find_all(&mut conn).await?, vec!["kids"]);
+        assert_eq!(
+            clients::find_group(&mut conn, "tablet").await?,
+            Some("kids".to_string())
+        );
+        assert_eq!(domain_groups::find_all(&mut conn).await?, vec!["games"]);
+        assert_eq!(
+            domain_groups::find_manual_domains(&mut conn, "games").await?,
+            vec!["chess.example."]
+        );
+        assert_eq!(
+            domain_groups::find_domains(&mut conn, "games").await?,
+            vec!["cards.example.", "chess.example.", "puzzles.example."]
+        );
+        assert_eq!(
+            groups_applied::find_all(&mut conn).await?,
+            vec![GroupApplied {
+                client_group_name: "kids".to_string(),
+                domain_group_name: "games".to_string(),
+            }]
+        );
+
+        let restored = policy::export(&mut conn).await?;
+        assert!(policy::diff(&restored, &snapshot).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_rollback() -> Result<(), SnapshotError> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+        let mut conn = pool.acquire().await?;
+
+        setup(&mut conn).await?;
+        let id = take(&mut conn, "manual").await?;
+        mutate(&mut conn).await?;
+
+        //Fails on the last step, after every group has already been cleared
+        let mut broken = find_policy(&mut conn, id)
+            .await?
+            .expect("the snapshot exists");
+        broken.groups_applied.push(GroupApplied {
+            client_group_name: "kids".to_string(),
+            domain_group_name: "missing".to_string(),
+        });
+
+        let before = policy::export(&mut conn).await?;
+        assert!(policy::replace(&mut conn, &broken).await.is_err());
+
+        let after = policy::export(&mut conn).await?;
+        assert_eq!(before, after);
+        assert_eq!(
+            domain_groups::find_domains(&mut conn, "games").await?,
+            vec!["cards.example.", "puzzles.example.", "video.example."]
+        );
+        Ok(())
+    }
+}
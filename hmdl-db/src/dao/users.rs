@@ -137,7 +137,7 @@ pub async fn update(
 ) -> Result<SqliteQueryResult, UserError> {
     let id = user.id.to_string();
     let keys = serde_json::to_string(&user.keys)?;
-    let role = serde_json::to_string(&user.role)?;
+    let role = user.role.to_string();
 
     sqlx::query!(
         r#"
@@ -161,6 +161,56 @@ pub async fn update(
     .map_err(UserError::Sqlx)
 }
 
+/// Drops every passkey a user has so they can register a new one, this is the way back
+/// in when the only admin has lost their device. Registering again needs the recovery
+/// token, only its hash is kept.
+pub async fn reset_keys(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    display_name: &str,
+    recovery_token_hash: &str,
+) -> Result<SqliteQueryResult, UserError> {
+    sqlx::query!(
+        r#"
+        update users
+        set
+            keys = '[]',
+            recovery_token = ?2
+        where
+            display_name = ?1
+        "#,
+        display_name,
+        recovery_token_hash
+    )
+    .execute(exec)
+    .await
+    .map_err(UserError::Sqlx)
+}
+
+/// Spends the token from `reset_keys`, true only the first time it matches
+pub async fn take_recovery_token(
+    exec: impl sqlx::SqliteExecutor<'_>,
+    display_name: &str,
+    recovery_token_hash: &str,
+) -> Result<bool, UserError> {
+    let res = sqlx::query!(
+        r#"
+        update users
+        set
+            recovery_token = NULL
+        where
+            display_name = ?1
+            and recovery_token = ?2
+            and keys = '[]'
+        "#,
+        display_name,
+        recovery_token_hash
+    )
+    .execute(exec)
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
 #[derive(Debug, Error)]
 pub enum UserError {
     #[error(transparent)]
@@ -170,3 +220,37 @@ pub enum UserError {
     #[error(transparent)]
     Sqlx(#[from] sqlx::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DatabaseHandle;
+
+    #[tokio::test]
+    async fn test_recovery_token() -> Result<(), UserError> {
+        let pool = DatabaseHandle::create_in_memory().await?;
+
+        let mut admin = User {
+            display_name: "admin".to_string(),
+            id: Uuid::new_v4(),
+            keys: vec![],
+            role: Roles::Registered,
+        };
+        create(&pool, &mut admin).await?;
+        assert_eq!(admin.role, Roles::Admin);
+
+        //Nothing to spend before a reset
+        assert!(!take_recovery_token(&pool, "admin", "hash").await?);
+
+        reset_keys(&pool, "admin", "hash").await?;
+        assert!(!take_recovery_token(&pool, "admin", "wrong").await?);
+        assert!(take_recovery_token(&pool, "admin", "hash").await?);
+        assert!(!take_recovery_token(&pool, "admin", "hash").await?);
+
+        let found = find_by_name(&pool, "admin")
+            .await?
+            .expect("admin was created");
+        assert_eq!(found.role, Roles::Admin);
+        Ok(())
+    }
+}
@@ -1,32 +1,208 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 pub mod dao;
 
 use sqlx::{
+    migrate::Migrator,
+    query, query_scalar,
     sqlite::{
-        SqliteConnectOptions, SqliteLockingMode::Exclusive, SqlitePoolOptions,
+        SqliteConnectOptions, SqliteConnection,
+        SqliteJournalMode::{Delete, Wal},
+        SqliteLockingMode, SqlitePoolOptions,
         SqliteSynchronous::Normal,
     },
-    Error, SqlitePool,
+    Connection, Error, SqlitePool,
 };
+use thiserror::Error;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// SQLite only lets one connection write at a time, a few are kept so the admin API and the
+/// DNS write queue don't wait on each other for a free connection
+const WRITER_CONNECTIONS: u32 = 4;
+/// WAL lets readers run alongside the writer, so answering DNS never waits on a write
+const READER_CONNECTIONS: u32 = 8;
 
 pub struct DatabaseHandle;
 
 impl DatabaseHandle {
+    /// The pool everything that writes goes through, it migrates the schema and puts the
+    /// database in WAL mode
     pub async fn create(path: &str) -> Result<SqlitePool, Error> {
-        let pool_opts = SqlitePoolOptions::new().min_connections(2);
+        let pool_opts = SqlitePoolOptions::new()
+            .min_connections(1)
+            .max_connections(WRITER_CONNECTIONS);
 
         let con_opts = SqliteConnectOptions::from_str(&format!("sqlite://{}", path))?
             .create_if_missing(true)
             .foreign_keys(true)
-            .locking_mode(Exclusive)
-            .shared_cache(true)
+            .journal_mode(Wal)
+            .locking_mode(SqliteLockingMode::Normal)
             .synchronous(Normal);
 
         let pool = pool_opts.connect_with(con_opts).await?;
 
-        sqlx::migrate!("./migrations").run(&pool).await?;
+        MIGRATOR.run(&pool).await?;
 
         Ok(pool)
     }
+
+    /// Read only connections for DNS decisions and heavy admin queries, `create` has to
+    /// have run first so the database exists and is in WAL mode
+    pub async fn create_reader(path: &str) -> Result<SqlitePool, Error> {
+        let pool_opts = SqlitePoolOptions::new()
+            .min_connections(2)
+            .max_connections(READER_CONNECTIONS);
+
+        let con_opts = SqliteConnectOptions::from_str(&format!("sqlite://{}", path))?
+            .read_only(true)
+            .journal_mode(Wal)
+            .locking_mode(SqliteLockingMode::Normal);
+
+        pool_opts.connect_with(con_opts).await
+    }
+
+    /// A private migrated database for tests, it is gone once the pool closes so the one
+    /// connection is never allowed to expire
+    #[cfg(test)]
+    pub(crate) async fn create_in_memory() -> Result<SqlitePool, Error> {
+        let pool_opts = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+
+        let con_opts = SqliteConnectOptions::from_str("sqlite::memory:")?.foreign_keys(true);
+
+        let pool = pool_opts.connect_with(con_opts).await?;
+
+        MIGRATOR.run(&pool).await?;
+
+        Ok(pool)
+    }
+
+    /// Writes a consistent copy of the live database to `path`, replacing whatever is
+    /// there. It goes through the pool so it works while everything else keeps running.
+    ///
+    /// VACUUM INTO copies the database inside a single read transaction, the same snapshot
+    /// guarantee the online backup API gives and one sqlx can reach without FFI. Under WAL
+    /// writers carry on meanwhile, and unlike a stepped backup it never restarts when they
+    /// do. The copy leaves out free pages, which is only smaller. It refuses to write over
+    /// an existing file, so one left at `path` by an earlier rotation is removed first.
+    pub async fn backup(pool: &SqlitePool, path: &str) -> Result<(), Error> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(Error::Io(e)),
+            _ => {}
+        }
+
+        query("VACUUM INTO ?1").bind(path).execute(pool).await?;
+        Ok(())
+    }
+
+    /// Takes the database at `path` for ourselves so a restore can replace it. Fails with
+    /// [`SchemaError::InUse`] while anything else, like a running hmdl, has it open.
+    ///
+    /// Switching out of WAL folds the log back in and removes the -wal and -shm files,
+    /// SQLite only allows that on the last connection. The lock is held until the returned
+    /// connection is closed.
+    pub async fn lock_exclusive(path: &str) -> Result<SqliteConnection, SchemaError> {
+        let con_opts = SqliteConnectOptions::from_str(&format!("sqlite://{}", path))?
+            .locking_mode(SqliteLockingMode::Exclusive)
+            .journal_mode(Delete)
+            .busy_timeout(Duration::ZERO);
+        let mut conn = SqliteConnection::connect_with(&con_opts)
+            .await
+            .map_err(in_use)?;
+
+        //In exclusive locking mode the write lock outlives the transaction
+        query("BEGIN EXCLUSIVE")
+            .execute(&mut conn)
+            .await
+            .map_err(in_use)?;
+        query("COMMIT").execute(&mut conn).await?;
+
+        //SQLite quietly stays in WAL when it can't switch
+        let mode: String = query_scalar("PRAGMA journal_mode = DELETE")
+            .fetch_one(&mut conn)
+            .await?;
+        if mode != "delete" {
+            return Err(SchemaError::InUse);
+        }
+
+        Ok(conn)
+    }
+
+    /// Makes sure a backup is an intact hmdl database this build can migrate forward, it
+    /// may be older than the running schema but never newer.
+    pub async fn check_backup(path: &str) -> Result<(), SchemaError> {
+        let con_opts =
+            SqliteConnectOptions::from_str(&format!("sqlite://{}", path))?.read_only(true);
+        let mut conn = SqliteConnection::connect_with(&con_opts).await?;
+
+        let integrity: String = query_scalar("PRAGMA integrity_check")
+            .fetch_one(&mut conn)
+            .await?;
+        if integrity != "ok" {
+            return Err(SchemaError::Corrupt(integrity));
+        }
+
+        let applied: Vec<(i64, Vec<u8>, bool)> =
+            sqlx::query_as("SELECT version, checksum, success FROM _sqlx_migrations")
+                .fetch_all(&mut conn)
+                .await
+                .map_err(|_| SchemaError::NotHmdl)?;
+        if applied.is_empty() {
+            return Err(SchemaError::NotHmdl);
+        }
+
+        for (version, checksum, success) in applied {
+            let known = MIGRATOR
+                .iter()
+                .find(|m| m.version == version)
+                .ok_or(SchemaError::Newer(version))?;
+            if !success || &*known.checksum != checksum.as_slice() {
+                return Err(SchemaError::Mismatch(version));
+            }
+        }
+
+        conn.close().await?;
+        Ok(())
+    }
+}
+
+/// SQLITE_BUSY and SQLITE_LOCKED, extended codes keep the primary one in the low byte
+fn in_use(e: Error) -> SchemaError {
+    let busy = match &e {
+        Error::Database(db) => db
+            .code()
+            .and_then(|c| c.parse::<i32>().ok())
+            .map(|c| matches!(c & 0xff, 5 | 6))
+            .unwrap_or(false),
+        _ => false,
+    };
+
+    match busy {
+        true => SchemaError::InUse,
+        false => SchemaError::Sqlx(e),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("the backup failed its integrity check: {0}")]
+    Corrupt(String),
+
+    #[error("migration {0} in the backup doesn't match this version of hmdl")]
+    Mismatch(i64),
+
+    #[error("the database is in use, stop hmdl before restoring")]
+    InUse,
+
+    #[error("the backup has migration {0}, it was made by a newer hmdl")]
+    Newer(i64),
+
+    #[error("the backup isn't an hmdl database")]
+    NotHmdl,
+
+    #[error(transparent)]
+    Sqlx(#[from] Error),
 }
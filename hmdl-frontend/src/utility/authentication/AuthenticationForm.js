@@ -10,6 +10,7 @@ import LoginButton from './LoginButton';
 
 export function AuthenticationForm() {
   const [nickname, setNickname] = useState('');
+  const [recoveryToken, setRecoveryToken] = useState('');
 
   if (!window.PublicKeyCredential) {
     return (
@@ -41,8 +42,16 @@ export function AuthenticationForm() {
                   name="nickname"
                   onChange={(event) => setNickname(event.target.value)} />
               </Form.Group>
+              <Form.Group className="mb-3" controlId="formRecoveryToken">
+                <Form.Label>Recovery Token</Form.Label>
+                <Form.Control
+                  type="text"
+                  placeholder="Only after a passkey reset"
+                  name="recoveryToken"
+                  onChange={(event) => setRecoveryToken(event.target.value)} />
+              </Form.Group>
               <Form.Group>
-                <RegisterButton nickname={nickname} />
+                <RegisterButton nickname={nickname} recoveryToken={recoveryToken} />
               </Form.Group>
               <br />
               <Form.Group>
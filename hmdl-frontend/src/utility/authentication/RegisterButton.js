@@ -35,7 +35,10 @@ export function RegisterButton(props) {
 
   const registerStart = (event) => {
     startRegister({
-      data: { 'username': props.nickname },
+      data: {
+        'username': props.nickname,
+        'recovery_token': props.recoveryToken || null,
+      },
     }).then((data) => {
       setRegChallenge(parseCreationOptionsFromJSON(data.data));
     }).catch((e) => {
@@ -87,6 +90,7 @@ export function RegisterButton(props) {
 
 RegisterButton.propTypes = {
   nickname: PropTypes.string.isRequired,
+  recoveryToken: PropTypes.string,
 };
 
 export default RegisterButton;
@@ -24,7 +24,7 @@ export function Health() {
     () => {
       if (count > 300) {
         setHealthError(true);
-      } else if (!loading && !error && data === 'Ok') {
+      } else if (!loading && !error && data?.status === 'ok') {
         setLoading(false);
         navigate('/pre-setup');
       } else if (!loading) {
@@ -0,0 +1,335 @@
+//Subcommands that work straight on the database for scripting and for getting back in
+//when the web interface can't be used. They can run alongside the daemon, only a restore
+//needs it stopped.
+
+use clap::Subcommand;
+use hmdl_db::{
+    dao::{
+        client_groups, clients, domain_groups, domains, groups_applied, policy,
+        snapshots::{self, SnapshotError},
+        users::{self, UserError},
+    },
+    DatabaseHandle,
+};
+use ring::{
+    error::Unspecified,
+    rand::{SecureRandom, SystemRandom},
+};
+use sqlx::{Acquire, SqlitePool};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::backup::{self, BackupError, Backups};
+use crate::config::{ConfigError, HmdlConfig};
+use crate::policy_file::{self, PolicyFileError, PolicyFormat};
+use crate::web::endpoints::authentication::recovery_token_hash;
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// Copy the database to a file or put a copy back
+    #[command(subcommand)]
+    Backup(BackupCommand),
+
+    /// List clients and assign them to client groups
+    #[command(subcommand)]
+    Clients(ClientCommand),
+
+    /// List domains and assign them to domain groups
+    #[command(subcommand)]
+    Domains(DomainCommand),
+
+    /// List groups and apply domain groups to client groups
+    #[command(subcommand)]
+    Groups(GroupCommand),
+
+    /// Keep the group policy in a JSON or YAML file and roll it back to a snapshot
+    #[command(subcommand)]
+    Policy(PolicyCommand),
+
+    /// List users and recover lost passkeys
+    #[command(subcommand)]
+    Users(UserCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BackupCommand {
+    /// Write a consistent copy of the database, encrypted if backup.passphrase is set
+    Create { file: PathBuf },
+    /// Replace the database with a backup after checking its schema
+    Restore { file: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClientCommand {
+    List {
+        /// Only clients that aren't in a group
+        #[arg(long)]
+        uncategorized: bool,
+    },
+    Assign {
+        client: String,
+        group: String,
+    },
+    Unassign {
+        client: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DomainCommand {
+    /// Domains that haven't been put in a group by hand
+    List,
+    Assign {
+        domain: String,
+        group: String,
+    },
+    Unassign {
+        domain: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GroupCommand {
+    List,
+    /// Block a domain group's domains for a client group
+    Apply {
+        client_group: String,
+        domain_group: String,
+    },
+    Unapply {
+        client_group: String,
+        domain_group: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PolicyCommand {
+    /// Write the policy to a file, or stdout if none is given
+    Export {
+        file: Option<PathBuf>,
+        /// Defaults to YAML for .yaml and .yml files and JSON otherwise
+        #[arg(long)]
+        format: Option<PolicyFormat>,
+    },
+    /// Make the database match a policy file, the current policy is snapshotted first
+    Import {
+        file: PathBuf,
+        #[arg(long)]
+        format: Option<PolicyFormat>,
+        /// Only print what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the policy snapshots, newest first
+    Snapshots,
+    /// Put the policy back the way it was when a snapshot was taken
+    Rollback { id: i64 },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    List,
+    /// Remove a user's passkeys and print a one time token to register a new one with
+    ResetPasskey {
+        name: String,
+    },
+}
+
+pub async fn run(config: &HmdlConfig, command: AdminCommand) -> Result<(), AdminError> {
+    let database_path = config.database_path()?;
+
+    //Restoring swaps the database file out so nothing can have it open
+    if let AdminCommand::Backup(BackupCommand::Restore { file }) = &command {
+        let passphrase = config.backup.passphrase.as_deref();
+        backup::restore(Path::new(database_path), file, passphrase).await?;
+        println!("Restored, start hmdl to bring the schema up to date");
+        return Ok(());
+    }
+
+    let pool = DatabaseHandle::create(database_path).await?;
+    let result = run_command(config, &pool, command).await;
+    pool.close().await;
+    result
+}
+
+async fn run_command(
+    config: &HmdlConfig,
+    pool: &SqlitePool,
+    command: AdminCommand,
+) -> Result<(), AdminError> {
+    let mut conn = pool.acquire().await?;
+
+    match command {
+        AdminCommand::Backup(BackupCommand::Create { file }) => {
+            let backups = Backups::create(
+                pool.clone(),
+                Path::new(config.database_path()?),
+                config.backup.passphrase.clone(),
+            );
+            backups.write_to(&file).await?;
+        }
+        AdminCommand::Backup(BackupCommand::Restore { .. }) => {
+            unreachable!("restores are handled before the database is opened")
+        }
+        AdminCommand::Clients(ClientCommand::List { uncategorized }) => {
+            let clients = match uncategorized {
+                true => clients::find_uncategorized(&mut conn).await?,
+                false => clients::find_all(&mut conn).await?,
+            };
+            for c in clients {
+                println!("{}\t{}\t{}", c.name, c.ip, c.mac);
+            }
+        }
+        AdminCommand::Clients(ClientCommand::Assign { client, group }) => {
+            clients::set_group(&mut conn, &client, &group).await?;
+        }
+        AdminCommand::Clients(ClientCommand::Unassign { client }) => {
+            clients::remove_from_group(&mut conn, &client).await?;
+        }
+        AdminCommand::Domains(DomainCommand::List) => {
+            for d in domains::find_uncategorized(&mut conn).await? {
+                println!("{}\t{}\t{}", d.name, d.last_seen, d.last_client);
+            }
+        }
+        AdminCommand::Domains(DomainCommand::Assign { domain, group }) => {
+            domains::set_group(&mut conn, &fqdn(&domain), &group).await?;
+        }
+        AdminCommand::Domains(DomainCommand::Unassign { domain }) => {
+            domains::remove_from_group(&mut conn, &fqdn(&domain)).await?;
+        }
+        AdminCommand::Groups(GroupCommand::List) => {
+            for group in client_groups::find_all(&mut conn).await? {
+                let applied = client_groups::find_domain_groups(&mut conn, &group).await?;
+                println!("client\t{}\t{}", group, applied.join(","));
+            }
+            for group in domain_groups::find_all(&mut conn).await? {
+                println!("domain\t{}", group);
+            }
+        }
+        AdminCommand::Groups(GroupCommand::Apply {
+            client_group,
+            domain_group,
+        }) => {
+            groups_applied::create(&mut conn, &client_group, &domain_group).await?;
+        }
+        AdminCommand::Groups(GroupCommand::Unapply {
+            client_group,
+            domain_group,
+        }) => {
+            groups_applied::delete(&mut conn, &client_group, &domain_group).await?;
+        }
+        AdminCommand::Policy(PolicyCommand::Export { file, format }) => {
+            let format = format
+                .or_else(|| file.as_deref().map(PolicyFormat::from_path))
+                .unwrap_or(PolicyFormat::Json);
+            let exported = format.render(&policy::export(&mut conn).await?)?;
+            match file {
+                Some(f) => fs::write(f, exported)?,
+                None => print!("{}", exported),
+            }
+        }
+        AdminCommand::Policy(PolicyCommand::Import {
+            file,
+            format,
+            dry_run,
+        }) => {
+            let format = format.unwrap_or_else(|| PolicyFormat::from_path(&file));
+            let imported = format.parse(&fs::read_to_string(file)?)?;
+
+            let mut tran = conn.begin().await?;
+            let diff = policy::diff(&policy::export(&mut tran).await?, &imported);
+            for line in policy_file::describe(&diff) {
+                println!("{}", line);
+            }
+
+            if diff.is_empty() {
+                println!("Nothing to change");
+            } else if !dry_run {
+                let id = snapshots::take(&mut tran, "before import").await?;
+                policy::replace(&mut tran, &imported).await?;
+                tran.commit().await?;
+                println!("Imported, roll back to snapshot {} to undo it", id);
+            }
+        }
+        AdminCommand::Policy(PolicyCommand::Snapshots) => {
+            for s in snapshots::find_all(&mut conn).await? {
+                println!("{}\t{}\t{}", s.id, s.taken_at, s.reason);
+            }
+        }
+        AdminCommand::Policy(PolicyCommand::Rollback { id }) => {
+            let restored = snapshots::find_policy(&mut conn, id)
+                .await?
+                .ok_or(AdminError::SnapshotNotFound(id))?;
+            let mut tran = conn.begin().await?;
+            snapshots::take(&mut tran, &format!("before rollback to {}", id)).await?;
+            policy::replace(&mut tran, &restored).await?;
+            tran.commit().await?;
+        }
+        AdminCommand::Users(UserCommand::List) => {
+            for u in users::find_all(&mut conn).await? {
+                println!("{}\t{}\t{} passkeys", u.display_name, u.role, u.keys.len());
+            }
+        }
+        AdminCommand::Users(UserCommand::ResetPasskey { name }) => {
+            if users::find_by_name(&mut conn, &name).await?.is_none() {
+                return Err(AdminError::UserNotFound(name));
+            }
+            let mut bytes = [0u8; 16];
+            SystemRandom::new().fill(&mut bytes)?;
+            let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+            users::reset_keys(&mut conn, &name, &recovery_token_hash(&token)).await?;
+            println!(
+                "Passkeys removed, register as {} with recovery token {} to set a new one. The token only works once.",
+                name, token
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Domains are stored fully qualified and lower case like the DNS server sees them
+fn fqdn(domain: &str) -> String {
+    let domain = domain.to_lowercase();
+    match domain.ends_with('.') {
+        true => domain,
+        false => domain + ".",
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    PolicyFile(#[from] PolicyFileError),
+
+    #[error("Unable to generate a recovery token")]
+    Random(#[from] Unspecified),
+
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+
+    #[error("No policy snapshot {0}")]
+    SnapshotNotFound(i64),
+
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    User(#[from] UserError),
+
+    #[error("No user named {0}")]
+    UserNotFound(String),
+}
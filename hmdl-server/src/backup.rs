@@ -0,0 +1,359 @@
+use chrono::Utc;
+use hmdl_db::{DatabaseHandle, SchemaError};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    error::Unspecified,
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use sqlx::{Connection, SqlitePool};
+use std::{
+    ffi::OsString,
+    io,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tokio::fs;
+
+/// Marks an encrypted backup, followed by the salt, nonce and the sealed database
+const MAGIC: &[u8] = b"HMDLENC1";
+const SALT_LEN: usize = 16;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// Backups are only rotated into a directory once one is set
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    pub directory: Option<PathBuf>,
+    pub interval_hours: u32,
+    /// Older backups in the directory are removed once there are more than this
+    pub keep: u32,
+    /// Encrypts every backup, the same passphrase is needed to restore them
+    pub passphrase: Option<String>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            interval_hours: 24,
+            keep: 7,
+            passphrase: None,
+        }
+    }
+}
+
+/// Takes consistent copies of the running database, shared by the rotation service, the
+/// admin endpoint and the command line
+pub struct Backups {
+    pool: SqlitePool,
+    database_path: PathBuf,
+    passphrase: Option<String>,
+    rand: SystemRandom,
+}
+
+impl Backups {
+    pub fn create(pool: SqlitePool, database_path: &Path, passphrase: Option<String>) -> Self {
+        Self {
+            pool,
+            database_path: database_path.to_path_buf(),
+            passphrase,
+            rand: SystemRandom::new(),
+        }
+    }
+
+    /// Timestamped so the names sort in the order the backups were taken
+    pub fn file_name(&self) -> String {
+        let extension = match self.passphrase {
+            Some(_) => "db.enc",
+            None => "db",
+        };
+        format!("hmdl-{}.{}", Utc::now().format("%Y%m%dT%H%M%SZ"), extension)
+    }
+
+    /// The whole backup in memory, encrypted if a passphrase is set. The plain copy is
+    /// made next to the database so it never leaves the database's directory.
+    pub async fn take(&self) -> Result<Vec<u8>, BackupError> {
+        let temp = self.temp_sibling(&self.database_path, "backup")?;
+
+        let copied = DatabaseHandle::backup(&self.pool, path_str(&temp)?).await;
+        let plain = match copied {
+            Ok(()) => fs::read(&temp).await,
+            Err(e) => {
+                let _ = fs::remove_file(&temp).await;
+                return Err(e.into());
+            }
+        };
+        fs::remove_file(&temp).await?;
+
+        match &self.passphrase {
+            Some(passphrase) => encrypt(&self.rand, passphrase, plain?),
+            None => Ok(plain?),
+        }
+    }
+
+    /// Written under a temporary name first so a half written backup is never mistaken
+    /// for a good one
+    pub async fn write_to(&self, path: &Path) -> Result<(), BackupError> {
+        let temp = self.temp_sibling(path, "partial")?;
+        fs::write(&temp, self.take().await?).await?;
+        fs::rename(&temp, path).await?;
+        Ok(())
+    }
+
+    /// Adds a timestamped backup to `directory` and removes all but the newest `keep`
+    pub async fn rotate(&self, directory: &Path, keep: u32) -> Result<PathBuf, BackupError> {
+        fs::create_dir_all(directory).await?;
+
+        let path = directory.join(self.file_name());
+        self.write_to(&path).await?;
+
+        let mut existing = Vec::new();
+        let mut entries = fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("hmdl-") && (name.ends_with(".db") || name.ends_with(".db.enc")) {
+                existing.push(entry.path());
+            }
+        }
+        existing.sort();
+
+        let excess = existing.len().saturating_sub(keep as usize);
+        for old in &existing[..excess] {
+            fs::remove_file(old).await?;
+        }
+
+        Ok(path)
+    }
+
+    /// Backups taken at the same time, from the endpoint and the rotation say, each get
+    /// their own temporary file
+    fn temp_sibling(&self, path: &Path, purpose: &str) -> Result<PathBuf, BackupError> {
+        let mut id = [0u8; 8];
+        self.rand.fill(&mut id)?;
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+
+        Ok(sibling(path, &format!(".{}-{}", purpose, id)))
+    }
+}
+
+/// Swaps the database for a backup once it has been decrypted and checked, it refuses while
+/// hmdl is running. The backup's schema is migrated forward the next time hmdl starts.
+pub async fn restore(
+    database_path: &Path,
+    backup: &Path,
+    passphrase: Option<&str>,
+) -> Result<(), BackupError> {
+    let mut contents = fs::read(backup).await?;
+    if contents.starts_with(MAGIC) {
+        let passphrase = passphrase.ok_or(BackupError::PassphraseRequired)?;
+        contents = decrypt(passphrase, contents)?;
+    }
+
+    let temp = sibling(database_path, ".restore");
+    fs::write(&temp, contents).await?;
+    if let Err(e) = DatabaseHandle::check_backup(path_str(&temp)?).await {
+        fs::remove_file(&temp).await?;
+        return Err(e.into());
+    }
+
+    //Holding the old database exclusively keeps hmdl from starting on it mid swap, and
+    //SQLite has already folded its WAL back in so there is no journal left to replay
+    let lock = match fs::metadata(database_path).await {
+        Ok(_) => match DatabaseHandle::lock_exclusive(path_str(database_path)?).await {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                fs::remove_file(&temp).await?;
+                return Err(e.into());
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    fs::rename(&temp, database_path).await?;
+    if let Some(lock) = lock {
+        lock.close().await?;
+    }
+
+    Ok(())
+}
+
+fn encrypt(
+    rand: &SystemRandom,
+    passphrase: &str,
+    mut plain: Vec<u8>,
+) -> Result<Vec<u8>, BackupError> {
+    let mut salt = [0u8; SALT_LEN];
+    rand.fill(&mut salt)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rand.fill(&mut nonce)?;
+
+    key(passphrase, &salt)?.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(MAGIC),
+        &mut plain,
+    )?;
+
+    let mut sealed = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + plain.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&plain);
+    Ok(sealed)
+}
+
+fn decrypt(passphrase: &str, sealed: Vec<u8>) -> Result<Vec<u8>, BackupError> {
+    let header = MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if sealed.len() < header {
+        return Err(BackupError::WrongPassphrase);
+    }
+    let (salt, rest) = sealed[MAGIC.len()..].split_at(SALT_LEN);
+    let (nonce, _) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+    let key = key(passphrase, salt)?;
+
+    let mut data = sealed[header..].to_vec();
+    let plain_len = key
+        .open_in_place(nonce, Aad::from(MAGIC), &mut data)
+        .map_err(|_| BackupError::WrongPassphrase)?
+        .len();
+    data.truncate(plain_len);
+    Ok(data)
+}
+
+fn key(passphrase: &str, salt: &[u8]) -> Result<LessSafeKey, Unspecified> {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ROUNDS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    Ok(LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &key)?))
+}
+
+/// `path` with `suffix` tacked on, kept in the same directory so renames stay atomic
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn path_str(path: &Path) -> Result<&str, BackupError> {
+    path.to_str()
+        .ok_or_else(|| BackupError::Path(path.to_path_buf()))
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("unable to encrypt the backup")]
+    Crypto(#[from] Unspecified),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("the backup is encrypted, backup.passphrase must be set to restore it")]
+    PassphraseRequired,
+
+    #[error("backups need a path that is valid UTF-8, got {0:?}")]
+    Path(PathBuf),
+
+    #[error(transparent)]
+    Schema(#[from] SchemaError),
+
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+
+    #[error("the backup couldn't be decrypted, check backup.passphrase")]
+    WrongPassphrase,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::query_scalar;
+
+    #[test]
+    fn test_encryption() -> Result<(), BackupError> {
+        let rand = SystemRandom::new();
+        let plain = b"SQLite format 3\0".to_vec();
+
+        let sealed = encrypt(&rand, "correct horse", plain.clone())?;
+        assert!(sealed.starts_with(MAGIC));
+        assert_ne!(&sealed[MAGIC.len()..], plain.as_slice());
+
+        assert_eq!(decrypt("correct horse", sealed.clone())?, plain);
+        assert!(matches!(
+            decrypt("battery staple", sealed),
+            Err(BackupError::WrongPassphrase)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_refuses_while_open() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("hmdl-restore-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        let database = dir.join("hmdl.db");
+        let backup_file = dir.join("backup.db");
+
+        let pool = DatabaseHandle::create(path_str(&database)?).await?;
+        let backups = Backups::create(pool.clone(), &database, None);
+        backups.write_to(&backup_file).await?;
+        sqlx::query("INSERT INTO client_groups (name) VALUES ('after')")
+            .execute(&pool)
+            .await?;
+
+        let refused = restore(&database, &backup_file, None).await;
+        assert!(matches!(
+            refused,
+            Err(BackupError::Schema(SchemaError::InUse))
+        ));
+        assert!(fs::metadata(sibling(&database, "-wal")).await.is_ok());
+
+        pool.close().await;
+        restore(&database, &backup_file, None).await?;
+        assert!(fs::metadata(sibling(&database, "-wal")).await.is_err());
+
+        let pool = DatabaseHandle::create(path_str(&database)?).await?;
+        let after: i64 = query_scalar("SELECT COUNT(*) FROM client_groups WHERE name = 'after'")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(after, 0);
+        pool.close().await;
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backup_replaces_existing() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("hmdl-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        let database = dir.join("hmdl.db");
+        let rotated = dir.join("hmdl-latest.db");
+
+        let pool = DatabaseHandle::create(path_str(&database)?).await?;
+        DatabaseHandle::backup(&pool, path_str(&rotated)?).await?;
+        sqlx::query("INSERT INTO client_groups (name) VALUES ('after')")
+            .execute(&pool)
+            .await?;
+        DatabaseHandle::backup(&pool, path_str(&rotated)?).await?;
+        pool.close().await;
+
+        let copy = DatabaseHandle::create(path_str(&rotated)?).await?;
+        let after: i64 = query_scalar("SELECT COUNT(*) FROM client_groups WHERE name = 'after'")
+            .fetch_one(&copy)
+            .await?;
+        assert_eq!(after, 1);
+        copy.close().await;
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use acme_lib::persist::{Persist, PersistKey};
 use acme_lib::Error;
-use sqlx::{query, SqlitePool};
+use hmdl_db::dao::acme_persist;
+use sqlx::SqlitePool;
 use std::sync::Arc;
 use tokio::runtime::Handle;
 
@@ -39,49 +40,12 @@ impl AcmePersistKey {
     }
 
     async fn put_key_value(&self, key: &str, value: &[u8]) -> Result<(), sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-
-        query!(
-            r#"
-                INSERT OR REPLACE INTO acme_persist (
-                    acme_key, 
-                    acme_value
-                ) VALUES (
-                    ?1,
-                    ?2
-                ) ON CONFLICT (acme_key) 
-                DO UPDATE 
-                SET 
-                    acme_value = ?2
-                "#,
-            key,
-            value
-        )
-        .execute(&mut conn)
-        .await?;
+        acme_persist::upsert(&self.pool, key, value).await?;
 
         Ok(())
     }
 
     async fn get_key_value(&self, key: &str) -> Result<Option<Vec<u8>>, sqlx::Error> {
-        let mut conn = self.pool.acquire().await?;
-
-        let rec = query!(
-            r#"
-                SELECT acme_value
-                FROM acme_persist
-                WHERE
-                    acme_key = ?1
-                "#,
-            key
-        )
-        .fetch_optional(&mut conn)
-        .await?;
-
-        if rec.is_some() {
-            Ok(Some(rec.unwrap().acme_value))
-        } else {
-            Ok(None)
-        }
+        acme_persist::find(&self.pool, key).await
     }
 }
@@ -0,0 +1,336 @@
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, io, net::IpAddr, path::PathBuf};
+use thiserror::Error;
+use tracing_subscriber::EnvFilter;
+
+use crate::admin::AdminCommand;
+use crate::backup::BackupConfig;
+use crate::coordinator::{ListenConfig, PrivilegeConfig};
+use crate::dns::DnsConfig;
+use crate::metrics::MetricsConfig;
+
+/// A filtering DNS server to limit children from bypassing parental blocks
+#[derive(Debug, Parser)]
+#[command(version = crate::GIT_VERSION, subcommand_precedence_over_arg = true)]
+pub struct Cli {
+    /// Run an administrative task against the database instead of starting the server
+    #[command(subcommand)]
+    pub command: Option<AdminCommand>,
+
+    /// Path to the sqlite database, kept as a positional argument for older launch scripts
+    #[arg(env = "HMDL_DATABASE")]
+    pub database: Option<PathBuf>,
+
+    /// TOML configuration file
+    #[arg(short, long, env = "HMDL_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    #[arg(long, env = "HMDL_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    #[arg(long, env = "HMDL_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// OTLP/gRPC collector to export traces to, such as http://localhost:4317
+    #[arg(long, env = "HMDL_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Comma separated upstream resolvers
+    #[arg(long, env = "HMDL_UPSTREAMS", value_delimiter = ',')]
+    pub upstreams: Option<Vec<IpAddr>>,
+
+    #[arg(long, env = "HMDL_DNS_PORT")]
+    pub dns_port: Option<u16>,
+
+    #[arg(long, env = "HMDL_HTTP_PORT")]
+    pub http_port: Option<u16>,
+
+    #[arg(long, env = "HMDL_HTTPS_PORT")]
+    pub https_port: Option<u16>,
+
+    /// Bearer token Prometheus must send to scrape /metrics
+    #[arg(long, env = "HMDL_METRICS_TOKEN", hide_env_values = true)]
+    pub metrics_token: Option<String>,
+
+    /// Encrypts backups, needed again to restore them
+    #[arg(long, env = "HMDL_BACKUP_PASSPHRASE", hide_env_values = true)]
+    pub backup_passphrase: Option<String>,
+}
+
+/// Everything the daemon reads at startup, every section falls back to its defaults
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HmdlConfig {
+    pub database_path: Option<PathBuf>,
+    pub listen: ListenConfig,
+    pub log: LogConfig,
+    pub dns: DnsConfig,
+    pub retention: RetentionConfig,
+    pub snapshots: SnapshotConfig,
+    pub backup: BackupConfig,
+    pub privileges: PrivilegeConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// An `EnvFilter` directive such as `info` or `hmdl=debug,sqlx=warn`
+    pub level: String,
+    pub format: LogFormat,
+    /// Per module levels layered on top of `level`, such as `"hmdl::dns" = "debug"`
+    pub modules: BTreeMap<String, String>,
+    /// Traces are only exported when a collector is configured
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+            modules: BTreeMap::new(),
+            otlp_endpoint: None,
+        }
+    }
+}
+
+impl LogConfig {
+    /// The combined `EnvFilter` directive for `level` and `modules`
+    pub fn directives(&self) -> String {
+        self.modules
+            .iter()
+            .fold(self.level.clone(), |mut directives, (module, level)| {
+                directives.push_str(&format!(",{}={}", module, level));
+                directives
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+    Journald,
+}
+
+/// How long collected data is kept before it is pruned
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub query_log_days: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self { query_log_days: 30 }
+    }
+}
+
+/// Scheduled policy snapshots, more are taken before bulk changes
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    pub interval_hours: u32,
+    /// Older snapshots are pruned once there are more than this
+    pub keep: u32,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            interval_hours: 24,
+            keep: 30,
+        }
+    }
+}
+
+impl HmdlConfig {
+    /// Reads the config file if there is one then lays the command line and environment
+    /// on top of it.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config: HmdlConfig = match &cli.config {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+            None => HmdlConfig::default(),
+        };
+
+        if let Some(database) = &cli.database {
+            config.database_path = Some(database.clone());
+        }
+        if let Some(level) = &cli.log_level {
+            config.log.level = level.clone();
+        }
+        if let Some(format) = cli.log_format {
+            config.log.format = format;
+        }
+        if let Some(endpoint) = &cli.otlp_endpoint {
+            config.log.otlp_endpoint = Some(endpoint.clone());
+        }
+        if let Some(upstreams) = &cli.upstreams {
+            config.dns.upstreams = upstreams.clone();
+        }
+        if let Some(port) = cli.dns_port {
+            config.listen.dns_port = port;
+        }
+        if let Some(port) = cli.http_port {
+            config.listen.http_port = port;
+        }
+        if let Some(port) = cli.https_port {
+            config.listen.https_port = port;
+        }
+        if let Some(token) = &cli.metrics_token {
+            config.metrics.token = Some(token.clone());
+        }
+        if let Some(passphrase) = &cli.backup_passphrase {
+            config.backup.passphrase = Some(passphrase.clone());
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn database_path(&self) -> Result<&str, ConfigError> {
+        self.database_path
+            .as_ref()
+            .and_then(|p| p.to_str())
+            .ok_or_else(|| ConfigError::Invalid("a database path is required".to_string()))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        self.database_path()?;
+
+        if EnvFilter::try_new(&self.log.level).is_err() {
+            return invalid("log.level is not a valid filter directive");
+        }
+        if EnvFilter::try_new(self.log.directives()).is_err() {
+            return invalid("log.modules must map module paths to levels");
+        }
+
+        let listen = &self.listen;
+        if listen.dns_port == 0 || listen.http_port == 0 || listen.https_port == 0 {
+            return invalid("listen ports must not be 0");
+        }
+        if listen.http_port == listen.https_port {
+            return invalid("listen.http_port and listen.https_port must differ");
+        }
+
+        let dns = &self.dns;
+        if dns.upstreams.is_empty() {
+            return invalid("dns.upstreams needs at least one resolver");
+        }
+        if let (Some(min), Some(max)) = (dns.min_ttl, dns.max_ttl) {
+            if min > max {
+                return invalid("dns.min_ttl is larger than dns.max_ttl");
+            }
+        }
+        if dns.rate_limit.client_queries_per_second <= 0.0
+            || dns.rate_limit.group_queries_per_second.unwrap_or(1.0) <= 0.0
+        {
+            return invalid("dns.rate_limit rates must be positive");
+        }
+        if !(0.0..=1.0).contains(&dns.tunnel_detection.flag_score) {
+            return invalid("dns.tunnel_detection.flag_score must be between 0 and 1");
+        }
+        if dns.tunnel_detection.interval_secs == 0 {
+            return invalid("dns.tunnel_detection.interval_secs must not be 0");
+        }
+        if dns.dnstap.file.is_some() && dns.dnstap.socket.is_some() {
+            return invalid("dns.dnstap takes either a file or a socket, not both");
+        }
+        if dns.dnstap.queue_size == 0 {
+            return invalid("dns.dnstap.queue_size must not be 0");
+        }
+
+        if self.privileges.group.is_some() && self.privileges.user.is_none() {
+            return invalid("privileges.group needs privileges.user to be set");
+        }
+
+        if matches!(&self.metrics.token, Some(t) if t.len() < 16) {
+            return invalid("metrics.token must be at least 16 characters");
+        }
+
+        if self.retention.query_log_days == 0 {
+            return invalid("retention.query_log_days must not be 0");
+        }
+
+        if self.snapshots.interval_hours == 0 {
+            return invalid("snapshots.interval_hours must not be 0");
+        }
+        if self.snapshots.keep == 0 {
+            return invalid("snapshots.keep must not be 0");
+        }
+
+        if self.backup.interval_hours == 0 {
+            return invalid("backup.interval_hours must not be 0");
+        }
+        if self.backup.keep == 0 {
+            return invalid("backup.keep must not be 0");
+        }
+        if matches!(&self.backup.passphrase, Some(p) if p.len() < 12) {
+            return invalid("backup.passphrase must be at least 12 characters");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config() -> Result<(), Box<dyn std::error::Error>> {
+        let mut config: HmdlConfig = toml::from_str(
+            r#"
+            database_path = "/var/lib/hmdl/data.db"
+
+            [log.modules]
+            "hmdl::dns" = "debug"
+
+            [listen]
+            dns_port = 5353
+
+            [dns]
+            upstreams = ["9.9.9.9"]
+            max_ttl = 600
+            "#,
+        )?;
+        config.validate()?;
+
+        assert_eq!(config.log.directives(), "info,hmdl::dns=debug");
+        assert_eq!(config.listen.dns_port, 5353);
+        assert_eq!(config.listen.https_port, 443);
+        assert_eq!(config.dns.upstreams, vec!["9.9.9.9".parse::<IpAddr>()?]);
+        assert_eq!(config.dns.max_ttl, Some(600));
+
+        config.dns.min_ttl = Some(900);
+        assert!(config.validate().is_err());
+
+        let cli = Cli::try_parse_from(["hmdl", "/tmp/data.db", "--dns-port", "5300"])?;
+        let config = HmdlConfig::load(&cli)?;
+        assert_eq!(config.database_path()?, "/tmp/data.db");
+        assert_eq!(config.listen.dns_port, 5300);
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
 use hmdl_db::DatabaseHandle;
+
+use crate::config::{ConfigError, HmdlConfig};
 use ring::rand::SystemRandom;
+use sqlx::SqlitePool;
+use std::{io, path::Path, sync::Arc};
 use thiserror::Error;
-use tokio::sync::broadcast::{self};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinError;
 
 /// The goal of the coordinator is to start up the various listening servers of HMDL
@@ -12,6 +17,8 @@ use tokio::task::JoinError;
 /// I'm changing course to instead use message pasisng to try and simplify all of this
 mod acme_provision_service;
 use acme_provision_service::AcmeProvisionService;
+mod backup_service;
+use backup_service::BackupService;
 mod cloudflare_a_service;
 use cloudflare_a_service::CloudflareAService;
 
@@ -20,118 +27,380 @@ pub use installation_status_service::HmdlSetup;
 use installation_status_service::InstallationStatusService;
 pub use installation_status_service::SetupStatus;
 
+mod health;
+pub use health::{Health, HealthFacts};
+
 mod ip_provider_service;
-use crate::dns::DnsServer;
+
+mod listeners;
+pub use listeners::{serve_each, serve_on, BoundSockets, ListenConfig, ListenError, ServerSockets};
+
+mod privileges;
+pub use privileges::{drop_privileges, PrivilegeConfig, PrivilegeError};
+
+mod systemd;
+pub use systemd::notify_ready;
+use systemd::ActivatedSockets;
+
+mod supervisor;
+pub use supervisor::{latest, ServiceHealth, ServiceState, ServiceStatus, Shutdown, DRAIN_TIMEOUT};
+use supervisor::{Service, Supervisor};
+
+mod retention_service;
+use retention_service::RetentionService;
+
+mod snapshot_service;
+use snapshot_service::SnapshotService;
+
+mod tunnel_analyzer_service;
+use tunnel_analyzer_service::TunnelAnalyzerService;
+
+mod upstream_health_service;
+pub use upstream_health_service::UpstreamHealth;
+use upstream_health_service::UpstreamHealthService;
+pub use upstream_health_service::UpstreamStatus;
+
+use crate::backup::Backups;
+use crate::dns::{DbWriter, DnsServer, DnstapWriter, RateLimiter};
+use crate::logging::LogHandle;
+use crate::metrics::Metrics;
 use crate::web::endpoints::{Endpoints, EndpointsError};
 use crate::web::install_endpoints::InstallEndpoints;
 
 pub use self::ip_provider_service::{IpProvderService, IpProvderServiceError};
 
+const INSTALLATION_STATUS: Service = Service {
+    name: "Install Status Service",
+    depends_on: &[],
+    drains: false,
+};
+const IP_PROVIDER: Service = Service {
+    name: "IP Provider",
+    depends_on: &[],
+    drains: false,
+};
+const DNSTAP: Service = Service {
+    name: "Dnstap Writer",
+    depends_on: &[],
+    drains: true,
+};
+const DB_WRITER: Service = Service {
+    name: "Database Writer",
+    depends_on: &[],
+    drains: true,
+};
+const DNS_SERVER: Service = Service {
+    name: "DNS Server",
+    depends_on: &[DNSTAP.name, DB_WRITER.name],
+    drains: true,
+};
+const INSTALL_ENDPOINTS: Service = Service {
+    name: "Install Endpoints",
+    depends_on: &[INSTALLATION_STATUS.name],
+    drains: true,
+};
+const CLOUDFLARE_A: Service = Service {
+    name: "Cloudflare A/AAAA record service",
+    depends_on: &[IP_PROVIDER.name, INSTALLATION_STATUS.name],
+    drains: false,
+};
+const ACME_PROVISION: Service = Service {
+    name: "Acme Service",
+    depends_on: &[INSTALLATION_STATUS.name],
+    drains: false,
+};
+const ENDPOINTS: Service = Service {
+    name: "Endpoints",
+    depends_on: &[ACME_PROVISION.name],
+    drains: true,
+};
+const UPSTREAM_HEALTH: Service = Service {
+    name: "Upstream Health Service",
+    depends_on: &[],
+    drains: false,
+};
+const TUNNEL_ANALYZER: Service = Service {
+    name: "Tunnel Analyzer Service",
+    depends_on: &[],
+    drains: false,
+};
+const RETENTION: Service = Service {
+    name: "Retention Service",
+    depends_on: &[],
+    drains: false,
+};
+const SNAPSHOTS: Service = Service {
+    name: "Snapshot Service",
+    depends_on: &[],
+    drains: false,
+};
+const BACKUP: Service = Service {
+    name: "Backup Service",
+    depends_on: &[],
+    drains: false,
+};
+const WATCHDOG: Service = Service {
+    name: "Systemd Watchdog",
+    depends_on: &[],
+    drains: false,
+};
+
 pub struct Coordinator {
+    pool: SqlitePool,
+    reader: SqlitePool,
+    health: Health,
     installation_status_service: InstallationStatusService,
     ip_provider_service: IpProvderService,
     dns_server_service: DnsServer,
+    dnstap_writer: DnstapWriter,
+    db_writer: DbWriter,
     install_endpoints: InstallEndpoints,
     cloudflare_a_service: CloudflareAService,
     acme_provision_service: AcmeProvisionService,
     endpoints: Endpoints,
+    upstream_health_service: UpstreamHealthService,
+    tunnel_analyzer_service: TunnelAnalyzerService,
+    retention_service: RetentionService,
+    snapshot_service: SnapshotService,
+    backup_service: BackupService,
 }
 
 impl Coordinator {
-    pub async fn create(path: &str) -> Result<Coordinator, CoordinatorError> {
+    /// Takes over any sockets systemd passed in. When privileges will be dropped the rest
+    /// are bound now as well since port 53 can't be bound afterwards.
+    pub fn bind(config: &HmdlConfig) -> Result<ServerSockets, CoordinatorError> {
+        let listen = &config.listen;
+        let prebind = config.privileges.user.is_some();
+        let mut activated = ActivatedSockets::take()?;
+
+        let mut bind = |name: &str, port: u16, udp: bool| match activated.remove(name) {
+            Some(sockets) => Ok(Some(sockets)),
+            None if prebind => BoundSockets::bind(listen, port, udp).map(Some),
+            None => Ok(None),
+        };
+
+        Ok(ServerSockets {
+            dns: bind("dns", listen.dns_port, true)?,
+            http: bind("http", listen.http_port, false)?,
+            https: bind("https", listen.https_port, false)?,
+        })
+    }
+
+    pub async fn create(
+        config: &HmdlConfig,
+        sockets: ServerSockets,
+        log_handle: LogHandle,
+    ) -> Result<Coordinator, CoordinatorError> {
         let rand_gen = SystemRandom::new();
-        let pool = DatabaseHandle::create(path).await?;
+        let database_path = config.database_path()?;
+        let pool = DatabaseHandle::create(database_path).await?;
+        let reader = DatabaseHandle::create_reader(database_path).await?;
+        let dns_config = &config.dns;
+        let listen_config = config.listen.clone();
+        let health = Health::default();
+        let metrics = Arc::new(Metrics::create()?);
+        let backups = Arc::new(Backups::create(
+            pool.clone(),
+            Path::new(database_path),
+            config.backup.passphrase.clone(),
+        ));
+        let rate_limiter = Arc::new(RateLimiter::create(
+            reader.clone(),
+            dns_config.rate_limit.clone(),
+        ));
 
         let installation_status_service = InstallationStatusService::create(pool.clone());
         let ip_provider_service = IpProvderService::create();
-        let dns_server_service = DnsServer::create(pool.clone()).await;
-        let install_endpoints = InstallEndpoints::create(pool.clone());
-        let cloudflare_a_service = CloudflareAService::create();
-        let acme_provision_service = AcmeProvisionService::create(pool.clone()).await;
-        let endpoints = Endpoints::create(pool.clone(), rand_gen.clone())?;
+        let dnstap_writer = DnstapWriter::create(dns_config.dnstap.clone());
+        let db_writer = DbWriter::create(pool.clone());
+        let dns_server_service = DnsServer::create(
+            reader.clone(),
+            db_writer.queue(),
+            dns_config,
+            listen_config.clone(),
+            sockets.dns,
+            rate_limiter.clone(),
+            health.facts.clone(),
+            metrics.clone(),
+            dnstap_writer.dnstap(),
+        )
+        .await;
+        let install_endpoints = InstallEndpoints::create(
+            pool.clone(),
+            health.clone(),
+            listen_config.clone(),
+            sockets.http,
+        );
+        let cloudflare_a_service = CloudflareAService::create(health.facts.clone());
+        let acme_provision_service =
+            AcmeProvisionService::create(pool.clone(), health.facts.clone()).await;
+        let endpoints = Endpoints::create(
+            pool.clone(),
+            reader.clone(),
+            rand_gen.clone(),
+            rate_limiter,
+            health.clone(),
+            metrics,
+            backups.clone(),
+            config.metrics.token.clone(),
+            log_handle,
+            listen_config,
+            sockets.https,
+        )?;
+        let upstream_health_service =
+            UpstreamHealthService::create(dns_config.upstreams.clone(), health.upstreams.clone());
+        let tunnel_analyzer_service =
+            TunnelAnalyzerService::create(pool.clone(), dns_config.tunnel_detection.clone());
+        let retention_service = RetentionService::create(pool.clone(), config.retention.clone());
+        let snapshot_service = SnapshotService::create(pool.clone(), config.snapshots.clone());
+        let backup_service = BackupService::create(backups, config.backup.clone());
 
         Ok(Self {
+            pool,
+            reader,
+            health,
             installation_status_service,
             ip_provider_service,
             dns_server_service,
+            dnstap_writer,
+            db_writer,
             install_endpoints,
             cloudflare_a_service,
             acme_provision_service,
             endpoints,
+            upstream_health_service,
+            tunnel_analyzer_service,
+            retention_service,
+            snapshot_service,
+            backup_service,
         })
     }
 
-    pub async fn start(&mut self) -> Result<(), CoordinatorError> {
-        let (install_refresh_sender, install_refresh_reciever) = broadcast::channel(1);
-        let (install_stat_sender, install_stat_reciever) = broadcast::channel(1);
-        let install_stat_reciever2 = install_stat_sender.subscribe();
-        let (ip_provider_sender, ip_provider_reciever) = broadcast::channel(1);
-        //let ip_provider_reciever2 = ip_provider_sender.subscribe();
-        let install_stat_reciever3 = install_stat_sender.subscribe();
-        let (tls_config_sender, tls_config_reciever) = broadcast::channel(1);
+    /// Runs every service under a supervisor until SIGTERM or ctrl-c, a failing service
+    /// is restarted on its own instead of taking the rest down with it.
+    pub async fn start(&self) -> Result<(), CoordinatorError> {
+        let (install_refresh_sender, _) = broadcast::channel(1);
+        let (install_stat_sender, install_stat_reciever) = watch::channel(None);
+        let (ip_provider_sender, ip_provider_reciever) = watch::channel(None);
+        let (tls_config_sender, tls_config_reciever) = watch::channel(None);
+        let (shutdown_sender, shutdown) = watch::channel(false);
 
-        //let (https_ready_sender, https_ready_reciever) = broadcast::channel(1);
+        let supervisor = Supervisor::create(self.health.services.clone(), Shutdown::new(shutdown));
+        let (install_stat_sender, ip_provider_sender, tls_config_sender) = (
+            &install_stat_sender,
+            &ip_provider_sender,
+            &tls_config_sender,
+        );
+
+        let services = async {
+            tokio::join!(
+                supervisor.run(INSTALLATION_STATUS, |_| {
+                    self.installation_status_service
+                        .start(install_refresh_sender.subscribe(), install_stat_sender)
+                }),
+                supervisor.run(IP_PROVIDER, |_| {
+                    self.ip_provider_service.start(ip_provider_sender)
+                }),
+                supervisor.run(DNSTAP, |shutdown| self.dnstap_writer.start(shutdown)),
+                supervisor.run(DB_WRITER, |shutdown| self.db_writer.start(shutdown)),
+                supervisor.run(DNS_SERVER, |shutdown| {
+                    self.dns_server_service
+                        .start(ip_provider_reciever.clone(), shutdown)
+                }),
+                supervisor.run(INSTALL_ENDPOINTS, |shutdown| {
+                    self.install_endpoints.start(
+                        install_stat_reciever.clone(),
+                        install_refresh_sender.clone(),
+                        ip_provider_reciever.clone(),
+                        shutdown,
+                    )
+                }),
+                supervisor.run(CLOUDFLARE_A, |_| {
+                    self.cloudflare_a_service
+                        .start(ip_provider_reciever.clone(), install_stat_reciever.clone())
+                }),
+                supervisor.run(ACME_PROVISION, |_| {
+                    self.acme_provision_service
+                        .start(install_stat_reciever.clone(), tls_config_sender)
+                }),
+                supervisor.run(ENDPOINTS, |shutdown| {
+                    self.endpoints.start(
+                        tls_config_reciever.clone(),
+                        ip_provider_reciever.clone(),
+                        shutdown,
+                    )
+                }),
+                supervisor.run(UPSTREAM_HEALTH, |_| self.upstream_health_service.start()),
+                supervisor.run(TUNNEL_ANALYZER, |_| self.tunnel_analyzer_service.start()),
+                supervisor.run(RETENTION, |_| self.retention_service.start()),
+                supervisor.run(SNAPSHOTS, |_| self.snapshot_service.start()),
+                supervisor.run(BACKUP, |_| self.backup_service.start()),
+                supervisor.run(WATCHDOG, |_| systemd::watchdog()),
+            )
+        };
+        tokio::pin!(services);
 
         tokio::select! {
-            r = self.installation_status_service.start(install_refresh_reciever, install_stat_sender) => {
-                match r {
-                    Ok(()) => tracing::debug!("Install Status Service exited."),
-                    Err(e) => tracing::error!("Install Status Service had an error |{}", e)
-                }
-            }
-            r = self.ip_provider_service.start(ip_provider_sender) => {
-                match r {
-                    Ok(()) => tracing::debug!("IP Provider exited."),
-                    Err(e) => tracing::error!("IP Provider had an error |{}", e)
-                }
-            }
-            r = self.dns_server_service.start() => {
-                match r {
-                    Ok(()) => tracing::debug!("DNS Server exited."),
-                    Err(e) => tracing::error!("DNS Server had an error |{}", e)
-                }
-            }
-            r = self.install_endpoints.start(install_stat_reciever, install_refresh_sender) => {
-                match r {
-                    Ok(()) => tracing::debug!("Install Endpoints exited."),
-                    Err(e) => tracing::error!("Install Endpoints had an error |{}", e)
-                }
+            _ = &mut services => {
+                tracing::warn!("Every service has exited.");
             }
-            r = self.cloudflare_a_service.start(ip_provider_reciever, install_stat_reciever2) => {
-                match r {
-                    Ok(()) => tracing::debug!("Cloudflare A/AAAA record service exited."),
-                    Err(e) => tracing::error!("Cloudflare A/AAAA had an error |{}", e)
-                }
+            _ = Self::shutdown_signal() => {
+                tracing::warn!("Shutting down.");
+                systemd::notify_stopping();
+                shutdown_sender.send_replace(true);
+                services.await;
             }
-            r = self.acme_provision_service.start(install_stat_reciever3, tls_config_sender) => {
-                match r {
-                    Ok(()) => tracing::debug!("Acme Service exited."),
-                    Err(e) => tracing::error!("Acme Service had an error |{}", e)
-                }
+        }
+
+        self.reader.close().await;
+        self.pool.close().await;
+        Ok(())
+    }
+
+    async fn shutdown_signal() {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Unable to listen for SIGTERM |{}", e);
+                return std::future::pending().await;
             }
-            r = self.endpoints.start(tls_config_reciever) => {
-                match r {
-                    Ok(()) => tracing::debug!("Endpoints exited."),
-                    Err(e) => tracing::error!("Endpoints had an error |{}", e)
+        };
+
+        tokio::select! {
+            _ = terminate.recv() => {}
+            r = tokio::signal::ctrl_c() => {
+                if let Err(e) = r {
+                    tracing::error!("Unable to listen for ctrl-c |{}", e);
+                    return std::future::pending().await;
                 }
             }
         }
-
-        Ok(())
     }
 }
 
 #[derive(Debug, Error)]
 pub enum CoordinatorError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
     #[error(transparent)]
     Endpoints(#[from] EndpointsError),
 
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
     #[error(transparent)]
     IpTrackingError(#[from] IpProvderServiceError),
 
     #[error(transparent)]
     JoinError(#[from] JoinError),
 
+    #[error(transparent)]
+    Listen(#[from] ListenError),
+
+    #[error(transparent)]
+    Metrics(#[from] prometheus::Error),
+
     #[error(transparent)]
     SqlxError(#[from] sqlx::Error),
 }
@@ -7,10 +7,7 @@ use sqlx::SqlitePool;
 use thiserror::Error;
 use tokio::{
     runtime::Handle,
-    sync::broadcast::{
-        error::{RecvError, SendError},
-        Receiver, Sender,
-    },
+    sync::watch::{self, error::RecvError},
     task::JoinError,
 };
 
@@ -18,38 +15,37 @@ use crate::certificate::{
     create_proof_domain, AcmePersistKey, CloudflareClient, CloudflareClientError,
 };
 
-use super::{HmdlSetup, SetupStatus};
+use super::{latest, HealthFacts, HmdlSetup, SetupStatus};
 
 const CERT_REFRESH: Duration = Duration::new(6 * 60 * 60, 0);
 
 pub struct AcmeProvisionService {
     handle: Arc<Handle>,
     persist: AcmePersistKey,
+    health_facts: HealthFacts,
 }
 
 impl AcmeProvisionService {
-    pub async fn create(pool: SqlitePool) -> Self {
+    pub async fn create(pool: SqlitePool, health_facts: HealthFacts) -> Self {
         let handle = Arc::new(Handle::current());
         Self {
             handle: handle.clone(),
             persist: AcmePersistKey::create(pool, handle),
+            health_facts,
         }
     }
 
     pub async fn start(
         &self,
-        mut install_stat_reciever: Receiver<SetupStatus>,
-        tls_config_sender: Sender<(RustlsConfig, HmdlSetup)>,
+        mut install_stat_reciever: watch::Receiver<Option<SetupStatus>>,
+        tls_config_sender: &watch::Sender<Option<(RustlsConfig, HmdlSetup)>>,
     ) -> Result<(), AcmeProvisionServiceError> {
-        let settings: Result<HmdlSetup, RecvError> = loop {
-            let set_val = install_stat_reciever.recv().await?;
-            if let SetupStatus::InProgress(s) = set_val {
-                break Ok(s);
-            } else if let SetupStatus::Setup(s) = set_val {
-                break Ok(s);
+        let settings = loop {
+            match latest(&mut install_stat_reciever).await? {
+                SetupStatus::InProgress(s) | SetupStatus::Setup(s) => break s,
+                SetupStatus::NotSetup => install_stat_reciever.changed().await?,
             }
         };
-        let settings = settings?;
 
         let persist = self.persist.clone();
         let settings2 = settings.clone();
@@ -57,6 +53,8 @@ impl AcmeProvisionService {
             .handle
             .spawn_blocking(move || Self::get_certificate(persist, settings2))
             .await??;
+        self.health_facts
+            .set_certificate_days_left(acme_cert.valid_days_left());
 
         let rustls_certs = vec![rustls::Certificate(acme_cert.certificate_der())];
         let rustls_private_key = rustls::PrivateKey(acme_cert.private_key_der());
@@ -67,9 +65,20 @@ impl AcmeProvisionService {
                 .with_no_client_auth()
                 .with_single_cert(rustls_certs, rustls_private_key)?,
         );
-        let rusttls_cfg = RustlsConfig::from_config(server_config);
 
-        tls_config_sender.send((rusttls_cfg.clone(), settings.clone()))?;
+        //After a restart the HTTPS server is still holding the first config, so update that
+        let current = tls_config_sender.borrow().clone();
+        let rusttls_cfg = match current {
+            Some((rusttls_cfg, _)) => {
+                rusttls_cfg.reload_from_config(server_config);
+                rusttls_cfg
+            }
+            None => {
+                let rusttls_cfg = RustlsConfig::from_config(server_config);
+                tls_config_sender.send_replace(Some((rusttls_cfg.clone(), settings.clone())));
+                rusttls_cfg
+            }
+        };
 
         loop {
             tokio::time::sleep(CERT_REFRESH).await;
@@ -80,6 +89,8 @@ impl AcmeProvisionService {
                 .handle
                 .spawn_blocking(move || Self::get_certificate(persist, settings2))
                 .await??;
+            self.health_facts
+                .set_certificate_days_left(acme_cert.valid_days_left());
 
             let rustls_certs = vec![acme_cert.certificate_der()];
 
@@ -183,6 +194,4 @@ pub enum AcmeProvisionServiceError {
     Recv(#[from] RecvError),
     #[error(transparent)]
     Rustls(#[from] rustls::Error),
-    #[error(transparent)]
-    Send(#[from] SendError<(RustlsConfig, HmdlSetup)>),
 }
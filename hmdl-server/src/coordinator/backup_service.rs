@@ -0,0 +1,37 @@
+use std::{sync::Arc, time::Duration};
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+use crate::backup::{BackupConfig, BackupError, Backups};
+
+/// Rotates backups into the configured directory
+pub struct BackupService {
+    backups: Arc<Backups>,
+    config: BackupConfig,
+}
+
+impl BackupService {
+    pub fn create(backups: Arc<Backups>, config: BackupConfig) -> Self {
+        Self { backups, config }
+    }
+
+    pub async fn start(&self) -> Result<(), BackupError> {
+        let directory = match &self.config.directory {
+            Some(d) => d,
+            //Park forever so the coordinator doesn't see this as an exit
+            None => return std::future::pending().await,
+        };
+
+        let period = Duration::from_secs(u64::from(self.config.interval_hours) * 60 * 60);
+        let mut duration = interval_at(Instant::now() + period, period);
+        duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            duration.tick().await;
+
+            match self.backups.rotate(directory, self.config.keep).await {
+                Ok(path) => tracing::info!("Backed up the database to {:?}", path),
+                Err(e) => tracing::error!("Unable to back up the database |{}", e),
+            }
+        }
+    }
+}
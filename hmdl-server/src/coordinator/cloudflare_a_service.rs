@@ -2,28 +2,30 @@ use std::{collections::HashSet, io, net::IpAddr};
 use thiserror::Error;
 use tokio::{
     runtime::Handle,
-    sync::broadcast::{error::RecvError, Receiver},
+    sync::watch::{self, error::RecvError},
     task::JoinError,
 };
 
 use crate::certificate::{CloudflareClient, CloudflareClientError};
 
-use super::{HmdlSetup, SetupStatus};
+use super::{latest, HealthFacts, HmdlSetup, SetupStatus};
 
-pub struct CloudflareAService {}
+pub struct CloudflareAService {
+    health_facts: HealthFacts,
+}
 
 impl CloudflareAService {
-    pub fn create() -> Self {
-        Self {}
+    pub fn create(health_facts: HealthFacts) -> Self {
+        Self { health_facts }
     }
 
     pub async fn start(
         &self,
-        mut ip_changed: Receiver<HashSet<IpAddr>>,
-        mut install_stat_reciever: Receiver<SetupStatus>,
+        mut ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+        mut install_stat_reciever: watch::Receiver<Option<SetupStatus>>,
     ) -> Result<(), CloudflareAServiceError> {
         let (initial_ips, install_status) =
-            tokio::join!(ip_changed.recv(), install_stat_reciever.recv());
+            tokio::join!(latest(&mut ip_changed), latest(&mut install_stat_reciever));
 
         let mut ips: HashSet<IpAddr> = initial_ips?
             .into_iter()
@@ -35,17 +37,20 @@ impl CloudflareAService {
         loop {
             if let SetupStatus::InProgress(settings) = &status {
                 Self::update_ips(settings, &ips).await?;
+                self.health_facts.set_cloudflare_synced();
             } else if let SetupStatus::Setup(settings) = &status {
                 Self::update_ips(settings, &ips).await?;
+                self.health_facts.set_cloudflare_synced();
             }
             tokio::select!(
-                Ok(new_ips) = ip_changed.recv() => {
+                Ok(()) = ip_changed.changed() => {
                     tracing::debug!("Got new IPs");
-                    ips = new_ips;
+                    ips = latest(&mut ip_changed).await?;
                 }
-                new_install_stat = install_stat_reciever.recv() => {
+                r = install_stat_reciever.changed() => {
                     tracing::debug!("Got new install status");
-                    status = new_install_stat?;
+                    r?;
+                    status = latest(&mut install_stat_reciever).await?;
                 }
             );
         }
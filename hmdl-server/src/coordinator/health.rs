@@ -0,0 +1,45 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+use super::{ServiceHealth, UpstreamHealth};
+
+/// Everything the health and readiness endpoints report on, shared between the
+/// services that record it and the web servers that serve it.
+#[derive(Clone, Default)]
+pub struct Health {
+    pub upstreams: UpstreamHealth,
+    pub services: ServiceHealth,
+    pub facts: HealthFacts,
+}
+
+/// Things services note about themselves as they run
+#[derive(Clone, Default)]
+pub struct HealthFacts {
+    facts: Arc<Mutex<Facts>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Facts {
+    pub dns_listening: bool,
+    pub certificate_expires: Option<DateTime<Utc>>,
+    pub cloudflare_last_sync: Option<DateTime<Utc>>,
+}
+
+impl HealthFacts {
+    pub fn facts(&self) -> Facts {
+        self.facts.lock().unwrap().clone()
+    }
+
+    pub fn set_dns_listening(&self, listening: bool) {
+        self.facts.lock().unwrap().dns_listening = listening;
+    }
+
+    pub fn set_certificate_days_left(&self, days: i64) {
+        self.facts.lock().unwrap().certificate_expires = Some(Utc::now() + Duration::days(days));
+    }
+
+    pub fn set_cloudflare_synced(&self) {
+        self.facts.lock().unwrap().cloudflare_last_sync = Some(Utc::now());
+    }
+}
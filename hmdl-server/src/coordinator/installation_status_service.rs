@@ -1,7 +1,8 @@
+use hmdl_db::dao::settings;
 use serde::Deserialize;
-use sqlx::{query, SqliteConnection, SqlitePool};
+use sqlx::{SqliteConnection, SqlitePool};
 use thiserror::Error;
-use tokio::sync::broadcast::{error::SendError, Receiver, Sender};
+use tokio::sync::{broadcast::Receiver, watch};
 
 pub struct InstallationStatusService {
     pool: SqlitePool,
@@ -15,18 +16,18 @@ impl InstallationStatusService {
     pub async fn start(
         &self,
         mut request_refresh: Receiver<()>,
-        installation_status: Sender<SetupStatus>,
+        installation_status: &watch::Sender<Option<SetupStatus>>,
     ) -> Result<(), InstallationStatusServiceError> {
         tracing::debug!("Checking installation status");
         let mut conn = self.pool.acquire().await?;
 
-        installation_status.send(Self::setup_status_db_check(&mut conn).await?)?;
+        installation_status.send_replace(Some(Self::setup_status_db_check(&mut conn).await?));
 
         loop {
             tokio::select! {
                 Ok(()) = request_refresh.recv() => {
                     tracing::info!("Requested refresh of setup status.");
-                    installation_status.send(Self::setup_status_db_check(&mut conn).await?)?;
+                    installation_status.send_replace(Some(Self::setup_status_db_check(&mut conn).await?));
                 }
             }
         }
@@ -35,17 +36,7 @@ impl InstallationStatusService {
     async fn setup_status_db_check(
         conn: &mut SqliteConnection,
     ) -> Result<SetupStatus, InstallationStatusServiceError> {
-        let setting_record = query!(
-            r#"
-            SELECT application_domain, cloudflare_api_token, acme_email, https_started_once
-            FROM hmdl_settings
-            WHERE lock_column == true
-            "#
-        )
-        .fetch_optional(conn)
-        .await?;
-
-        if let Some(rec) = setting_record {
+        if let Some(rec) = settings::find(conn).await? {
             let settings = HmdlSetup {
                 application_domain: rec.application_domain,
                 cloudflare_api_token: rec.cloudflare_api_token,
@@ -79,9 +70,6 @@ pub struct HmdlSetup {
 
 #[derive(Debug, Error)]
 pub enum InstallationStatusServiceError {
-    #[error(transparent)]
-    Send(#[from] SendError<SetupStatus>),
-
     #[error(transparent)]
     Sqlx(#[from] sqlx::Error),
 }
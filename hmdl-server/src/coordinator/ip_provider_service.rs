@@ -7,7 +7,7 @@ use std::{
 use local_ip_address::list_afinet_netifas;
 use thiserror::Error;
 use tokio::{
-    sync::broadcast::Sender,
+    sync::watch,
     time::{interval, MissedTickBehavior},
 };
 
@@ -26,7 +26,7 @@ impl IpProvderService {
     /// TODO: Switch to a model that asks the underlying operating system
     pub async fn start(
         &self,
-        ip_changed: Sender<HashSet<IpAddr>>,
+        ip_changed: &watch::Sender<Option<HashSet<IpAddr>>>,
     ) -> Result<(), IpProvderServiceError> {
         let mut duration = interval(Duration::from_millis(60 * 1000));
         duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
@@ -34,7 +34,7 @@ impl IpProvderService {
 
         //Always send the starting IPs
         tracing::debug!("Sending Initial IP addresses");
-        ip_changed.send(current_ips.clone()).ok();
+        ip_changed.send_replace(Some(current_ips.clone()));
 
         loop {
             duration.tick().await;
@@ -43,7 +43,7 @@ impl IpProvderService {
             if current_ips != new_ips {
                 current_ips = new_ips;
                 tracing::info!("IP addresses changed, broadcasting");
-                ip_changed.send(current_ips.clone()).ok();
+                ip_changed.send_replace(Some(current_ips.clone()));
             }
         }
     }
@@ -0,0 +1,268 @@
+use local_ip_address::list_afinet_netifas;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+};
+use thiserror::Error;
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinSet},
+};
+
+use super::{IpProvderService, IpProvderServiceError, Shutdown};
+
+/// Where the DNS, HTTP and HTTPS servers listen
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ListenConfig {
+    /// Specific addresses to listen on, they are bound once they show up on the machine
+    pub addresses: Vec<IpAddr>,
+
+    /// Network interfaces to listen on, their addresses are followed as they change.
+    /// With this and `addresses` both empty every address is used.
+    pub interfaces: Vec<String>,
+
+    pub dns_port: u16,
+    pub http_port: u16,
+    pub https_port: u16,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            addresses: vec![],
+            interfaces: vec![],
+            dns_port: 53,
+            http_port: 80,
+            https_port: 443,
+        }
+    }
+}
+
+impl ListenConfig {
+    pub fn is_wildcard(&self) -> bool {
+        self.addresses.is_empty() && self.interfaces.is_empty()
+    }
+
+    /// The addresses to bind for a port as the machine currently stands
+    pub fn socket_addrs(&self, port: u16) -> Result<Vec<SocketAddr>, ListenError> {
+        if self.is_wildcard() {
+            return Ok(vec![SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                port,
+            )]);
+        }
+
+        let mut addrs: Vec<SocketAddr> = list_afinet_netifas()
+            .map_err(IpProvderServiceError::from)?
+            .into_iter()
+            .filter(|(name, ip)| self.interfaces.contains(name) || self.addresses.contains(ip))
+            .filter(|(_, ip)| match ip {
+                //These need a scope id to bind so they are skipped like the IP provider does
+                IpAddr::V6(v6) => !IpProvderService::has_unicast_link_local_scope(*v6),
+                IpAddr::V4(_) => true,
+            })
+            .map(|(_, ip)| SocketAddr::new(ip, port))
+            .collect();
+
+        addrs.sort();
+        addrs.dedup();
+        Ok(addrs)
+    }
+}
+
+/// Sockets that were bound before a server started, like the ones systemd passes in
+#[derive(Debug, Default)]
+pub struct BoundSockets {
+    pub udp: Vec<UdpSocket>,
+    pub tcp: Vec<TcpListener>,
+}
+
+impl BoundSockets {
+    /// Binds every address the config resolves to right now, these are not followed
+    /// if the machine's addresses change later.
+    pub fn bind(listen: &ListenConfig, port: u16, udp: bool) -> Result<Self, ListenError> {
+        let mut sockets = Self::default();
+        for addr in listen.socket_addrs(port)? {
+            let bound = Self::bind_addr(addr, udp)?;
+            sockets.udp.extend(bound.udp);
+            sockets.tcp.extend(bound.tcp);
+        }
+        Ok(sockets)
+    }
+
+    pub fn bind_addr(addr: SocketAddr, udp: bool) -> io::Result<Self> {
+        let mut sockets = Self::default();
+        if udp {
+            let socket = UdpSocket::bind(addr)?;
+            socket.set_nonblocking(true)?;
+            sockets.udp.push(socket);
+        }
+
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        sockets.tcp.push(listener);
+        Ok(sockets)
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            udp: self
+                .udp
+                .iter()
+                .map(|s| s.try_clone())
+                .collect::<io::Result<_>>()?,
+            tcp: self
+                .tcp
+                .iter()
+                .map(|s| s.try_clone())
+                .collect::<io::Result<_>>()?,
+        })
+    }
+}
+
+/// The sockets each server was started with, a server without any binds its own
+#[derive(Debug, Default)]
+pub struct ServerSockets {
+    pub dns: Option<BoundSockets>,
+    pub http: Option<BoundSockets>,
+    pub https: Option<BoundSockets>,
+}
+
+/// Runs a server on each already bound socket, these are never rebound
+pub async fn serve_each<T, F, Fut, E>(sockets: Vec<T>, serve: F) -> Result<(), E>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: From<ListenError> + Send + 'static,
+{
+    let mut servers = JoinSet::new();
+    for socket in sockets {
+        servers.spawn(serve(socket));
+    }
+
+    while let Some(r) = servers.join_next().await {
+        r.map_err(ListenError::from)??;
+    }
+
+    Ok(())
+}
+
+/// Runs a server on every address configured for the port, rebinding them all when the
+/// IP provider reports the machine's addresses changed. The servers are expected to
+/// watch the shutdown themselves, once it starts nothing is rebound.
+pub async fn serve_on<F, Fut, E>(
+    listen: &ListenConfig,
+    port: u16,
+    mut ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+    mut shutdown: Shutdown,
+    serve: F,
+) -> Result<(), E>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: From<ListenError> + Send + 'static,
+{
+    //The wildcard address never changes so there is nothing to follow
+    let mut watching = !listen.is_wildcard();
+
+    loop {
+        let addrs = listen.socket_addrs(port)?;
+        if addrs.is_empty() {
+            tracing::warn!("None of the configured addresses for port {} are up", port);
+        }
+
+        let mut servers = JoinSet::new();
+        for addr in &addrs {
+            tracing::info!("Listening on {}", addr);
+            servers.spawn(serve(*addr));
+        }
+
+        loop {
+            let stopping = shutdown.is_requested();
+            tokio::select! {
+                Some(r) = servers.join_next() => {
+                    r.map_err(ListenError::from)??;
+                }
+                r = ip_changed.changed(), if watching && !stopping => {
+                    match r {
+                        Ok(()) => {
+                            if listen.socket_addrs(port)? != addrs {
+                                tracing::info!("Addresses changed, rebinding port {}", port);
+                                break;
+                            }
+                        }
+                        Err(_) => watching = false,
+                    }
+                }
+                _ = shutdown.requested(), if !stopping => {}
+                else => return Ok(()),
+            }
+        }
+
+        servers.shutdown().await;
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ListenError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    IpProvderService(#[from] IpProvderServiceError),
+
+    #[error(transparent)]
+    Join(#[from] JoinError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_socket_addrs() -> Result<(), Box<dyn std::error::Error>> {
+        let wildcard = ListenConfig::default();
+        assert_eq!(
+            wildcard.socket_addrs(5353)?,
+            vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 5353)]
+        );
+
+        let loopback = ListenConfig {
+            addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            ..ListenConfig::default()
+        };
+        assert_eq!(
+            loopback.socket_addrs(5353)?,
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5353)]
+        );
+
+        let missing = ListenConfig {
+            interfaces: vec!["not-a-real-interface".to_string()],
+            ..ListenConfig::default()
+        };
+        assert!(missing.socket_addrs(5353)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind() -> Result<(), Box<dyn std::error::Error>> {
+        let loopback = ListenConfig {
+            addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            ..ListenConfig::default()
+        };
+
+        let dns = BoundSockets::bind(&loopback, 0, true)?;
+        assert_eq!(dns.udp.len(), 1);
+        assert_eq!(dns.tcp.len(), 1);
+
+        let http = BoundSockets::bind(&loopback, 0, false)?;
+        assert!(http.udp.is_empty());
+        assert_eq!(http.tcp[0].local_addr()?.ip(), Ipv4Addr::LOCALHOST);
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//Lets hmdl give up root once its privileged ports are bound, everything after that
+//only needs the database.
+
+use crate::dns::ARP_COMMAND;
+use nix::unistd::{self, Group, User};
+use serde::Deserialize;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PrivilegeConfig {
+    /// User to switch to once the sockets are bound, hmdl stays as it was started if not set
+    pub user: Option<String>,
+
+    /// Defaults to the user's primary group
+    pub group: Option<String>,
+
+    /// Limit filesystem access to the database's directory, read only system
+    /// configuration and the arp lookup using Landlock (Linux only)
+    pub sandbox: bool,
+}
+
+/// Must run before the tokio runtime starts, the sandbox only covers threads created
+/// after it is applied. The directories holding `writable_files` stay writable.
+pub fn drop_privileges(
+    config: &PrivilegeConfig,
+    writable_files: &[&Path],
+) -> Result<(), PrivilegeError> {
+    if let Some(name) = &config.user {
+        let user =
+            User::from_name(name)?.ok_or_else(|| PrivilegeError::UnknownUser(name.clone()))?;
+        let gid = match &config.group {
+            Some(name) => {
+                Group::from_name(name)?
+                    .ok_or_else(|| PrivilegeError::UnknownGroup(name.clone()))?
+                    .gid
+            }
+            None => user.gid,
+        };
+
+        //The group has to change first, afterwards there is no permission left to do it
+        set_groups(gid)?;
+        unistd::setgid(gid)?;
+        unistd::setuid(user.uid)?;
+    }
+
+    if config.sandbox {
+        sandbox(writable_files)?;
+    }
+
+    Ok(())
+}
+
+/// Root's supplementary groups (wheel, admin and the like) would otherwise outlive setuid
+#[cfg(not(target_os = "macos"))]
+fn set_groups(gid: unistd::Gid) -> Result<(), PrivilegeError> {
+    unistd::setgroups(&[gid])?;
+    Ok(())
+}
+
+/// nix leaves setgroups out on Apple platforms, where the group count is a c_int
+#[cfg(target_os = "macos")]
+fn set_groups(gid: unistd::Gid) -> Result<(), PrivilegeError> {
+    let gid = gid.as_raw();
+    //Safety: the pointer is to a single gid_t that outlives the call
+    let result = unsafe { nix::libc::setgroups(1, &gid) };
+    nix::errno::Errno::result(result)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sandbox(writable_files: &[&Path]) -> Result<(), PrivilegeError> {
+    use landlock::{
+        Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+
+    let abi = ABI::V1;
+
+    let mut ruleset = Ruleset::new()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?;
+
+    //Sqlite keeps its journal next to the database so the whole directory is needed
+    for file in writable_files {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        ruleset = ruleset.add_rule(PathBeneath::new(PathFd::new(dir)?, AccessFs::from_all(abi)))?;
+    }
+
+    //Resolver configuration, certificates and timezones. Arp needs its shared libraries
+    //and the neighbour table under /proc, which is the caller's own /proc/<pid> so only
+    //a rule on /proc itself covers it.
+    let dirs = [
+        "/etc",
+        "/usr/share/zoneinfo",
+        "/lib",
+        "/lib64",
+        "/usr/lib",
+        "/usr/lib64",
+        "/proc",
+    ];
+    for path in dirs {
+        if Path::new(path).exists() {
+            ruleset = ruleset.add_rule(PathBeneath::new(
+                PathFd::new(path)?,
+                AccessFs::from_read(abi),
+            ))?;
+        }
+    }
+
+    //Directory rights on a single file are rejected, so files get file rights only
+    let files = [
+        (ARP_COMMAND, AccessFs::Execute | AccessFs::ReadFile),
+        ("/dev/urandom", AccessFs::ReadFile.into()),
+        ("/dev/null", AccessFs::ReadFile | AccessFs::WriteFile),
+    ];
+    for (path, access) in files {
+        if Path::new(path).exists() {
+            ruleset = ruleset.add_rule(PathBeneath::new(PathFd::new(path)?, access))?;
+        }
+    }
+
+    let status = ruleset.restrict_self()?;
+    if status.ruleset == RulesetStatus::NotEnforced {
+        return Err(PrivilegeError::SandboxUnsupported);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sandbox(_writable_files: &[&Path]) -> Result<(), PrivilegeError> {
+    Err(PrivilegeError::SandboxUnsupported)
+}
+
+#[derive(Debug, Error)]
+pub enum PrivilegeError {
+    #[cfg(target_os = "linux")]
+    #[error(transparent)]
+    Landlock(#[from] landlock::RulesetError),
+
+    #[cfg(target_os = "linux")]
+    #[error(transparent)]
+    LandlockPath(#[from] landlock::PathFdError),
+
+    #[error(transparent)]
+    Nix(#[from] nix::Error),
+
+    #[error("the filesystem sandbox needs a kernel with Landlock enabled")]
+    SandboxUnsupported,
+
+    #[error("unknown group {0}")]
+    UnknownGroup(String),
+
+    #[error("unknown user {0}")]
+    UnknownUser(String),
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::dns::{should_filter_internal, DbWriter, Decision};
+    use hmdl_db::DatabaseHandle;
+    use std::{fs, str::FromStr, thread};
+    use tokio::{process::Command, runtime::Builder};
+    use trust_dns_server::client::rr::LowerName;
+
+    type TestError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Landlock only restricts the thread applying it and the threads it starts, so the
+    /// whole decision runs on its own thread
+    #[test]
+    fn test_decision_under_sandbox() -> Result<(), TestError> {
+        let dir = std::env::temp_dir().join(format!("hmdl-sandbox-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let database = dir.join("hmdl.db");
+
+        let result = thread::spawn(move || -> Result<(), TestError> {
+            let config = PrivilegeConfig {
+                sandbox: true,
+                ..Default::default()
+            };
+            match drop_privileges(&config, &[database.as_path()]) {
+                Err(PrivilegeError::SandboxUnsupported) => return Ok(()),
+                r => r?,
+            }
+
+            let runtime = Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                //Every client query shells out to arp before it is decided
+                if Path::new(ARP_COMMAND).exists() {
+                    let output = Command::new(ARP_COMMAND).arg("-a").output().await?;
+                    assert!(output.status.success());
+                }
+
+                let pool = DatabaseHandle::create(&database.to_string_lossy()).await?;
+                let writer = DbWriter::create(pool.clone());
+                let domain = LowerName::from_str("sandbox.example.com.")?;
+
+                //New domains are blocked, an allow here means the decision failed open
+                let decision = should_filter_internal(pool.clone(), &writer.queue(), &domain).await;
+                assert_eq!(decision, Decision::Block);
+
+                pool.close().await;
+                Ok(())
+            })
+        })
+        .join()
+        .expect("sandboxed thread panicked");
+
+        fs::remove_dir_all(&dir)?;
+        result
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use sqlx::{query, SqlitePool};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::RetentionConfig;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Prunes collected data once it is past the configured retention
+pub struct RetentionService {
+    pool: SqlitePool,
+    config: RetentionConfig,
+}
+
+impl RetentionService {
+    pub fn create(pool: SqlitePool, config: RetentionConfig) -> Self {
+        Self { pool, config }
+    }
+
+    pub async fn start(&self) -> Result<(), RetentionServiceError> {
+        let mut duration = interval(PRUNE_INTERVAL);
+        duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            duration.tick().await;
+
+            match self.prune().await {
+                Ok(rows) => tracing::debug!("Pruned {} query log entries", rows),
+                Err(e) => tracing::error!("Unable to prune the query log |{}", e),
+            }
+        }
+    }
+
+    async fn prune(&self) -> Result<u64, RetentionServiceError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let cutoff = Utc::now() - ChronoDuration::days(self.config.query_log_days.into());
+
+        let result = query!(
+            r#"
+            DELETE FROM query_log
+            WHERE query_time < ?1
+            "#,
+            cutoff
+        )
+        .execute(&mut conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RetentionServiceError {
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
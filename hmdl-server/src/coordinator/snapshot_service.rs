@@ -0,0 +1,52 @@
+use hmdl_db::dao::snapshots::{self, SnapshotError};
+use sqlx::SqlitePool;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+use crate::config::SnapshotConfig;
+
+/// Takes a policy snapshot on a schedule and prunes the old ones
+pub struct SnapshotService {
+    pool: SqlitePool,
+    config: SnapshotConfig,
+}
+
+impl SnapshotService {
+    pub fn create(pool: SqlitePool, config: SnapshotConfig) -> Self {
+        Self { pool, config }
+    }
+
+    pub async fn start(&self) -> Result<(), SnapshotServiceError> {
+        // Waits a full period first so restarts don't push the real history out
+        let period = Duration::from_secs(u64::from(self.config.interval_hours) * 60 * 60);
+        let mut duration = interval_at(Instant::now() + period, period);
+        duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            duration.tick().await;
+
+            match self.snapshot().await {
+                Ok(id) => tracing::debug!("Took policy snapshot {}", id),
+                Err(e) => tracing::error!("Unable to snapshot the policy |{}", e),
+            }
+        }
+    }
+
+    async fn snapshot(&self) -> Result<i64, SnapshotServiceError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let id = snapshots::take(&mut conn, "scheduled").await?;
+        snapshots::prune(&mut conn, self.config.keep).await?;
+
+        Ok(id)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotServiceError {
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
@@ -0,0 +1,328 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{collections::HashMap, fmt::Display, future::Future, sync::Arc, time::Duration};
+use tokio::{
+    sync::watch::{self, error::RecvError},
+    time::{sleep, timeout, Instant},
+};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// A service that stays up this long has its backoff reset
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// How long a draining service gets to finish in-flight requests
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Describes a service to the supervisor. Services wait for their dependencies to start
+/// and are only stopped once everything depending on them has stopped.
+pub struct Service {
+    pub name: &'static str,
+    pub depends_on: &'static [&'static str],
+
+    /// The service watches its `Shutdown` and finishes its in-flight work, otherwise
+    /// it is simply dropped
+    pub drains: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ServiceState {
+    Waiting,
+    Running,
+    Restarting { last_error: String },
+    Stopping,
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServiceStatus {
+    pub name: &'static str,
+    pub depends_on: &'static [&'static str],
+    pub state: ServiceState,
+    pub restarts: u32,
+    pub since: DateTime<Utc>,
+}
+
+/// The current state of every supervised service
+#[derive(Clone)]
+pub struct ServiceHealth {
+    statuses: Arc<watch::Sender<HashMap<&'static str, ServiceStatus>>>,
+}
+
+impl Default for ServiceHealth {
+    fn default() -> Self {
+        Self {
+            statuses: Arc::new(watch::channel(HashMap::new()).0),
+        }
+    }
+}
+
+impl ServiceHealth {
+    pub fn statuses(&self) -> Vec<ServiceStatus> {
+        let mut statuses: Vec<ServiceStatus> = self.statuses.borrow().values().cloned().collect();
+        statuses.sort_by_key(|s| s.name);
+        statuses
+    }
+
+    fn set(&self, service: &Service, state: ServiceState) {
+        self.statuses.send_modify(|statuses| {
+            let status = statuses.entry(service.name).or_insert(ServiceStatus {
+                name: service.name,
+                depends_on: service.depends_on,
+                state: ServiceState::Waiting,
+                restarts: 0,
+                since: Utc::now(),
+            });
+
+            if matches!(state, ServiceState::Restarting { .. }) {
+                status.restarts += 1;
+            }
+            status.state = state;
+            status.since = Utc::now();
+        });
+    }
+
+    async fn wait_until<F>(&self, done: F)
+    where
+        F: Fn(&HashMap<&'static str, ServiceStatus>) -> bool,
+    {
+        let mut statuses = self.statuses.subscribe();
+        loop {
+            if done(&statuses.borrow_and_update()) {
+                return;
+            }
+            if statuses.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn dependencies_started(&self, service: &Service) {
+        self.wait_until(|statuses| {
+            service.depends_on.iter().all(|dep| {
+                statuses
+                    .get(dep)
+                    .map_or(false, |s| s.state != ServiceState::Waiting)
+            })
+        })
+        .await
+    }
+
+    async fn dependents_stopped(&self, service: &Service) {
+        self.wait_until(|statuses| {
+            statuses
+                .values()
+                .filter(|s| s.depends_on.contains(&service.name))
+                .all(|s| s.state == ServiceState::Stopped)
+        })
+        .await
+    }
+}
+
+/// Resolves once a shutdown has been requested
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn new(receiver: watch::Receiver<bool>) -> Self {
+        Self(receiver)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// A dropped sender counts as a shutdown
+    pub async fn requested(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// A handle that stops an axum server taking connections on shutdown and gives the
+    /// open ones `DRAIN_TIMEOUT` to finish
+    pub fn axum_handle(&self) -> axum_server::Handle {
+        let handle = axum_server::Handle::new();
+
+        let mut shutdown = self.clone();
+        let drain = handle.clone();
+        tokio::spawn(async move {
+            shutdown.requested().await;
+            drain.graceful_shutdown(Some(DRAIN_TIMEOUT));
+        });
+
+        handle
+    }
+}
+
+/// Waits for a watched value to be set. Services read their inputs this way so that
+/// after a restart they pick up whatever was last sent instead of waiting for a change.
+pub async fn latest<T: Clone>(receiver: &mut watch::Receiver<Option<T>>) -> Result<T, RecvError> {
+    loop {
+        if let Some(value) = receiver.borrow_and_update().clone() {
+            return Ok(value);
+        }
+        receiver.changed().await?;
+    }
+}
+
+/// Runs each service, restarting it with backoff when it fails, until shutdown
+#[derive(Clone)]
+pub struct Supervisor {
+    health: ServiceHealth,
+    shutdown: Shutdown,
+}
+
+impl Supervisor {
+    pub fn create(health: ServiceHealth, shutdown: Shutdown) -> Self {
+        Self { health, shutdown }
+    }
+
+    /// `start` is called again for every restart with a `Shutdown` for that run
+    pub async fn run<F, Fut, E>(&self, service: Service, mut start: F)
+    where
+        F: FnMut(Shutdown) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        let mut shutdown = self.shutdown.clone();
+        self.health.set(&service, ServiceState::Waiting);
+
+        tokio::select! {
+            _ = self.health.dependencies_started(&service) => {}
+            _ = shutdown.requested() => {
+                self.health.set(&service, ServiceState::Stopped);
+                return;
+            }
+        }
+
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            self.health.set(&service, ServiceState::Running);
+            let started = Instant::now();
+
+            let (stop_sender, stop) = watch::channel(false);
+            let running = start(Shutdown::new(stop));
+            tokio::pin!(running);
+
+            let result = tokio::select! {
+                r = &mut running => r,
+                _ = self.stop_turn(&service) => {
+                    self.health.set(&service, ServiceState::Stopping);
+                    if service.drains {
+                        stop_sender.send_replace(true);
+                        match timeout(DRAIN_TIMEOUT * 2, &mut running).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => tracing::error!("{} failed while stopping |{}", service.name, e),
+                            Err(_) => tracing::warn!("{} did not stop in time", service.name),
+                        }
+                    }
+                    tracing::debug!("{} stopped.", service.name);
+                    self.health.set(&service, ServiceState::Stopped);
+                    return;
+                }
+            };
+
+            match result {
+                Ok(()) => {
+                    tracing::debug!("{} exited.", service.name);
+                    self.health.set(&service, ServiceState::Stopped);
+                    return;
+                }
+                Err(e) => {
+                    if started.elapsed() >= STABLE_AFTER {
+                        backoff = MIN_BACKOFF;
+                    }
+                    tracing::error!(
+                        "{} had an error, restarting in {:?} |{}",
+                        service.name,
+                        backoff,
+                        e
+                    );
+                    self.health.set(
+                        &service,
+                        ServiceState::Restarting {
+                            last_error: e.to_string(),
+                        },
+                    );
+                }
+            }
+
+            tokio::select! {
+                _ = sleep(backoff) => {}
+                _ = shutdown.requested() => {
+                    self.health.set(&service, ServiceState::Stopped);
+                    return;
+                }
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// A service is stopped once shutdown is requested and everything that needs it is gone
+    async fn stop_turn(&self, service: &Service) {
+        self.shutdown.clone().requested().await;
+        self.health.dependents_stopped(service).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn test_restart_and_shutdown_order() -> Result<(), Box<dyn std::error::Error>> {
+        const PRODUCER: Service = Service {
+            name: "Producer",
+            depends_on: &[],
+            drains: false,
+        };
+        const CONSUMER: Service = Service {
+            name: "Consumer",
+            depends_on: &[PRODUCER.name],
+            drains: true,
+        };
+
+        let health = ServiceHealth::default();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let supervisor = Supervisor::create(health.clone(), Shutdown::new(shutdown));
+
+        let attempts = &AtomicU32::new(0);
+        let producer = supervisor.run(PRODUCER, |_| async move {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err("not yet");
+            }
+            std::future::pending().await
+        });
+        let consumer = supervisor.run(CONSUMER, |mut shutdown| {
+            let health = health.clone();
+            async move {
+                shutdown.requested().await;
+                //The producer has to outlive its consumers
+                let producer = health.statuses().into_iter().find(|s| s.name == "Producer");
+                assert_eq!(producer.map(|s| s.state), Some(ServiceState::Running));
+                Ok::<(), &str>(())
+            }
+        });
+        let stop = async {
+            health
+                .wait_until(|statuses| statuses["Producer"].restarts == 2)
+                .await;
+            shutdown_sender.send_replace(true);
+        };
+
+        tokio::join!(producer, consumer, stop);
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        let statuses = health.statuses();
+        assert!(statuses.iter().all(|s| s.state == ServiceState::Stopped));
+        assert_eq!(statuses[1].name, "Producer");
+        assert_eq!(statuses[1].restarts, 2);
+        Ok(())
+    }
+}
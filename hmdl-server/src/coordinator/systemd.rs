@@ -0,0 +1,81 @@
+//Linux service manager integration, everything here quietly does nothing when hmdl
+//wasn't started by systemd so the launchd setup keeps working.
+
+use sd_notify::NotifyState;
+use socket2::{Socket, Type};
+use std::{collections::HashMap, env, io, os::unix::io::FromRawFd, time::Duration};
+use tokio::time::{interval, MissedTickBehavior};
+
+use super::BoundSockets;
+
+/// Tells systemd the unit is up, used once DNS is answering
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        tracing::warn!("Unable to notify systemd of readiness |{}", e);
+    }
+}
+
+pub fn notify_stopping() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+        tracing::warn!("Unable to notify systemd that we're stopping |{}", e);
+    }
+}
+
+/// Pings the watchdog at half its timeout, the pings come from the coordinator's own
+/// task so a wedged runtime gets the unit restarted.
+pub async fn watchdog() -> Result<(), io::Error> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return std::future::pending().await;
+    }
+
+    let mut duration = interval(Duration::from_micros(usec) / 2);
+    duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        duration.tick().await;
+        sd_notify::notify(false, &[NotifyState::Watchdog])?;
+    }
+}
+
+/// Sockets passed in through socket activation, grouped by their `FileDescriptorName=`
+/// (dns, http or https) so the servers can pick up the ones meant for them.
+#[derive(Default)]
+pub struct ActivatedSockets {
+    sockets: HashMap<String, BoundSockets>,
+}
+
+impl ActivatedSockets {
+    /// Must only be called once, the file descriptors are owned from here on
+    pub fn take() -> Result<Self, io::Error> {
+        let names: Vec<String> = env::var("LISTEN_FDNAMES")
+            .map(|n| n.split(':').map(String::from).collect())
+            .unwrap_or_default();
+
+        let mut activated = Self::default();
+        for (i, fd) in sd_notify::listen_fds()?.enumerate() {
+            let name = names
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+
+            //Safety: systemd hands these over for us alone and they are only wrapped once
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_nonblocking(true)?;
+
+            let entry = activated.sockets.entry(name.clone()).or_default();
+            if socket.r#type()? == Type::DGRAM {
+                entry.udp.push(socket.into());
+            } else {
+                entry.tcp.push(socket.into());
+            }
+            tracing::info!("Using socket activated {} socket", name);
+        }
+
+        Ok(activated)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<BoundSockets> {
+        self.sockets.remove(name)
+    }
+}
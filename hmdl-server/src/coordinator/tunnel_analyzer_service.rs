@@ -0,0 +1,120 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use hmdl_db::dao::domains;
+use sqlx::{query, SqlitePool};
+use std::collections::HashSet;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::dns::tunnel_analyzer::{block_domain, collect_stats, SuspicionStatus};
+use crate::dns::TunnelDetectionConfig;
+
+/// Looks back over the query log on an interval for parent domains whose subdomains
+/// look like data being smuggled out, flagging them for review or blocking them.
+pub struct TunnelAnalyzerService {
+    pool: SqlitePool,
+    config: TunnelDetectionConfig,
+}
+
+impl TunnelAnalyzerService {
+    pub fn create(pool: SqlitePool, config: TunnelDetectionConfig) -> Self {
+        Self { pool, config }
+    }
+
+    pub async fn start(&self) -> Result<(), TunnelAnalyzerServiceError> {
+        if !self.config.enabled {
+            //Park forever so the coordinator doesn't see this as an exit
+            return std::future::pending().await;
+        }
+
+        let mut duration = interval(Duration::from_secs(self.config.interval_secs));
+        duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            duration.tick().await;
+
+            if let Err(e) = self.analyze().await {
+                tracing::error!("Tunnel analysis failed |{}", e);
+            }
+        }
+    }
+
+    async fn analyze(&self) -> Result<(), TunnelAnalyzerServiceError> {
+        let mut conn = self.pool.acquire().await?;
+
+        let window = Duration::from_secs(self.config.window_secs);
+        let since = Utc::now() - ChronoDuration::seconds(self.config.window_secs as i64);
+
+        let queries: Vec<(String, i64)> = query!(
+            r#"
+            SELECT domain_name, response_code
+            FROM query_log
+            WHERE query_time > ?1
+            "#,
+            since
+        )
+        .map(|x| (x.domain_name, x.response_code))
+        .fetch_all(&mut conn)
+        .await?;
+
+        let known: HashSet<String> = domains::find_all_names(&mut conn)
+            .await?
+            .into_iter()
+            .collect();
+
+        let pending = SuspicionStatus::Pending.to_string();
+        let stats = collect_stats(&queries, &known);
+        for (parent, stat) in stats {
+            let suspicion = match stat.score(window, self.config.min_queries) {
+                Some(s) if s.score >= self.config.flag_score => s,
+                _ => continue,
+            };
+
+            //Dismissed domains keep their status, everything else is refreshed
+            let timestamp = Utc::now();
+            let status = query!(
+                r#"
+                INSERT INTO suspicious_domains (name, score, reason, first_flagged, last_flagged, status)
+                VALUES (?1, ?2, ?3, ?4, ?4, ?5)
+                ON CONFLICT(name) DO UPDATE SET
+                    score = ?2,
+                    reason = ?3,
+                    last_flagged = ?4
+                RETURNING status
+                "#,
+                parent,
+                suspicion.score,
+                suspicion.reason,
+                timestamp,
+                pending
+            )
+            .fetch_one(&mut conn)
+            .await?
+            .status;
+
+            if status != pending {
+                continue;
+            }
+
+            tracing::warn!(
+                "{} looks like a DNS tunnel (score {:.2}: {})",
+                parent,
+                suspicion.score,
+                suspicion.reason
+            );
+
+            //Domains an admin categorized by hand are left for review even with auto block on
+            if self.config.auto_block && block_domain(&self.pool, &parent).await? {
+                tracing::warn!("Automatically blocked {}", parent);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TunnelAnalyzerServiceError {
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
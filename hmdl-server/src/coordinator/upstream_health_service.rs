@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+use trust_dns_server::client::rr::{Name, RecordType};
+use trust_dns_server::resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_server::resolver::error::ResolveError;
+use trust_dns_server::resolver::TokioAsyncResolver;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Periodically asks each upstream resolver for the root NS records so the admin can
+/// see which of them are reachable and how quickly they answer.
+pub struct UpstreamHealthService {
+    upstreams: Vec<IpAddr>,
+    health: UpstreamHealth,
+}
+
+#[derive(Clone, Default)]
+pub struct UpstreamHealth {
+    statuses: Arc<Mutex<HashMap<IpAddr, UpstreamStatus>>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UpstreamStatus {
+    pub address: IpAddr,
+    pub reachable: bool,
+    pub latency_ms: Option<u128>,
+    pub last_error: Option<String>,
+    pub last_checked: DateTime<Utc>,
+}
+
+impl UpstreamHealth {
+    pub fn statuses(&self) -> Vec<UpstreamStatus> {
+        let mut statuses: Vec<UpstreamStatus> =
+            self.statuses.lock().unwrap().values().cloned().collect();
+        statuses.sort_by_key(|s| s.address);
+        statuses
+    }
+
+    fn update(&self, status: UpstreamStatus) {
+        self.statuses.lock().unwrap().insert(status.address, status);
+    }
+}
+
+impl UpstreamHealthService {
+    pub fn create(upstreams: Vec<IpAddr>, health: UpstreamHealth) -> Self {
+        Self { upstreams, health }
+    }
+
+    pub async fn start(&self) -> Result<(), UpstreamHealthServiceError> {
+        let mut resolvers = vec![];
+        for upstream in &self.upstreams {
+            resolvers.push((*upstream, Self::single_resolver(upstream)?));
+        }
+
+        let mut duration = interval(CHECK_INTERVAL);
+        duration.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            duration.tick().await;
+
+            for (address, resolver) in &resolvers {
+                let start = Instant::now();
+                let result = resolver.lookup(Name::root(), RecordType::NS).await;
+
+                let status = match result {
+                    Ok(_) => UpstreamStatus {
+                        address: *address,
+                        reachable: true,
+                        latency_ms: Some(start.elapsed().as_millis()),
+                        last_error: None,
+                        last_checked: Utc::now(),
+                    },
+                    Err(e) => {
+                        tracing::warn!(
+                            "Upstream resolver {} failed its health check |{}",
+                            address,
+                            e
+                        );
+                        UpstreamStatus {
+                            address: *address,
+                            reachable: false,
+                            latency_ms: None,
+                            last_error: Some(e.to_string()),
+                            last_checked: Utc::now(),
+                        }
+                    }
+                };
+
+                self.health.update(status);
+            }
+        }
+    }
+
+    /// A resolver pointed at just one upstream with caching off so every check goes out
+    fn single_resolver(upstream: &IpAddr) -> Result<TokioAsyncResolver, ResolveError> {
+        let config = ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&[*upstream], 53, true),
+        );
+        let options = ResolverOpts {
+            attempts: 1,
+            cache_size: 0,
+            timeout: CHECK_TIMEOUT,
+            use_hosts_file: false,
+            ..ResolverOpts::default()
+        };
+
+        TokioAsyncResolver::tokio(config, options)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UpstreamHealthServiceError {
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+}
@@ -1,12 +1,62 @@
+mod answer_cache;
+pub use answer_cache::AnswerCache;
+
 mod arp_lookup;
 pub use arp_lookup::lookup_mac;
+pub use arp_lookup::ARP_COMMAND;
+
+mod db_writer;
+pub use db_writer::DbWriter;
+pub use db_writer::Write;
+pub use db_writer::WriteQueue;
 
 mod decider;
 pub use decider::should_filter;
+pub use decider::should_filter_cname;
+pub use decider::should_filter_internal;
 pub use decider::Decision;
 
+mod dnssec_validator;
+pub use dnssec_validator::DnssecValidator;
+
+mod dnstap;
+pub use dnstap::Dnstap;
+pub use dnstap::DnstapConfig;
+pub use dnstap::DnstapError;
+pub use dnstap::DnstapWriter;
+
+mod dnstap_handler;
+pub use dnstap_handler::DnstapHandler;
+
+mod dns_config;
+pub use dns_config::DnsConfig;
+pub use dns_config::TunnelDetectionConfig;
+
 mod dns_server;
 pub use dns_server::DnsServer;
 
+mod draining_handler;
+pub use draining_handler::DrainingHandler;
+
 mod filtering_fowarder;
 pub use filtering_fowarder::FilteringForwarder;
+
+pub mod query_log;
+
+mod rate_limited_handler;
+pub use rate_limited_handler::RateLimitedHandler;
+
+mod rate_limiter;
+pub use rate_limiter::RateLimiter;
+pub use rate_limiter::ThrottledClient;
+
+mod record_type_policy;
+pub use record_type_policy::RecordTypeAction;
+
+mod rebinding_guard;
+pub use rebinding_guard::RebindingGuard;
+
+pub mod tunnel_analyzer;
+
+mod validating_handler;
+pub use validating_handler::ValidatingHandler;
@@ -0,0 +1,122 @@
+//Our own copy of recent upstream answers so the house keeps working when the upstream
+//resolvers don't, this is the serve-stale behavior from RFC 8767.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use trust_dns_server::client::rr::{LowerName, Record, RecordType};
+use trust_dns_server::resolver::error::ResolveErrorKind;
+use trust_dns_server::resolver::lookup::Lookup;
+use trust_dns_server::store::forwarder::ForwardLookup;
+
+/// RFC 8767 section 4 recommends 30 seconds for stale answers
+const STALE_TTL: u32 = 30;
+
+pub struct AnswerCache {
+    max_entries: usize,
+    max_stale: Duration,
+    entries: Mutex<HashMap<(LowerName, RecordType), Lookup>>,
+}
+
+impl AnswerCache {
+    pub fn create(max_entries: usize, max_stale: Duration) -> Self {
+        Self {
+            max_entries,
+            max_stale,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn store(&self, name: &LowerName, rtype: RecordType, lookup: &ForwardLookup) {
+        if lookup.0.is_empty() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.max_entries {
+            let now = Instant::now();
+            let max_stale = self.max_stale;
+            entries.retain(|_, l| now.saturating_duration_since(l.valid_until()) < max_stale);
+
+            if entries.len() >= self.max_entries {
+                tracing::debug!("Answer cache is full, not caching {} {}", name, rtype);
+                return;
+            }
+        }
+
+        entries.insert((name.clone(), rtype), lookup.0.clone());
+    }
+
+    /// Finds an expired answer that is still within the stale window, the TTLs are
+    /// rewritten so clients come back soon to see if upstream has recovered.
+    pub fn stale(&self, name: &LowerName, rtype: RecordType) -> Option<ForwardLookup> {
+        let entries = self.entries.lock().unwrap();
+        let lookup = entries.get(&(name.clone(), rtype))?;
+
+        let now = Instant::now();
+        if now.saturating_duration_since(lookup.valid_until()) > self.max_stale {
+            return None;
+        }
+
+        let records: Vec<Record> = lookup
+            .record_iter()
+            .cloned()
+            .map(|mut r| {
+                r.set_ttl(STALE_TTL);
+                r
+            })
+            .collect();
+
+        Some(ForwardLookup(Lookup::new_with_deadline(
+            lookup.query().clone(),
+            Arc::from(records),
+            now + Duration::from_secs(STALE_TTL.into()),
+        )))
+    }
+}
+
+/// Only failures to reach upstream should fall back to stale data, a real NXDOMAIN
+/// or a DNSSEC failure is an answer.
+pub fn is_upstream_failure(kind: &ResolveErrorKind) -> bool {
+    matches!(
+        kind,
+        ResolveErrorKind::Timeout | ResolveErrorKind::NoConnections | ResolveErrorKind::Io(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use trust_dns_server::client::op::Query;
+    use trust_dns_server::client::rr::{Name, RData};
+
+    #[test]
+    fn test_serve_stale() -> Result<(), Box<dyn std::error::Error>> {
+        let name = Name::from_str("example.com.")?;
+        let record = Record::from_rdata(name.clone(), 300, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        let expired = ForwardLookup(Lookup::new_with_deadline(
+            Query::query(name.clone(), RecordType::A),
+            Arc::from(vec![record]),
+            Instant::now(),
+        ));
+
+        let cache = AnswerCache::create(10, Duration::from_secs(60));
+        let lower = LowerName::from(&name);
+        cache.store(&lower, RecordType::A, &expired);
+
+        let stale = cache.stale(&lower, RecordType::A).unwrap();
+        assert_eq!(stale.0.records()[0].ttl(), STALE_TTL);
+        assert!(cache.stale(&lower, RecordType::AAAA).is_none());
+
+        let no_window = AnswerCache::create(10, Duration::from_secs(0));
+        no_window.store(&lower, RecordType::A, &expired);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(no_window.stale(&lower, RecordType::A).is_none());
+        Ok(())
+    }
+}
@@ -3,6 +3,9 @@ use std::{io, net::IpAddr, string::FromUtf8Error};
 use thiserror::Error;
 use tokio::process::Command;
 
+/// Run for every client query, the sandbox has to leave it executable
+pub const ARP_COMMAND: &str = "/usr/sbin/arp";
+
 pub async fn lookup_mac(ip_addr: &IpAddr) -> Result<(String, String), ArpError> {
     if ip_addr.is_loopback() {
         return Ok(("localhost".to_string(), "00:00:00:00:00:00".to_string()));
@@ -10,7 +13,7 @@ pub async fn lookup_mac(ip_addr: &IpAddr) -> Result<(String, String), ArpError>
 
     let ip_str = format!("({})", ip_addr);
 
-    let output = Command::new("/usr/sbin/arp").arg("-a").output().await?;
+    let output = Command::new(ARP_COMMAND).arg("-a").output().await?;
     let output_str = String::from_utf8(output.stdout)?;
 
     //Arp has super easy to parse output, let's just do it the easy way
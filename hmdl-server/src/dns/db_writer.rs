@@ -0,0 +1,181 @@
+//DNS answers never wait on a write. Everything the DNS server records goes through a
+//queue and is written here in batches, decisions only ever read.
+
+use chrono::{DateTime, Utc};
+use hmdl_db::dao::{
+    clients::{self, Client},
+    domains,
+};
+use sqlx::{query, Sqlite, SqlitePool, Transaction};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::coordinator::Shutdown;
+
+/// Writes waiting for the writer, anything past this is dropped rather than slowing DNS
+const QUEUE_SIZE: usize = 10_000;
+/// Most writes committed in one transaction, keeps the write lock from being held for long
+const BATCH_SIZE: usize = 500;
+
+/// Something the DNS server saw that should end up in the database
+#[derive(Clone, Debug)]
+pub enum Write {
+    Client {
+        name: String,
+        ip: String,
+        mac: String,
+    },
+    Domain {
+        name: String,
+        last_seen: DateTime<Utc>,
+        last_client: String,
+    },
+    Query {
+        query_time: DateTime<Utc>,
+        client_ip: String,
+        domain_name: String,
+        record_type: String,
+        decision: String,
+        dnssec_status: String,
+        response_code: u16,
+    },
+}
+
+impl Write {
+    async fn apply(self, tx: &mut Transaction<'_, Sqlite>) -> Result<(), sqlx::Error> {
+        match self {
+            Self::Client { name, ip, mac } => {
+                clients::upsert(&mut *tx, &Client { name, ip, mac }).await?;
+            }
+            Self::Domain {
+                name,
+                last_seen,
+                last_client,
+            } => {
+                domains::touch(&mut *tx, &name, last_seen, &last_client).await?;
+            }
+            Self::Query {
+                query_time,
+                client_ip,
+                domain_name,
+                record_type,
+                decision,
+                dnssec_status,
+                response_code,
+            } => {
+                query!(
+                    r#"
+                    INSERT INTO query_log (
+                        query_time, client_ip, domain_name, record_type, decision, dnssec_status, response_code
+                    ) VALUES (
+                        ?1, ?2, ?3, ?4, ?5, ?6, ?7
+                    )
+                    "#,
+                    query_time,
+                    client_ip,
+                    domain_name,
+                    record_type,
+                    decision,
+                    dnssec_status,
+                    response_code
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Cheap to clone handle the DNS server queues its writes on
+#[derive(Clone)]
+pub struct WriteQueue {
+    sender: mpsc::Sender<Write>,
+}
+
+impl WriteQueue {
+    /// Never waits, a backed up database costs log rows instead of query latency
+    pub fn push(&self, write: Write) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(write) {
+            tracing::debug!("Database write queue is full, dropping a write");
+        }
+    }
+}
+
+/// The only writer for what the DNS server records, run under the supervisor so a
+/// failing batch is retried with backoff
+pub struct DbWriter {
+    pool: SqlitePool,
+    queue: WriteQueue,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<Write>>,
+}
+
+impl DbWriter {
+    pub fn create(pool: SqlitePool) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+
+        Self {
+            pool,
+            queue: WriteQueue { sender },
+            receiver: tokio::sync::Mutex::new(receiver),
+        }
+    }
+
+    pub fn queue(&self) -> WriteQueue {
+        self.queue.clone()
+    }
+
+    /// Commits whatever has queued up since the last batch, so a busy network gets
+    /// fewer and larger transactions
+    pub async fn start(&self, mut shutdown: Shutdown) -> Result<(), sqlx::Error> {
+        let mut receiver = self.receiver.lock().await;
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+        loop {
+            let write = tokio::select! {
+                write = receiver.recv() => write,
+                _ = shutdown.requested() => None,
+            };
+            match write {
+                Some(w) => batch.push(w),
+                None => break,
+            }
+
+            fill(&mut receiver, &mut batch);
+            self.commit(&mut batch).await?;
+        }
+
+        //Whatever was queued before the DNS server stopped still gets written
+        loop {
+            fill(&mut receiver, &mut batch);
+            if batch.is_empty() {
+                return Ok(());
+            }
+            self.commit(&mut batch).await?;
+        }
+    }
+
+    /// A write SQLite rejects, like a client taking over another's IP, only loses that
+    /// write. SQLite rolls back just the failed statement so the rest still commit.
+    async fn commit(&self, batch: &mut Vec<Write>) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        for write in batch.drain(..) {
+            match write.apply(&mut tx).await {
+                Err(sqlx::Error::Database(e)) => {
+                    tracing::error!("Failure to write what the DNS server saw {}", e)
+                }
+                r => r?,
+            }
+        }
+        tx.commit().await
+    }
+}
+
+fn fill(receiver: &mut mpsc::Receiver<Write>, batch: &mut Vec<Write>) {
+    while batch.len() < BATCH_SIZE {
+        match receiver.try_recv() {
+            Ok(w) => batch.push(w),
+            Err(_) => break,
+        }
+    }
+}
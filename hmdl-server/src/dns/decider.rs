@@ -2,24 +2,49 @@
 
 //Note: Any system failure in here will result in an allow so we don't block access
 
+//Decisions only read, what they learn about clients and domains is queued for the writer
+
 use chrono::Utc;
-use sqlx::{query, query_as, SqlitePool};
+use hmdl_db::dao::{domains, memberships};
+use sqlx::SqlitePool;
 use std::net::IpAddr;
+use strum::Display;
 use thiserror::Error;
-use trust_dns_server::client::rr::LowerName;
-
-use crate::web::endpoints::domains::Domain;
+use trust_dns_server::client::rr::{LowerName, RecordType};
 
 use super::arp_lookup::{self, ArpError};
+use super::db_writer::{Write, WriteQueue};
+use super::record_type_policy::{looks_like_tunnel, record_type_action, RecordTypeAction};
 
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
 pub enum Decision {
     Allow,
     Block,
+    /// Operations we never serve, like zone transfers, dynamic updates and record
+    /// types a client group refuses
+    Refuse,
+    /// Answered empty without asking upstream
+    Strip,
+}
+
+/// Stands in for the client on lookups that don't come from a client request
+const INTERNAL_CLIENT: &str = "internal";
+
+pub fn client_label(client: Option<&IpAddr>) -> String {
+    client
+        .map(|c| c.to_string())
+        .unwrap_or_else(|| INTERNAL_CLIENT.to_string())
 }
 
 //We absorb all errors here since this is the decision point of what to do
-pub async fn should_filter(pool: SqlitePool, client: &IpAddr, domain: &LowerName) -> Decision {
-    match should_filter_int(pool, client, domain).await {
+pub async fn should_filter(
+    pool: SqlitePool,
+    writer: &WriteQueue,
+    client: &IpAddr,
+    domain: &LowerName,
+    record_type: RecordType,
+) -> Decision {
+    match should_filter_int(pool, writer, client, domain, record_type).await {
         Ok(x) => x,
         Err(e) => {
             tracing::error!("Failure of the filtering code {}", e);
@@ -28,142 +53,118 @@ pub async fn should_filter(pool: SqlitePool, client: &IpAddr, domain: &LowerName
     }
 }
 
+/// Checks a name found while following an upstream CNAME chain. The client has
+/// already been logged by the original query so we skip straight to the domain.
+pub async fn should_filter_cname(
+    pool: SqlitePool,
+    writer: &WriteQueue,
+    client: Option<&IpAddr>,
+    target: &LowerName,
+) -> Decision {
+    match decide(&pool, writer, client, target).await {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("Failure of the cname filtering code {}", e);
+            Decision::Allow
+        }
+    }
+}
+
+/// Lookups that don't come from a client (trust-dns internals, nsec lookups) have no
+/// group to check, so only the domain wide rules apply to them.
+pub async fn should_filter_internal(
+    pool: SqlitePool,
+    writer: &WriteQueue,
+    domain: &LowerName,
+) -> Decision {
+    match decide(&pool, writer, None, domain).await {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("Failure of the internal filtering code {}", e);
+            Decision::Allow
+        }
+    }
+}
+
 async fn should_filter_int(
     pool: SqlitePool,
+    writer: &WriteQueue,
     client: &IpAddr,
     domain: &LowerName,
+    record_type: RecordType,
 ) -> Result<Decision, DecisionError> {
-    log_client(&pool, client).await?;
+    log_client(writer, client).await?;
 
-    let client_str = client.to_string();
-    let domain = log_domain(&pool, domain, client).await?;
+    //Record type rules come before the domain rules, they apply no matter the name
+    match record_type_action(&pool, client, record_type).await? {
+        Some(RecordTypeAction::Refuse) => return Ok(Decision::Refuse),
+        Some(RecordTypeAction::Strip) => return Ok(Decision::Strip),
+        Some(RecordTypeAction::LimitTunnels) if looks_like_tunnel(domain) => {
+            tracing::info!(
+                "Blocking {} query for {} from {}, it looks like a tunnel",
+                record_type,
+                domain,
+                client
+            );
+            return Ok(Decision::Block);
+        }
+        _ => {}
+    }
 
-    //Logging complete, let's make decisions
-    let mut conn = pool.acquire().await?;
-    let blocked = query!(
-        r#"
-        SELECT
-            coalesce(
-                (
-                    SELECT 1
-                    FROM (
-                        SELECT name
-                        FROM known_domains
-                        WHERE name = ?1
-                        EXCEPT
-                        SELECT domain_name
-                        FROM domain_group_member
-                        WHERE domain_name = ?1
-                    )
-                ),
-                (
-                    SELECT 1
-                    FROM known_domains
-                    INNER JOIN domain_group_member ON known_domains.name = domain_group_member.domain_name
-                    INNER JOIN groups_applied ON groups_applied.domain_group_name = domain_group_member.group_name
-                    INNER JOIN client_group_member ON groups_applied.client_group_name = client_group_member.group_name
-                    INNER JOIN clients ON clients.name = client_group_member.client_name
-                    WHERE known_domains.name=?1
-                    and clients.ip = ?2
-                )
-            ) as block
-        "#,
-        domain.name,
-        client_str
-    ).fetch_one(&mut conn).await?;
+    decide(&pool, writer, Some(client), domain).await
+}
 
-    if blocked.block == Some(1) {
+async fn decide(
+    pool: &SqlitePool,
+    writer: &WriteQueue,
+    client: Option<&IpAddr>,
+    domain: &LowerName,
+) -> Result<Decision, DecisionError> {
+    let client_str = client_label(client);
+    let domain = log_domain(pool, writer, domain, &client_str).await?;
+
+    //Logging complete, let's make decisions. A new domain may still be waiting in the write
+    //queue, so any domain outside every group is blocked whether or not it is stored yet.
+    if memberships::blocks(pool, &domain, &client_str).await? {
         Ok(Decision::Block)
     } else {
         Ok(Decision::Allow)
     }
 }
 
-async fn log_client(pool: &SqlitePool, client: &IpAddr) -> Result<(), DecisionError> {
-    let mut conn = pool.acquire().await?;
-
+async fn log_client(writer: &WriteQueue, client: &IpAddr) -> Result<(), DecisionError> {
     let (mut hostname, mac) = arp_lookup::lookup_mac(client).await?;
 
     if hostname == "?" {
         hostname = mac.clone();
     }
 
-    let client_str = client.to_string();
-
-    query!(
-        r#"
-        INSERT INTO clients (
-            name, ip, mac
-        ) VALUES (
-            ?1, ?2, ?3
-        ) ON CONFLICT(name) DO UPDATE SET
-            ip=?2,
-            mac=?3
-        "#,
-        hostname,
-        client_str,
-        mac
-    )
-    .execute(&mut conn)
-    .await?;
+    writer.push(Write::Client {
+        name: hostname,
+        ip: client.to_string(),
+        mac,
+    });
 
     Ok(())
 }
 
+/// Subdomains are tracked under the shortest suffix we already know, a name with no known
+/// suffix is tracked as itself. Returns the name the rules are looked up by.
 async fn log_domain(
     pool: &SqlitePool,
+    writer: &WriteQueue,
     domain: &LowerName,
-    last_client: &IpAddr,
-) -> Result<Domain, DecisionError> {
-    let mut conn = pool.acquire().await?;
-
-    let domain_str = domain.to_string();
-
-    let timestamp = Utc::now();
-    let client_str = last_client.to_string();
-
-    let domain = query_as!(
-        Domain,
-        r#"
-        WITH RECURSIVE
-            known(depth, domain_exists, domain_nm) AS (
-                VALUES (0, 1, ?1)
-                UNION ALL
-                SELECT
-                    k.depth+1,
-                    EXISTS(
-                        SELECT 1
-                        FROM known_domains kn
-                        WHERE kn.name=substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1)
-                    ),
-                    substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1)
-                FROM
-                known k
-                WHERE length(substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1)) > 1
-                and substring(k.domain_nm, (length(k.domain_nm) - instr(k.domain_nm,'.')) * -1) != k.domain_nm
-        )
-        INSERT OR REPLACE INTO known_domains (name, last_seen, last_client)
-        SELECT 
-            domain_nm, ?2, ?3
-        FROM 
-            known
-        WHERE 
-            domain_exists = 1
-        ORDER BY 
-            depth desc
-        LIMIT 1
-        ON CONFLICT(name) 
-        DO UPDATE SET
-            last_seen=?2,
-            last_client=?3
-        RETURNING name, last_seen, last_client
-        "#,
-        domain_str,
-        timestamp,
-        client_str
-    ).fetch_one(&mut conn).await?;
-
-    Ok(domain)
+    last_client: &str,
+) -> Result<String, DecisionError> {
+    let name = domains::resolve(pool, &domain.to_string()).await?;
+
+    writer.push(Write::Domain {
+        name: name.clone(),
+        last_seen: Utc::now(),
+        last_client: last_client.to_string(),
+    });
+
+    Ok(name)
 }
 
 #[derive(Debug, Error)]
@@ -0,0 +1,150 @@
+use serde::Deserialize;
+use std::net::IpAddr;
+
+use super::DnstapConfig;
+use trust_dns_server::resolver::config::{CLOUDFLARE_IPS, GOOGLE_IPS};
+
+/// Tunables for how the DNS server treats the answers it gets back from upstream
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// Upstream resolvers, the fastest healthy one is preferred
+    pub upstreams: Vec<IpAddr>,
+
+    /// Keep answering from expired cache entries when every upstream is down (RFC 8767)
+    pub serve_stale: bool,
+
+    /// How long past its TTL an answer may still be served stale
+    pub max_stale_secs: u64,
+
+    pub answer_cache_size: usize,
+
+    /// Strip answers that point public names at addresses inside the house
+    pub rebinding_protection: bool,
+
+    /// Domains (and their subdomains) that may legitimately resolve to private addresses,
+    /// the application domain is always added to this list
+    pub rebinding_allowlist: Vec<String>,
+
+    /// Validate upstream answers with DNSSEC, bogus answers become SERVFAIL. Signed zones
+    /// are answered with the validated records, proving a zone unsigned costs extra queries.
+    pub dnssec_validation: bool,
+
+    pub rate_limit: RateLimitConfig,
+
+    /// Record refused zone transfers and dynamic updates at warn level and in the query log
+    pub log_refused_operations: bool,
+
+    /// Floor for the TTLs handed to clients, upstream values are kept if not set
+    pub min_ttl: Option<u32>,
+
+    /// Ceiling for the TTLs handed to clients so policy changes take effect quickly
+    pub max_ttl: Option<u32>,
+
+    /// TTL on the answers given for blocked names
+    pub block_ttl: u32,
+
+    pub tunnel_detection: TunnelDetectionConfig,
+
+    pub dnstap: DnstapConfig,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: GOOGLE_IPS.iter().chain(CLOUDFLARE_IPS).cloned().collect(),
+            serve_stale: true,
+            max_stale_secs: 24 * 60 * 60,
+            answer_cache_size: 10_000,
+            rebinding_protection: false,
+            rebinding_allowlist: vec![
+                "localhost.".to_string(),
+                "local.".to_string(),
+                "lan.".to_string(),
+                "home.arpa.".to_string(),
+                "internal.".to_string(),
+            ],
+            dnssec_validation: false,
+            rate_limit: RateLimitConfig::default(),
+            log_refused_operations: true,
+            min_ttl: None,
+            max_ttl: Some(300),
+            block_ttl: 60,
+            tunnel_detection: TunnelDetectionConfig::default(),
+            dnstap: DnstapConfig::default(),
+        }
+    }
+}
+
+/// Token bucket limits on how fast clients may query
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+
+    /// What happens to a query once a limit is hit
+    pub action: RateLimitAction,
+
+    pub client_queries_per_second: f64,
+    pub client_burst: u32,
+
+    /// Shared budget for all the clients in a group, unlimited if not set
+    pub group_queries_per_second: Option<f64>,
+    pub group_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            action: RateLimitAction::Refuse,
+            client_queries_per_second: 50.0,
+            client_burst: 200,
+            group_queries_per_second: None,
+            group_burst: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    /// Answer with REFUSED
+    Refuse,
+    /// Don't answer at all
+    Drop,
+}
+
+/// Periodic scan of the query log for parent domains that look like tunnels or DGAs
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TunnelDetectionConfig {
+    pub enabled: bool,
+
+    /// Block flagged domains straight away instead of leaving them for review
+    pub auto_block: bool,
+
+    pub interval_secs: u64,
+
+    /// How far back each scan looks
+    pub window_secs: u64,
+
+    /// Parent domains with fewer queries in the window are never scored
+    pub min_queries: usize,
+
+    /// Scores run from 0 to 1, at or above this a domain is flagged
+    pub flag_score: f64,
+}
+
+impl Default for TunnelDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            auto_block: false,
+            interval_secs: 5 * 60,
+            window_secs: 15 * 60,
+            min_queries: 30,
+            flag_score: 0.5,
+        }
+    }
+}
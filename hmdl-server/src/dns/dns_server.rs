@@ -1,16 +1,26 @@
-use super::FilteringForwarder;
-use crate::coordinator::IpProvderServiceError;
+use super::validating_handler::SyntheticAnswers;
+use super::{
+    DnsConfig, Dnstap, DnstapHandler, DrainingHandler, FilteringForwarder, RateLimitedHandler,
+    RateLimiter, ValidatingHandler, WriteQueue,
+};
+use crate::coordinator::{
+    notify_ready, serve_on, BoundSockets, HealthFacts, IpProvderServiceError, ListenConfig,
+    ListenError, Shutdown, DRAIN_TIMEOUT,
+};
+use crate::metrics::Metrics;
 use sqlx::SqlitePool;
 use std::{
+    collections::HashSet,
     io,
-    net::{IpAddr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
 use thiserror::Error;
 use tokio::{
     net::{TcpListener, UdpSocket},
-    sync::broadcast::error::RecvError,
+    sync::{broadcast::error::RecvError, watch},
+    time::timeout,
 };
 use trust_dns_server::{
     authority::{AuthorityObject, Catalog},
@@ -19,41 +29,160 @@ use trust_dns_server::{
     ServerFuture,
 };
 
-const PORT: u16 = 53;
 const TIMEOUT: Duration = Duration::new(30, 0);
 
+type Handler = DrainingHandler<DnstapHandler<RateLimitedHandler<ValidatingHandler>>>;
+
 /// This is an extremely opinionated forwarding DNS server used for agressive filtering
 /// Hint on existing DNS server example: https://github.com/bluejekyll/trust-dns/blob/main/bin/src/named.rs
 pub struct DnsServer {
     filtering_forwarder: Arc<FilteringForwarder>,
+    dnssec_validation: bool,
+    synthetic_answers: SyntheticAnswers,
+    rate_limiter: Arc<RateLimiter>,
+    dnstap: Option<Dnstap>,
+    listen: ListenConfig,
+    bound: Option<BoundSockets>,
+    health_facts: HealthFacts,
 }
 
 impl DnsServer {
-    pub async fn create(pool: SqlitePool) -> Self {
-        let filtering_forwarder = Arc::new(FilteringForwarder::create(pool.clone()).await);
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        pool: SqlitePool,
+        writer: WriteQueue,
+        config: &DnsConfig,
+        listen: ListenConfig,
+        bound: Option<BoundSockets>,
+        rate_limiter: Arc<RateLimiter>,
+        health_facts: HealthFacts,
+        metrics: Arc<Metrics>,
+        dnstap: Option<Dnstap>,
+    ) -> Self {
+        let synthetic_answers = SyntheticAnswers::default();
+        let filtering_forwarder = Arc::new(
+            FilteringForwarder::create(
+                pool,
+                writer,
+                config,
+                synthetic_answers.clone(),
+                metrics,
+                dnstap.clone(),
+            )
+            .await,
+        );
         Self {
             filtering_forwarder,
+            dnssec_validation: config.dnssec_validation,
+            synthetic_answers,
+            rate_limiter,
+            dnstap,
+            listen,
+            bound,
+            health_facts,
+        }
+    }
+
+    pub async fn start(
+        &self,
+        ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+        shutdown: Shutdown,
+    ) -> Result<(), DnsServerError> {
+        let result = self.serve_all(ip_changed, shutdown).await;
+        self.health_facts.set_dns_listening(false);
+        result
+    }
+
+    async fn serve_all(
+        &self,
+        ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+        shutdown: Shutdown,
+    ) -> Result<(), DnsServerError> {
+        //Sockets from systemd or bound before privileges were dropped are never rebound
+        if let Some(bound) = &self.bound {
+            let sockets = bound.try_clone()?;
+            return Self::serve(sockets, self.handler(), self.health_facts.clone(), shutdown).await;
         }
+
+        serve_on(
+            &self.listen,
+            self.listen.dns_port,
+            ip_changed,
+            shutdown.clone(),
+            |addr| {
+                Self::serve_addr(
+                    addr,
+                    self.handler(),
+                    self.health_facts.clone(),
+                    shutdown.clone(),
+                )
+            },
+        )
+        .await
     }
 
-    pub async fn start(&self) -> Result<(), DnsServerError> {
+    fn handler(&self) -> Handler {
         let mut catalog: Catalog = Catalog::new();
 
         catalog.upsert(
             Name::root().into(),
             Box::new(self.filtering_forwarder.clone()) as Box<dyn AuthorityObject>,
         );
-        let mut server = ServerFuture::new(catalog);
 
-        let listen_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), PORT);
+        DrainingHandler::create(DnstapHandler::create(
+            RateLimitedHandler::create(
+                ValidatingHandler::create(
+                    catalog,
+                    self.dnssec_validation,
+                    self.synthetic_answers.clone(),
+                ),
+                self.rate_limiter.clone(),
+            ),
+            self.dnstap.clone(),
+        ))
+    }
+
+    async fn serve_addr(
+        listen_addr: SocketAddr,
+        handler: Handler,
+        health_facts: HealthFacts,
+        shutdown: Shutdown,
+    ) -> Result<(), DnsServerError> {
+        let sockets = BoundSockets::bind_addr(listen_addr, true)?;
+        Self::serve(sockets, handler, health_facts, shutdown).await
+    }
 
-        let udp_socket = UdpSocket::bind(listen_addr).await?;
-        server.register_socket(udp_socket);
+    async fn serve(
+        sockets: BoundSockets,
+        handler: Handler,
+        health_facts: HealthFacts,
+        mut shutdown: Shutdown,
+    ) -> Result<(), DnsServerError> {
+        let in_flight = handler.in_flight();
+        let mut server = ServerFuture::new(handler);
 
-        let tcp_listener = TcpListener::bind(listen_addr).await?;
-        server.register_listener(tcp_listener, TIMEOUT);
+        for udp_socket in sockets.udp {
+            server.register_socket(UdpSocket::from_std(udp_socket)?);
+        }
+        for tcp_listener in sockets.tcp {
+            server.register_listener(TcpListener::from_std(tcp_listener)?, TIMEOUT);
+        }
 
-        server.block_until_done().await?;
+        //Systemd only considers us started once queries can be answered
+        health_facts.set_dns_listening(true);
+        notify_ready();
+
+        tokio::select! {
+            r = server.block_until_done() => r?,
+            _ = shutdown.requested() => {
+                //The sockets keep being served until the server is dropped, so new queries
+                //are refused first or steady load would keep the count from reaching zero
+                in_flight.stop_accepting();
+                if timeout(DRAIN_TIMEOUT, in_flight.drained()).await.is_err() {
+                    tracing::warn!("DNS queries were still being answered at shutdown");
+                }
+            }
+        }
 
         Ok(())
     }
@@ -67,6 +196,9 @@ pub enum DnsServerError {
     #[error(transparent)]
     IpProvderService(#[from] IpProvderServiceError),
 
+    #[error(transparent)]
+    Listen(#[from] ListenError),
+
     #[error(transparent)]
     Proto(#[from] ProtoError),
 
@@ -0,0 +1,550 @@
+//The forwarder's resolver can only say "validated" or "failed", which can't tell a
+//forged answer apart from a zone that simply isn't signed. Queries go to the upstreams
+//through a validating client and only the records it accepted are served as secure.
+//When they don't validate, insecurity is proven the way RFC 4035 5.2 does, by finding
+//the delegation that has no DS record.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+use trust_dns_server::authority::LookupError;
+use trust_dns_server::client::{
+    client::{AsyncClient, AsyncDnssecClient, ClientHandle},
+    op::{Query, ResponseCode},
+    rr::{dnssec::TrustAnchor, DNSClass, LowerName, Name, Record, RecordType},
+    udp::UdpClientStream,
+};
+use trust_dns_server::proto::error::{ProtoError, ProtoErrorKind};
+use trust_dns_server::proto::xfer::DnsResponse;
+use trust_dns_server::resolver::{error::ResolveError, lookup::Lookup};
+use trust_dns_server::store::forwarder::ForwardLookup;
+
+use super::query_log::DnssecStatus;
+
+/// Long enough to spare the upstream a proof per query, short enough to notice a zone being signed
+const STATUS_TTL: Duration = Duration::from_secs(60);
+const MAX_STATUSES: usize = 10_000;
+
+pub struct DnssecValidator {
+    /// One pair per upstream, tried in order until one of them answers
+    upstreams: Vec<(AsyncDnssecClient, AsyncClient)>,
+    statuses: Mutex<HashMap<(LowerName, RecordType), Checked>>,
+}
+
+enum Checked {
+    /// The validated answer itself, reused until its TTL runs out
+    Secure(Lookup),
+    /// Insecure or Bogus, the proof holds for `STATUS_TTL`
+    Proven(DnssecStatus, Instant),
+}
+
+impl Checked {
+    fn is_current(&self, now: Instant) -> bool {
+        match self {
+            Checked::Secure(lookup) => now < lookup.valid_until(),
+            Checked::Proven(_, checked) => now.duration_since(*checked) < STATUS_TTL,
+        }
+    }
+}
+
+impl DnssecValidator {
+    /// Validates against the IANA root keys
+    pub async fn create(upstreams: &[SocketAddr]) -> Result<Self, ProtoError> {
+        Self::with_trust_anchor(upstreams, TrustAnchor::default()).await
+    }
+
+    pub async fn with_trust_anchor(
+        upstreams: &[SocketAddr],
+        trust_anchor: TrustAnchor,
+    ) -> Result<Self, ProtoError> {
+        let mut clients = Vec::with_capacity(upstreams.len());
+        for upstream in upstreams {
+            let (validating, validating_bg) =
+                AsyncDnssecClient::builder(UdpClientStream::<UdpSocket>::new(*upstream))
+                    .trust_anchor(trust_anchor.clone())
+                    .build()
+                    .await?;
+            tokio::spawn(validating_bg);
+
+            let (plain, plain_bg) =
+                AsyncClient::connect(UdpClientStream::<UdpSocket>::new(*upstream)).await?;
+            tokio::spawn(plain_bg);
+
+            clients.push((validating, plain));
+        }
+
+        Ok(Self {
+            upstreams: clients,
+            statuses: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Secure answers are exactly the records that chained to the trust anchor (or their
+    /// NSEC denial), `unvalidated` is never awaited for them. It is what gets served when a
+    /// parent proves the zone Insecure, or when upstream trouble leaves it Unchecked so a
+    /// flaky network never turns into SERVFAIL. Bogus answers are SERVFAIL.
+    pub async fn lookup<F>(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        unvalidated: F,
+    ) -> (Result<ForwardLookup, LookupError>, DnssecStatus)
+    where
+        F: Future<Output = Result<ForwardLookup, LookupError>>,
+    {
+        let key = (name.clone(), rtype);
+        let status = match self.cached(&key) {
+            Some(Checked::Secure(lookup)) => {
+                return (Ok(ForwardLookup(lookup)), DnssecStatus::Secure)
+            }
+            Some(Checked::Proven(status, _)) => status,
+            None => match self.validated(Name::from(name), rtype).await {
+                Ok(response) => {
+                    let lookup = secure_lookup(Query::query(Name::from(name), rtype), response);
+                    if let Ok(ForwardLookup(l)) = &lookup {
+                        self.store(key, Checked::Secure(l.clone()));
+                    }
+                    return (lookup, DnssecStatus::Secure);
+                }
+                Err(e) if is_validation_failure(&e) => {
+                    tracing::debug!("{} {} did not validate |{}", name, rtype, e);
+                    let status = self.prove_insecure(Name::from(name)).await;
+                    if status != DnssecStatus::Unchecked {
+                        self.store(key, Checked::Proven(status, Instant::now()));
+                    }
+                    status
+                }
+                Err(e) => {
+                    tracing::debug!("Could not validate {} {} |{}", name, rtype, e);
+                    DnssecStatus::Unchecked
+                }
+            },
+        };
+
+        match status {
+            DnssecStatus::Bogus => (
+                Err(LookupError::ResponseCode(ResponseCode::ServFail)),
+                status,
+            ),
+            _ => (unvalidated.await, status),
+        }
+    }
+
+    /// Walks toward the root looking for the zone cut whose parent securely denies a DS.
+    /// Reaching a signed DS or the root first means the answer should have validated.
+    async fn prove_insecure(&self, mut name: Name) -> DnssecStatus {
+        while !name.is_root() {
+            match self.validated(name.clone(), RecordType::DS).await {
+                Ok(response) => {
+                    if response
+                        .answers()
+                        .iter()
+                        .any(|r| r.record_type() == RecordType::DS)
+                    {
+                        return DnssecStatus::Bogus;
+                    }
+                    match self.is_zone_cut(&name).await {
+                        Some(true) => return DnssecStatus::Insecure,
+                        Some(false) => {}
+                        None => return DnssecStatus::Unchecked,
+                    }
+                }
+                //Names below the cut have nothing signed to say about their DS
+                Err(e) if is_validation_failure(&e) => {}
+                Err(_) => return DnssecStatus::Unchecked,
+            }
+            name = name.base_name();
+        }
+
+        DnssecStatus::Bogus
+    }
+
+    /// An empty DS answer only proves something at an actual delegation point
+    async fn is_zone_cut(&self, name: &Name) -> Option<bool> {
+        for (_, plain) in &self.upstreams {
+            if let Ok(response) = plain
+                .clone()
+                .query(name.clone(), DNSClass::IN, RecordType::NS)
+                .await
+            {
+                return Some(
+                    response
+                        .answers()
+                        .iter()
+                        .any(|r| r.record_type() == RecordType::NS && r.name() == name),
+                );
+            }
+        }
+
+        None
+    }
+
+    /// A validation failure is the answer, only network trouble moves on to the next upstream
+    async fn validated(&self, name: Name, rtype: RecordType) -> Result<DnsResponse, ProtoError> {
+        let mut last_error = ProtoError::from(io::Error::from(io::ErrorKind::NotConnected));
+        for (validating, _) in &self.upstreams {
+            match validating
+                .clone()
+                .query(name.clone(), DNSClass::IN, rtype)
+                .await
+            {
+                Err(e) if !is_validation_failure(&e) => last_error = e,
+                result => return result,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn cached(&self, key: &(LowerName, RecordType)) -> Option<Checked> {
+        let statuses = self.statuses.lock().unwrap();
+        statuses
+            .get(key)
+            .filter(|checked| checked.is_current(Instant::now()))
+            .map(|checked| match checked {
+                Checked::Secure(lookup) => Checked::Secure(lookup.clone()),
+                Checked::Proven(status, at) => Checked::Proven(*status, *at),
+            })
+    }
+
+    fn store(&self, key: (LowerName, RecordType), checked: Checked) {
+        let mut statuses = self.statuses.lock().unwrap();
+
+        if statuses.len() >= MAX_STATUSES {
+            let now = Instant::now();
+            statuses.retain(|_, c| c.is_current(now));
+
+            if statuses.len() >= MAX_STATUSES {
+                return;
+            }
+        }
+
+        statuses.insert(key, checked);
+    }
+}
+
+/// Serves what the validating client returned, minus the signatures that proved it since
+/// the resolver path never hands those out either. Denials keep the resolver's error shape
+/// so the response code and SOA come out the same.
+fn secure_lookup(query: Query, response: DnsResponse) -> Result<ForwardLookup, LookupError> {
+    let response =
+        ResolveError::from_response(response, false).map_err(LookupError::ResolveError)?;
+    let rtype = query.query_type();
+    let records: Vec<Record> = response
+        .answers()
+        .iter()
+        .filter(|r| r.record_type() == rtype || !r.record_type().is_dnssec())
+        .cloned()
+        .collect();
+    let ttl = records.iter().map(|r| r.ttl()).min().unwrap_or(0);
+
+    Ok(ForwardLookup(Lookup::new_with_deadline(
+        query,
+        Arc::from(records),
+        Instant::now() + Duration::from_secs(ttl.into()),
+    )))
+}
+
+/// The validating client reports missing or broken signatures as these kinds, anything
+/// else is the network or the upstream itself failing.
+fn is_validation_failure(e: &ProtoError) -> bool {
+    matches!(
+        e.kind(),
+        ProtoErrorKind::RrsigsNotPresent { .. }
+            | ProtoErrorKind::Message(_)
+            | ProtoErrorKind::Msg(_)
+            | ProtoErrorKind::Ring(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use std::error::Error;
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use trust_dns_server::authority::{Authority, AuthorityObject, Catalog, ZoneType};
+    use trust_dns_server::client::rr::dnssec::{
+        Algorithm, DigestType, KeyPair, Private, PublicKeyBuf, SigSigner,
+    };
+    use trust_dns_server::client::rr::rdata::{DNSSECRData, SOA};
+    use trust_dns_server::client::rr::RData;
+    use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+    use trust_dns_server::store::in_memory::InMemoryAuthority;
+    use trust_dns_server::ServerFuture;
+
+    const ALGORITHM: Algorithm = Algorithm::ECDSAP256SHA256;
+    const FORGED: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+
+    /// Parent and child zones share one server here, so DS queries have to be answered
+    /// from the parent side of the cut like a real delegation would.
+    struct Delegations {
+        parents: Catalog,
+        zones: Catalog,
+    }
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Delegations {
+        async fn handle_request<R: ResponseHandler>(
+            &self,
+            request: &Request,
+            response_handle: R,
+        ) -> ResponseInfo {
+            match request.query().query_type() {
+                RecordType::DS => self.parents.handle_request(request, response_handle).await,
+                _ => self.zones.handle_request(request, response_handle).await,
+            }
+        }
+    }
+
+    fn generate_key() -> Result<KeyPair<Private>, Box<dyn Error>> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())?;
+        Ok(KeyPair::from_ecdsa(key))
+    }
+
+    fn zone(
+        origin: &str,
+        records: Vec<(&str, RData)>,
+    ) -> Result<InMemoryAuthority, Box<dyn Error>> {
+        let origin = Name::from_str(origin)?;
+        let mut authority = InMemoryAuthority::empty(origin.clone(), ZoneType::Primary, false);
+
+        let ns = origin.prepend_label("ns")?;
+        let soa = SOA::new(
+            ns.clone(),
+            origin.prepend_label("admin")?,
+            1,
+            3600,
+            600,
+            86400,
+            60,
+        );
+        authority.upsert_mut(Record::from_rdata(origin.clone(), 60, RData::SOA(soa)), 0);
+        authority.upsert_mut(
+            Record::from_rdata(origin.clone(), 60, RData::NS(ns.clone())),
+            0,
+        );
+        authority.upsert_mut(Record::from_rdata(ns, 60, RData::A(Ipv4Addr::LOCALHOST)), 0);
+
+        for (name, rdata) in records {
+            authority.upsert_mut(Record::from_rdata(Name::from_str(name)?, 60, rdata), 0);
+        }
+
+        Ok(authority)
+    }
+
+    fn sign(
+        authority: &mut InMemoryAuthority,
+        key: KeyPair<Private>,
+    ) -> Result<(), Box<dyn Error>> {
+        let dnskey = key.to_dnskey(ALGORITHM)?;
+        let origin = Name::from(authority.origin());
+        let signer = SigSigner::dnssec(dnskey, key, origin, Duration::from_secs(86400));
+
+        authority.add_zone_signing_key_mut(signer)?;
+        authority.secure_zone_mut()?;
+        Ok(())
+    }
+
+    /// Serves a signed example. that delegates to an unsigned insecure.example. and to a
+    /// bogus.example. whose signatures don't match the DS its parent publishes.
+    async fn serve_zones() -> Result<(SocketAddr, TrustAnchor), Box<dyn Error>> {
+        let example_key = generate_key()?;
+        let mut trust_anchor = TrustAnchor::new();
+        trust_anchor.insert_trust_anchor(&PublicKeyBuf::new(example_key.to_public_bytes()?));
+
+        let decoy_ds = generate_key()?.to_ds(
+            &Name::from_str("bogus.example.")?,
+            ALGORITHM,
+            DigestType::SHA256,
+        )?;
+
+        let mut example = zone(
+            "example.",
+            vec![
+                ("www.example.", RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+                (
+                    "insecure.example.",
+                    RData::NS(Name::from_str("ns.insecure.example.")?),
+                ),
+                (
+                    "bogus.example.",
+                    RData::NS(Name::from_str("ns.bogus.example.")?),
+                ),
+                ("bogus.example.", RData::DNSSEC(DNSSECRData::DS(decoy_ds))),
+            ],
+        )?;
+        sign(&mut example, example_key)?;
+        let example = Arc::new(example);
+
+        let insecure = zone(
+            "insecure.example.",
+            vec![(
+                "www.insecure.example.",
+                RData::A(Ipv4Addr::new(192, 0, 2, 2)),
+            )],
+        )?;
+
+        let mut bogus = zone(
+            "bogus.example.",
+            vec![("www.bogus.example.", RData::A(Ipv4Addr::new(192, 0, 2, 3)))],
+        )?;
+        sign(&mut bogus, generate_key()?)?;
+
+        let mut parents = Catalog::new();
+        parents.upsert(
+            LowerName::from_str("example.")?,
+            Box::new(example.clone()) as Box<dyn AuthorityObject>,
+        );
+
+        let mut zones = Catalog::new();
+        zones.upsert(
+            LowerName::from_str("example.")?,
+            Box::new(example) as Box<dyn AuthorityObject>,
+        );
+        zones.upsert(
+            LowerName::from_str("insecure.example.")?,
+            Box::new(Arc::new(insecure)) as Box<dyn AuthorityObject>,
+        );
+        zones.upsert(
+            LowerName::from_str("bogus.example.")?,
+            Box::new(Arc::new(bogus)) as Box<dyn AuthorityObject>,
+        );
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+        let mut server = ServerFuture::new(Delegations { parents, zones });
+        server.register_socket(socket);
+        tokio::spawn(async move { server.block_until_done().await });
+
+        Ok((addr, trust_anchor))
+    }
+
+    /// An answer nothing checked, standing in for what the resolver got from an upstream
+    fn forged(name: &LowerName, rtype: RecordType) -> Result<ForwardLookup, LookupError> {
+        let name = Name::from(name);
+        let record = Record::from_rdata(name.clone(), 60, RData::A(FORGED));
+        Ok(ForwardLookup(Lookup::new_with_deadline(
+            Query::query(name, rtype),
+            Arc::from(vec![record]),
+            Instant::now() + Duration::from_secs(60),
+        )))
+    }
+
+    async fn lookup(
+        validator: &DnssecValidator,
+        name: &LowerName,
+        rtype: RecordType,
+    ) -> (Result<ForwardLookup, LookupError>, DnssecStatus) {
+        validator
+            .lookup(name, rtype, async { forged(name, rtype) })
+            .await
+    }
+
+    fn addresses(result: &Result<ForwardLookup, LookupError>) -> Vec<RData> {
+        match result {
+            Ok(lookup) => lookup.0.iter().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_zone() -> Result<(), Box<dyn Error>> {
+        let (upstream, trust_anchor) = serve_zones().await?;
+        let validator = DnssecValidator::with_trust_anchor(&[upstream], trust_anchor).await?;
+
+        let secure = LowerName::from_str("www.example.")?;
+        let insecure = LowerName::from_str("www.insecure.example.")?;
+        let bogus = LowerName::from_str("www.bogus.example.")?;
+
+        let (answer, status) = lookup(&validator, &secure, RecordType::A).await;
+        assert_eq!(status, DnssecStatus::Secure);
+        assert_eq!(
+            addresses(&answer),
+            vec![RData::A(Ipv4Addr::new(192, 0, 2, 1))]
+        );
+
+        //Nothing can be checked in an unsigned zone so the resolver's answer goes out as is
+        let (answer, status) = lookup(&validator, &insecure, RecordType::A).await;
+        assert_eq!(status, DnssecStatus::Insecure);
+        assert_eq!(addresses(&answer), vec![RData::A(FORGED)]);
+
+        let (answer, status) = lookup(&validator, &bogus, RecordType::A).await;
+        assert_eq!(status, DnssecStatus::Bogus);
+        assert!(matches!(
+            answer,
+            Err(LookupError::ResponseCode(ResponseCode::ServFail))
+        ));
+
+        //A signed denial of existence is just as secure as an answer
+        let (answer, status) = lookup(&validator, &secure, RecordType::AAAA).await;
+        assert_eq!(status, DnssecStatus::Secure);
+        assert!(matches!(answer, Err(LookupError::ResolveError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forged_answer() -> Result<(), Box<dyn Error>> {
+        let (upstream, trust_anchor) = serve_zones().await?;
+
+        let forger = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let forger_addr = forger.local_addr()?;
+        let mut catalog = Catalog::new();
+        catalog.upsert(
+            LowerName::from_str("example.")?,
+            Box::new(Arc::new(zone(
+                "example.",
+                vec![("www.example.", RData::A(FORGED))],
+            )?)) as Box<dyn AuthorityObject>,
+        );
+        let mut server = ServerFuture::new(catalog);
+        server.register_socket(forger);
+        tokio::spawn(async move { server.block_until_done().await });
+
+        let name = LowerName::from_str("www.example.")?;
+
+        //The resolver was lied to, the secure answer is the one that validated instead
+        let validator =
+            DnssecValidator::with_trust_anchor(&[upstream], trust_anchor.clone()).await?;
+        let (answer, status) = lookup(&validator, &name, RecordType::A).await;
+        assert_eq!(status, DnssecStatus::Secure);
+        assert_eq!(
+            addresses(&answer),
+            vec![RData::A(Ipv4Addr::new(192, 0, 2, 1))]
+        );
+
+        //An upstream that lies is never failed over from, its answer is bogus
+        let validator =
+            DnssecValidator::with_trust_anchor(&[forger_addr, upstream], trust_anchor).await?;
+        let (answer, status) = lookup(&validator, &name, RecordType::A).await;
+        assert_eq!(status, DnssecStatus::Bogus);
+        assert!(addresses(&answer).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_validation_failure() {
+        assert!(is_validation_failure(&ProtoError::from(
+            "rrsig failed to verify"
+        )));
+        assert!(is_validation_failure(&ProtoError::from(
+            ProtoErrorKind::Message("validation failed")
+        )));
+        assert!(!is_validation_failure(&ProtoError::from(
+            ProtoErrorKind::Timeout
+        )));
+        assert!(!is_validation_failure(&ProtoError::from(
+            std::io::Error::from(std::io::ErrorKind::ConnectionRefused)
+        )));
+    }
+}
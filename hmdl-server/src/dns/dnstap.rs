@@ -0,0 +1,440 @@
+use prost::Message;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::UnixStream,
+    sync::mpsc::{self, error::TrySendError},
+};
+use trust_dns_server::server::Protocol;
+
+use super::validating_handler::RequestKey;
+use super::Decision;
+use crate::coordinator::Shutdown;
+use crate::GIT_VERSION;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+//Frame Streams control frames and fields
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+//Values from dnstap.proto
+const DNSTAP_MESSAGE: i32 = 1;
+const CLIENT_QUERY: i32 = 5;
+const CLIENT_RESPONSE: i32 = 6;
+const INET: i32 = 1;
+const INET6: i32 = 2;
+const UDP: i32 = 1;
+const TCP: i32 = 2;
+const DOT: i32 = 3;
+const DOH: i32 = 4;
+
+/// Where dnstap frames go, nothing is captured unless `file` or `socket` is set
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DnstapConfig {
+    /// Frame Streams file, one left over from the last run is moved aside to `<file>.1`
+    pub file: Option<PathBuf>,
+    /// Unix socket of a Frame Streams receiver such as `fstrm_capture` or `dnstap-receiver`
+    pub socket: Option<PathBuf>,
+    /// Sent as the dnstap identity, the hostname is a common choice
+    pub identity: Option<String>,
+    /// Frames waiting to be written, anything past this is dropped rather than slowing DNS
+    pub queue_size: usize,
+}
+
+impl Default for DnstapConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            socket: None,
+            identity: None,
+            queue_size: 10_000,
+        }
+    }
+}
+
+/// Cheap to clone handle the DNS server uses to emit frames
+#[derive(Clone)]
+pub struct Dnstap {
+    sender: mpsc::Sender<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    decisions: Arc<Mutex<HashMap<RequestKey, Decision>>>,
+}
+
+impl Dnstap {
+    /// Remembers what the forwarder decided so the response frame can carry it
+    pub fn note_decision(&self, key: RequestKey, decision: Decision) {
+        if let Ok(mut decisions) = self.decisions.lock() {
+            decisions.insert(key, decision);
+        }
+    }
+
+    pub(super) fn take_decision(&self, key: &RequestKey) -> Option<Decision> {
+        self.decisions.lock().ok()?.remove(key)
+    }
+
+    pub(super) fn client_query(
+        &self,
+        client: SocketAddr,
+        protocol: Protocol,
+        time: SystemTime,
+        query: &[u8],
+    ) {
+        let (sec, nsec) = timestamp(time);
+        self.send(
+            DnstapMessage {
+                query_time_sec: Some(sec),
+                query_time_nsec: Some(nsec),
+                query_message: Some(query.to_vec()),
+                ..DnstapMessage::client(CLIENT_QUERY, client, protocol)
+            },
+            None,
+        );
+    }
+
+    pub(super) fn client_response(
+        &self,
+        client: SocketAddr,
+        protocol: Protocol,
+        query_time: SystemTime,
+        response: &[u8],
+        decision: Option<Decision>,
+    ) {
+        let (query_sec, query_nsec) = timestamp(query_time);
+        let (sec, nsec) = timestamp(SystemTime::now());
+        self.send(
+            DnstapMessage {
+                query_time_sec: Some(query_sec),
+                query_time_nsec: Some(query_nsec),
+                response_time_sec: Some(sec),
+                response_time_nsec: Some(nsec),
+                response_message: Some(response.to_vec()),
+                ..DnstapMessage::client(CLIENT_RESPONSE, client, protocol)
+            },
+            decision,
+        );
+    }
+
+    /// Never waits, a slow or missing receiver costs frames instead of query latency
+    fn send(&self, message: DnstapMessage, decision: Option<Decision>) {
+        let frame = DnstapFrame {
+            identity: self.identity.clone(),
+            version: Some(format!("hmdl {}", GIT_VERSION).into_bytes()),
+            extra: decision.map(|d| format!("decision={}", d.to_string().to_lowercase()).into()),
+            message: Some(message),
+            frame_type: DNSTAP_MESSAGE,
+        };
+
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(frame.encode_to_vec()) {
+            tracing::debug!("dnstap queue is full, dropping a frame");
+        }
+    }
+}
+
+/// Drains queued frames to the configured output, run under the supervisor so a
+/// receiver going away is retried with backoff
+pub struct DnstapWriter {
+    config: DnstapConfig,
+    dnstap: Dnstap,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl DnstapWriter {
+    pub fn create(config: DnstapConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_size);
+        let dnstap = Dnstap {
+            sender,
+            identity: config.identity.clone().map(String::into_bytes),
+            decisions: Arc::default(),
+        };
+
+        Self {
+            config,
+            dnstap,
+            receiver: tokio::sync::Mutex::new(receiver),
+        }
+    }
+
+    /// Only handed out when there is somewhere for the frames to go
+    pub fn dnstap(&self) -> Option<Dnstap> {
+        (self.config.file.is_some() || self.config.socket.is_some()).then(|| self.dnstap.clone())
+    }
+
+    pub async fn start(&self, shutdown: Shutdown) -> Result<(), DnstapError> {
+        let mut receiver = self.receiver.lock().await;
+
+        if let Some(path) = &self.config.file {
+            if fs::metadata(path).await.is_ok() {
+                let mut previous = path.clone().into_os_string();
+                previous.push(".1");
+                fs::rename(path, previous).await?;
+            }
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .await?;
+            tracing::info!("Writing dnstap frames to {:?}", path);
+            let mut writer = BufWriter::new(file);
+            write_control(&mut writer, CONTROL_START, true).await?;
+            return Self::write_frames(&mut writer, &mut receiver, shutdown).await;
+        }
+
+        if let Some(path) = &self.config.socket {
+            let mut stream = UnixStream::connect(path).await?;
+            write_control(&mut stream, CONTROL_READY, true).await?;
+            expect_control(&mut stream, CONTROL_ACCEPT).await?;
+            tracing::info!("Sending dnstap frames to {:?}", path);
+
+            let (mut reader, writer) = stream.split();
+            let mut writer = BufWriter::new(writer);
+            write_control(&mut writer, CONTROL_START, true).await?;
+            Self::write_frames(&mut writer, &mut receiver, shutdown).await?;
+            expect_control(&mut reader, CONTROL_FINISH).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes whenever the queue runs dry so frames aren't held back on a quiet network
+    async fn write_frames<W: AsyncWrite + Unpin>(
+        writer: &mut W,
+        receiver: &mut mpsc::Receiver<Vec<u8>>,
+        mut shutdown: Shutdown,
+    ) -> Result<(), DnstapError> {
+        loop {
+            let frame = tokio::select! {
+                frame = receiver.recv() => frame,
+                _ = shutdown.requested() => None,
+            };
+            let frame = match frame {
+                Some(f) => f,
+                None => break,
+            };
+
+            write_frame(writer, &frame).await?;
+            while let Ok(frame) = receiver.try_recv() {
+                write_frame(writer, &frame).await?;
+            }
+            writer.flush().await?;
+        }
+
+        //Whatever was queued before the DNS server stopped still gets written
+        while let Ok(frame) = receiver.try_recv() {
+            write_frame(writer, &frame).await?;
+        }
+        write_control(writer, CONTROL_STOP, false).await
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await
+}
+
+async fn write_control<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    control: u32,
+    content_type: bool,
+) -> Result<(), DnstapError> {
+    let mut frame = control.to_be_bytes().to_vec();
+    if content_type {
+        frame.extend(FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend(CONTENT_TYPE);
+    }
+
+    //A zero length marks a control frame
+    writer.write_u32(0).await?;
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn expect_control<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    control: u32,
+) -> Result<(), DnstapError> {
+    let escape = reader.read_u32().await?;
+    let length = reader.read_u32().await?;
+    if escape != 0 || !(4..=512).contains(&length) {
+        return Err(DnstapError::Handshake);
+    }
+
+    let mut frame = vec![0; length as usize];
+    reader.read_exact(&mut frame).await?;
+    if frame[..4] != control.to_be_bytes() {
+        return Err(DnstapError::Handshake);
+    }
+    Ok(())
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+/// The `Dnstap` message from dnstap.proto
+#[derive(Clone, PartialEq, Message)]
+struct DnstapFrame {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    identity: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    version: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    extra: Option<Vec<u8>>,
+    #[prost(message, optional, tag = "14")]
+    message: Option<DnstapMessage>,
+    #[prost(int32, required, tag = "15")]
+    frame_type: i32,
+}
+
+/// The `Message` message from dnstap.proto, only what client queries need
+#[derive(Clone, PartialEq, Message)]
+struct DnstapMessage {
+    #[prost(int32, required, tag = "1")]
+    message_type: i32,
+    #[prost(int32, optional, tag = "2")]
+    socket_family: Option<i32>,
+    #[prost(int32, optional, tag = "3")]
+    socket_protocol: Option<i32>,
+    #[prost(bytes = "vec", optional, tag = "4")]
+    query_address: Option<Vec<u8>>,
+    #[prost(uint32, optional, tag = "6")]
+    query_port: Option<u32>,
+    #[prost(uint64, optional, tag = "8")]
+    query_time_sec: Option<u64>,
+    #[prost(fixed32, optional, tag = "9")]
+    query_time_nsec: Option<u32>,
+    #[prost(bytes = "vec", optional, tag = "10")]
+    query_message: Option<Vec<u8>>,
+    #[prost(uint64, optional, tag = "12")]
+    response_time_sec: Option<u64>,
+    #[prost(fixed32, optional, tag = "13")]
+    response_time_nsec: Option<u32>,
+    #[prost(bytes = "vec", optional, tag = "14")]
+    response_message: Option<Vec<u8>>,
+}
+
+impl DnstapMessage {
+    fn client(message_type: i32, client: SocketAddr, protocol: Protocol) -> Self {
+        let (family, address) = match client.ip() {
+            IpAddr::V4(ip) => (INET, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (INET6, ip.octets().to_vec()),
+        };
+        let protocol = match protocol {
+            Protocol::Udp => Some(UDP),
+            Protocol::Tcp => Some(TCP),
+            Protocol::Tls => Some(DOT),
+            Protocol::Https => Some(DOH),
+            _ => None,
+        };
+
+        Self {
+            message_type,
+            socket_family: Some(family),
+            socket_protocol: protocol,
+            query_address: Some(address),
+            query_port: Some(client.port().into()),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DnstapError {
+    #[error("the dnstap receiver did not complete the Frame Streams handshake")]
+    Handshake,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_frames() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("hmdl-dnstap-{}.fstrm", std::process::id()));
+        let writer = DnstapWriter::create(DnstapConfig {
+            file: Some(path.clone()),
+            identity: Some("test".to_string()),
+            ..DnstapConfig::default()
+        });
+        let dnstap = writer.dnstap().ok_or("dnstap should be enabled")?;
+
+        let client: SocketAddr = "192.168.1.10:5300".parse()?;
+        let key = (client, 1);
+        dnstap.note_decision(key, Decision::Block);
+        dnstap.client_query(client, Protocol::Udp, SystemTime::now(), b"query");
+        let decision = dnstap.take_decision(&key);
+        dnstap.client_response(
+            client,
+            Protocol::Udp,
+            SystemTime::now(),
+            b"answer",
+            decision,
+        );
+
+        //Shutting down before starting writes what was queued and stops
+        let (_sender, stop) = tokio::sync::watch::channel(true);
+        writer.start(Shutdown::new(stop)).await?;
+
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+
+        //START control frame, two data frames then STOP
+        let mut frames = vec![];
+        let mut rest = &bytes[..];
+        while rest.len() >= 4 {
+            let length = u32::from_be_bytes(rest[..4].try_into()?) as usize;
+            rest = &rest[4..];
+            if length == 0 {
+                let control_length = u32::from_be_bytes(rest[..4].try_into()?) as usize;
+                let control = u32::from_be_bytes(rest[4..8].try_into()?);
+                frames.push(Err(control));
+                rest = &rest[4 + control_length..];
+            } else {
+                frames.push(Ok(DnstapFrame::decode(&rest[..length])?));
+                rest = &rest[length..];
+            }
+        }
+
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0], Err(CONTROL_START));
+        assert_eq!(frames[3], Err(CONTROL_STOP));
+
+        let query = frames[1].clone().map_err(|_| "expected a data frame")?;
+        let query_message = query.message.ok_or("missing message")?;
+        assert_eq!(query.identity, Some(b"test".to_vec()));
+        assert_eq!(query.extra, None);
+        assert_eq!(query_message.message_type, CLIENT_QUERY);
+        assert_eq!(query_message.query_address, Some(vec![192, 168, 1, 10]));
+        assert_eq!(query_message.query_message, Some(b"query".to_vec()));
+
+        let response = frames[2].clone().map_err(|_| "expected a data frame")?;
+        assert_eq!(response.extra, Some(b"decision=block".to_vec()));
+        assert_eq!(
+            response.message.ok_or("missing message")?.response_message,
+            Some(b"answer".to_vec())
+        );
+        Ok(())
+    }
+}
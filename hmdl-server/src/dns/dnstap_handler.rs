@@ -0,0 +1,116 @@
+use std::{io, iter, sync::Arc, time::SystemTime};
+use trust_dns_server::{
+    authority::{MessageRequest, MessageResponse, MessageResponseBuilder},
+    client::{op::Message, rr::Record},
+    proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder},
+    server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+
+use super::dnstap::Dnstap;
+use super::validating_handler::RequestKey;
+
+/// Sits outside the rate limiter so throttled clients still show up, answers pass
+/// straight through when dnstap is off.
+pub struct DnstapHandler<H: RequestHandler> {
+    inner: H,
+    dnstap: Option<Dnstap>,
+}
+
+impl<H: RequestHandler> DnstapHandler<H> {
+    pub fn create(inner: H, dnstap: Option<Dnstap>) -> Self {
+        Self { inner, dnstap }
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: RequestHandler> RequestHandler for DnstapHandler<H> {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let dnstap = match &self.dnstap {
+            Some(d) => d,
+            None => return self.inner.handle_request(request, response_handle).await,
+        };
+
+        let query = match request.to_bytes() {
+            Ok(q) => q,
+            Err(e) => {
+                tracing::debug!("Unable to encode query for dnstap |{}", e);
+                return self.inner.handle_request(request, response_handle).await;
+            }
+        };
+
+        let query_time = SystemTime::now();
+        dnstap.client_query(request.src(), request.protocol(), query_time, &query);
+
+        let key = (request.src(), request.header().id());
+        let response_handle = DnstapResponseHandler {
+            inner: response_handle,
+            dnstap: dnstap.clone(),
+            key,
+            protocol: request.protocol(),
+            query: Arc::new(query),
+            query_time,
+        };
+
+        let info = self.inner.handle_request(request, response_handle).await;
+        dnstap.take_decision(&key);
+        info
+    }
+}
+
+#[derive(Clone)]
+struct DnstapResponseHandler<R: ResponseHandler> {
+    inner: R,
+    dnstap: Dnstap,
+    key: RequestKey,
+    protocol: Protocol,
+    query: Arc<Vec<u8>>,
+    query_time: SystemTime,
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for DnstapResponseHandler<R> {
+    /// A response can only be encoded once, so it is encoded for dnstap and then rebuilt
+    /// from those bytes for the client
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut bytes = Vec::with_capacity(512);
+        response.destructive_emit(&mut BinEncoder::new(&mut bytes))?;
+        let message = Message::from_vec(&bytes)?;
+
+        self.dnstap.client_response(
+            self.key.0,
+            self.protocol,
+            self.query_time,
+            &bytes,
+            self.dnstap.take_decision(&self.key),
+        );
+
+        let request = MessageRequest::from_bytes(&self.query)?;
+        let mut builder = MessageResponseBuilder::from_message_request(&request);
+        if let Some(edns) = message.extensions() {
+            builder.edns(edns.clone());
+        }
+        let response = builder.build(
+            *message.header(),
+            message.answers(),
+            message.name_servers(),
+            iter::empty(),
+            message.additionals(),
+        );
+
+        self.inner.send_response(response).await
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::watch;
+use trust_dns_server::{
+    authority::MessageResponseBuilder,
+    client::op::{Header, ResponseCode},
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+
+/// Outermost handler, it counts the queries being answered so a shutdown can wait for
+/// them before the sockets are closed. Once draining starts new queries are refused so
+/// clients move on to their next resolver instead of keeping the count above zero.
+pub struct DrainingHandler<H: RequestHandler> {
+    inner: H,
+    in_flight: InFlight,
+}
+
+impl<H: RequestHandler> DrainingHandler<H> {
+    pub fn create(inner: H) -> Self {
+        Self {
+            inner,
+            in_flight: InFlight::default(),
+        }
+    }
+
+    pub fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: RequestHandler> RequestHandler for DrainingHandler<H> {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        //Counted before the check so a query can't slip in after `drained` saw zero
+        let _guard = self.in_flight.start();
+        if !self.in_flight.is_draining() {
+            return self.inner.handle_request(request, response_handle).await;
+        }
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_response_code(ResponseCode::Refused);
+
+        let response = MessageResponseBuilder::from_message_request(request)
+            .error_msg(request.header(), ResponseCode::Refused);
+        match response_handle.send_response(response).await {
+            Ok(info) => info,
+            Err(e) => {
+                tracing::debug!("Unable to refuse a query while draining |{}", e);
+                header.into()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct InFlight {
+    count: Arc<watch::Sender<usize>>,
+    draining: Arc<AtomicBool>,
+}
+
+impl InFlight {
+    fn start(&self) -> InFlightGuard<'_> {
+        self.count.send_modify(|count| *count += 1);
+        InFlightGuard(self)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Every query from here on is refused, the ones already running are left to finish
+    pub fn stop_accepting(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Resolves once nothing is being answered
+    pub async fn drained(&self) {
+        let mut count = self.count.subscribe();
+        while *count.borrow_and_update() > 0 {
+            if count.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Finishing is tracked on drop so cancelled requests are counted too
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.count.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_drained() -> Result<(), Box<dyn std::error::Error>> {
+        let in_flight = InFlight::default();
+        let running = in_flight.start();
+
+        in_flight.stop_accepting();
+        assert!(in_flight.is_draining());
+        assert!(timeout(Duration::from_millis(50), in_flight.drained())
+            .await
+            .is_err());
+
+        drop(running);
+        timeout(Duration::from_secs(1), in_flight.drained()).await?;
+        Ok(())
+    }
+}
@@ -1,41 +1,364 @@
 use sqlx::SqlitePool;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use trust_dns_server::authority::{
     Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType,
 };
-use trust_dns_server::client::op::ResponseCode;
-use trust_dns_server::client::rr::{LowerName, RecordType};
-use trust_dns_server::resolver::config::{NameServerConfigGroup, ResolverOpts};
+use trust_dns_server::client::op::{Query, ResponseCode};
+use trust_dns_server::client::rr::rdata::SOA;
+use trust_dns_server::client::rr::{LowerName, RData, Record, RecordType};
+use trust_dns_server::resolver::config::{
+    NameServerConfigGroup, ResolverOpts, ServerOrderingStrategy,
+};
+use trust_dns_server::resolver::error::ResolveErrorKind;
+use trust_dns_server::resolver::lookup::Lookup;
 use trust_dns_server::resolver::Name;
 use trust_dns_server::server::RequestInfo;
 use trust_dns_server::store::forwarder::{ForwardAuthority, ForwardConfig, ForwardLookup};
 
-use super::{should_filter, Decision};
+use crate::metrics::Metrics;
+
+use super::answer_cache::is_upstream_failure;
+use super::decider::client_label;
+use super::query_log::{log_query, DnssecStatus, QueryLogEntry};
+use super::validating_handler::{RequestKey, SyntheticAnswers};
+use super::{
+    should_filter, should_filter_cname, should_filter_internal, AnswerCache, Decision, DnsConfig,
+    DnssecValidator, Dnstap, RebindingGuard, WriteQueue,
+};
 
+/// Every path into the upstream resolver goes through `enforce` so nothing can skip
+/// the decider or the query log.
 pub struct FilteringForwarder {
     fwd_authority: ForwardAuthority,
     pool: SqlitePool,
+    writer: WriteQueue,
+    rebinding_guard: Option<RebindingGuard>,
+    answer_cache: Option<AnswerCache>,
+    synthetic_answers: SyntheticAnswers,
+    metrics: Arc<Metrics>,
+    dnstap: Option<Dnstap>,
+    dnssec_validator: Option<DnssecValidator>,
+    log_refused_operations: bool,
+    min_ttl: Option<u32>,
+    max_ttl: Option<u32>,
+    block_ttl: u32,
 }
 
 impl FilteringForwarder {
-    pub async fn create(pool: SqlitePool) -> FilteringForwarder {
+    /// `pool` is only read from, everything the forwarder records goes through `writer`
+    pub async fn create(
+        pool: SqlitePool,
+        writer: WriteQueue,
+        config: &DnsConfig,
+        synthetic_answers: SyntheticAnswers,
+        metrics: Arc<Metrics>,
+        dnstap: Option<Dnstap>,
+    ) -> FilteringForwarder {
         let fa_config = ForwardConfig {
-            name_servers: NameServerConfigGroup::google(),
-            options: Some(ResolverOpts::default()),
+            name_servers: NameServerConfigGroup::from_ips_clear(&config.upstreams, 53, true),
+            options: Some(ResolverOpts {
+                //Validation happens in DnssecValidator, which can tell unsigned from forged
+                edns0: config.dnssec_validation,
+                //Latency based, unhealthy upstreams fall to the back of the line
+                server_ordering_strategy: ServerOrderingStrategy::QueryStatistics,
+                positive_max_ttl: config.max_ttl.map(|t| Duration::from_secs(t.into())),
+                ..ResolverOpts::default()
+            }),
         };
         let fwd_authority =
             ForwardAuthority::try_from_config(Name::root(), ZoneType::Forward, &fa_config)
                 .await
                 .unwrap();
 
+        let rebinding_guard = config
+            .rebinding_protection
+            .then(|| RebindingGuard::create(pool.clone(), config));
+        let upstreams: Vec<SocketAddr> = config
+            .upstreams
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, 53))
+            .collect();
+        let dnssec_validator = match config.dnssec_validation && !upstreams.is_empty() {
+            true => Some(DnssecValidator::create(&upstreams).await.unwrap()),
+            false => None,
+        };
+        let answer_cache = config.serve_stale.then(|| {
+            AnswerCache::create(
+                config.answer_cache_size,
+                Duration::from_secs(config.max_stale_secs),
+            )
+        });
+
         FilteringForwarder {
             fwd_authority,
             pool,
+            writer,
+            rebinding_guard,
+            answer_cache,
+            synthetic_answers,
+            metrics,
+            dnstap,
+            dnssec_validator,
+            log_refused_operations: config.log_refused_operations,
+            min_ttl: config.min_ttl,
+            max_ttl: config.max_ttl,
+            block_ttl: config.block_ttl,
         }
     }
 
-    /*  pub async fn log_client(&mut self, src: SocketAddr) -> Result<(), sqlx::Error> {
+    /// The single enforcement point, the upstream future is only awaited if policy allows it
+    async fn enforce<F>(
+        &self,
+        request: Option<RequestKey>,
+        name: &LowerName,
+        rtype: RecordType,
+        upstream: F,
+    ) -> Result<ForwardLookup, LookupError>
+    where
+        F: Future<Output = Result<ForwardLookup, LookupError>> + Send,
+    {
+        let client_ip = request.map(|(src, _)| src.ip());
+        let client = client_ip.as_ref();
+
+        if matches!(rtype, RecordType::AXFR | RecordType::IXFR) {
+            self.log_refused(client, name, rtype, "zone transfer").await;
+            return Err(LookupError::ResponseCode(ResponseCode::Refused));
+        }
+
+        let started = Instant::now();
+        let decision = match client {
+            Some(c) => should_filter(self.pool.clone(), &self.writer, c, name, rtype).await,
+            None => should_filter_internal(self.pool.clone(), &self.writer, name).await,
+        };
+        self.metrics.observe_decider(started.elapsed());
+
+        let (decision, dnssec_status, result) = match decision {
+            Decision::Allow => self.forward(client, name, rtype, upstream).await,
+            Decision::Refuse => (
+                Decision::Refuse,
+                DnssecStatus::Unchecked,
+                Err(LookupError::ResponseCode(ResponseCode::Refused)),
+            ),
+            Decision::Strip => (
+                Decision::Strip,
+                DnssecStatus::Unchecked,
+                Ok(self.synthetic_answer(name, rtype, None)),
+            ),
+            d => (
+                d,
+                DnssecStatus::Unchecked,
+                Ok(self.blocked_answer(name, rtype)),
+            ),
+        };
 
-    }*/
+        //Only proven answers get the AD bit, never our own blocks or unsigned zones
+        if dnssec_status != DnssecStatus::Secure {
+            if let Some(key) = request {
+                match &result {
+                    Ok(lookup) if decision != Decision::Allow && lookup.0.is_empty() => self
+                        .synthetic_answers
+                        .mark_empty(key, negative_soa(name, self.block_ttl)),
+                    _ => self.synthetic_answers.mark(key),
+                }
+            }
+        }
+
+        self.metrics.observe_query(decision, rtype);
+        if let (Some(dnstap), Some(key)) = (&self.dnstap, request) {
+            dnstap.note_decision(key, decision);
+        }
+        log_query(
+            &self.writer,
+            QueryLogEntry {
+                client,
+                name,
+                record_type: rtype,
+                decision,
+                dnssec_status,
+                response_code: response_code(&result),
+            },
+        );
+
+        result
+    }
+
+    async fn forward<F>(
+        &self,
+        client: Option<&IpAddr>,
+        name: &LowerName,
+        rtype: RecordType,
+        upstream: F,
+    ) -> (Decision, DnssecStatus, Result<ForwardLookup, LookupError>)
+    where
+        F: Future<Output = Result<ForwardLookup, LookupError>> + Send,
+    {
+        //With validation on the resolver is only asked when no validated answer exists
+        let unvalidated = self.upstream_or_stale(name, rtype, upstream);
+        let (lookup, mut dnssec_status) = match &self.dnssec_validator {
+            Some(validator) => validator.lookup(name, rtype, unvalidated).await,
+            None => (unvalidated.await, DnssecStatus::Unchecked),
+        };
+
+        if dnssec_status == DnssecStatus::Bogus {
+            tracing::warn!("DNSSEC validation failed for {}", name);
+            return (
+                Decision::Allow,
+                dnssec_status,
+                Err(LookupError::ResponseCode(ResponseCode::ServFail)),
+            );
+        }
+
+        let lookup = match lookup {
+            Ok(l) => l,
+            Err(e) => return (Decision::Allow, dnssec_status, Err(e)),
+        };
+
+        if let Decision::Block = self.check_cname_chain(client, &lookup).await {
+            return (
+                Decision::Block,
+                DnssecStatus::Unchecked,
+                Ok(self.blocked_answer(name, rtype)),
+            );
+        }
+
+        let lookup = match &self.rebinding_guard {
+            Some(guard) => {
+                let validated = lookup.0.record_iter().count();
+                let lookup = guard.strip(client, lookup).await;
+                //Whatever is left is no longer the answer that validated
+                if lookup.0.record_iter().count() != validated {
+                    dnssec_status = DnssecStatus::Unchecked;
+                }
+                lookup
+            }
+            None => lookup,
+        };
+
+        (
+            Decision::Allow,
+            dnssec_status,
+            Ok(clamp_ttls(lookup, self.min_ttl, self.max_ttl)),
+        )
+    }
+
+    async fn upstream_or_stale<F>(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        upstream: F,
+    ) -> Result<ForwardLookup, LookupError>
+    where
+        F: Future<Output = Result<ForwardLookup, LookupError>> + Send,
+    {
+        let started = Instant::now();
+        let result = upstream.await;
+        let failed =
+            matches!(&result, Err(LookupError::ResolveError(e)) if is_upstream_failure(e.kind()));
+        self.metrics.observe_upstream(started.elapsed(), failed);
+        if let Ok(lookup) = &result {
+            self.metrics.observe_resolver_cache(from_resolver_cache(
+                &lookup.0,
+                started,
+                self.max_ttl,
+            ));
+        }
+
+        let cache = match &self.answer_cache {
+            Some(c) => c,
+            None => return result,
+        };
+
+        match result {
+            Ok(lookup) => {
+                cache.store(name, rtype, &lookup);
+                Ok(lookup)
+            }
+            Err(LookupError::ResolveError(e)) if is_upstream_failure(e.kind()) => {
+                let stale = cache.stale(name, rtype);
+                self.metrics.observe_stale(stale.is_some());
+                match stale {
+                    Some(stale) => {
+                        tracing::warn!("Upstream failed, serving stale {} {} |{}", name, rtype, e);
+                        Ok(stale)
+                    }
+                    None => Err(LookupError::ResolveError(e)),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Trackers like to hide behind first party names that CNAME over to them so
+    /// every target in the upstream answer gets the same policy check as the query.
+    async fn check_cname_chain(&self, client: Option<&IpAddr>, lookup: &ForwardLookup) -> Decision {
+        let targets = lookup.0.record_iter().filter_map(|r| match r.data() {
+            Some(RData::CNAME(target)) => Some(LowerName::from(target)),
+            _ => None,
+        });
+
+        for target in targets {
+            let decision =
+                should_filter_cname(self.pool.clone(), &self.writer, client, &target).await;
+            if let Decision::Block = decision {
+                tracing::info!(
+                    "Blocking {} for {} due to its CNAME chain",
+                    target,
+                    client_label(client)
+                );
+                return Decision::Block;
+            }
+        }
+
+        Decision::Allow
+    }
+
+    fn blocked_answer(&self, name: &LowerName, rtype: RecordType) -> ForwardLookup {
+        synthetic_answer(name, rtype, blocked_rdata(rtype), self.block_ttl)
+    }
+
+    fn synthetic_answer(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        rdata: Option<RData>,
+    ) -> ForwardLookup {
+        synthetic_answer(name, rtype, rdata, self.block_ttl)
+    }
+
+    async fn log_refused(
+        &self,
+        client: Option<&IpAddr>,
+        name: &LowerName,
+        rtype: RecordType,
+        operation: &str,
+    ) {
+        if !self.log_refused_operations {
+            tracing::debug!("Refused {} of {}", operation, name);
+            return;
+        }
+
+        tracing::warn!(
+            "Refused {} of {} from {}",
+            operation,
+            name,
+            client_label(client)
+        );
+
+        log_query(
+            &self.writer,
+            QueryLogEntry {
+                client,
+                name,
+                record_type: rtype,
+                decision: Decision::Refuse,
+                dnssec_status: DnssecStatus::Unchecked,
+                response_code: ResponseCode::Refused,
+            },
+        );
+    }
 }
 
 #[async_trait::async_trait]
@@ -47,11 +370,16 @@ impl Authority for FilteringForwarder {
     }
 
     fn is_axfr_allowed(&self) -> bool {
-        self.fwd_authority.is_axfr_allowed()
+        false
     }
 
-    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
-        self.fwd_authority.update(_update).await
+    /// RFC 2136 updates have no business going through a filtering forwarder
+    async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+        let zone = update.query();
+        self.log_refused(None, zone.name(), zone.query_type(), "dynamic update")
+            .await;
+
+        Err(ResponseCode::Refused)
     }
 
     fn origin(&self) -> &LowerName {
@@ -64,12 +392,9 @@ impl Authority for FilteringForwarder {
         rtype: RecordType,
         lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        let result = self
-            .fwd_authority
-            .lookup(name, rtype, lookup_options)
-            .await?;
+        let upstream = self.fwd_authority.lookup(name, rtype, lookup_options);
 
-        Ok(result)
+        self.enforce(None, name, rtype, upstream).await
     }
 
     async fn search(
@@ -77,30 +402,219 @@ impl Authority for FilteringForwarder {
         request_info: RequestInfo<'_>,
         lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        match should_filter(
-            self.pool.clone(),
-            &request_info.src.ip(),
-            request_info.query.name(),
-        )
-        .await
-        {
-            Decision::Block => return Err(LookupError::ResponseCode(ResponseCode::Unknown(3841))),
-            Decision::Allow => {
-                return self
-                    .fwd_authority
-                    .search(request_info, lookup_options)
-                    .await
-            }
-        };
+        let request = (request_info.src, request_info.header.id());
+        let query = request_info.query.clone();
+        let upstream = self.fwd_authority.search(request_info, lookup_options);
+
+        self.enforce(Some(request), query.name(), query.query_type(), upstream)
+            .await
     }
 
     async fn get_nsec_records(
         &self,
-        _name: &LowerName,
-        _lookup_options: LookupOptions,
+        name: &LowerName,
+        lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        self.fwd_authority
-            .get_nsec_records(_name, _lookup_options)
-            .await
+        let upstream = self.fwd_authority.get_nsec_records(name, lookup_options);
+
+        self.enforce(None, name, RecordType::NSEC, upstream).await
+    }
+}
+
+/// Blocked names used to get the made up response code 3841, which doesn't fit the 4 bit
+/// header field and reached clients without EDNS as FORMERR so they went off to retry
+/// other resolvers. A null address answers the question instead, other record types get
+/// an empty answer.
+fn blocked_rdata(rtype: RecordType) -> Option<RData> {
+    match rtype {
+        RecordType::A => Some(RData::A(Ipv4Addr::UNSPECIFIED)),
+        RecordType::AAAA => Some(RData::AAAA(Ipv6Addr::UNSPECIFIED)),
+        _ => None,
+    }
+}
+
+/// Our own answers carry a short TTL so a policy change reaches clients quickly
+fn synthetic_answer(
+    name: &LowerName,
+    rtype: RecordType,
+    rdata: Option<RData>,
+    ttl: u32,
+) -> ForwardLookup {
+    let name = Name::from(name);
+    let records: Vec<Record> = rdata
+        .into_iter()
+        .map(|r| Record::from_rdata(name.clone(), ttl, r))
+        .collect();
+
+    ForwardLookup(Lookup::new_with_deadline(
+        Query::query(name, rtype),
+        Arc::from(records),
+        Instant::now() + Duration::from_secs(ttl.into()),
+    ))
+}
+
+/// Clients cache an empty answer for the lower of the SOA's TTL and minimum (RFC 2308 5),
+/// so both are the block TTL.
+fn negative_soa(name: &LowerName, ttl: u32) -> Record {
+    let name = Name::from(name);
+    let refresh = i32::try_from(ttl).unwrap_or(i32::MAX);
+    let soa = SOA::new(
+        name.clone(),
+        name.clone(),
+        1,
+        refresh,
+        refresh,
+        refresh,
+        ttl,
+    );
+
+    Record::from_rdata(name, ttl, RData::SOA(soa))
+}
+
+/// Keeps upstream TTLs within bounds so clients come back often enough to see policy changes
+fn clamp_ttls(lookup: ForwardLookup, min_ttl: Option<u32>, max_ttl: Option<u32>) -> ForwardLookup {
+    if min_ttl.is_none() && max_ttl.is_none() {
+        return lookup;
+    }
+
+    let records: Vec<Record> = lookup
+        .0
+        .record_iter()
+        .cloned()
+        .map(|mut r| {
+            let mut ttl = r.ttl();
+            if let Some(max) = max_ttl {
+                ttl = ttl.min(max);
+            }
+            if let Some(min) = min_ttl {
+                ttl = ttl.max(min);
+            }
+            r.set_ttl(ttl);
+            r
+        })
+        .collect();
+
+    ForwardLookup(Lookup::new_with_deadline(
+        lookup.0.query().clone(),
+        Arc::from(records),
+        lookup.0.valid_until(),
+    ))
+}
+
+/// The resolver caps cached answers at a day unless `positive_max_ttl` says otherwise
+const RESOLVER_MAX_TTL: u32 = 86400;
+
+/// The resolver's cache hands back the deadline set when an answer was first stored while
+/// a fresh answer expires a full TTL after the query started, so anything expiring sooner
+/// has been sitting in the cache.
+fn from_resolver_cache(lookup: &Lookup, started: Instant, max_ttl: Option<u32>) -> bool {
+    let ttl = lookup
+        .record_iter()
+        .map(|r| r.ttl())
+        .chain([max_ttl.unwrap_or(RESOLVER_MAX_TTL)])
+        .min()
+        .unwrap_or(RESOLVER_MAX_TTL);
+    lookup.valid_until() < started + Duration::from_secs(ttl.into())
+}
+
+fn response_code(result: &Result<ForwardLookup, LookupError>) -> ResponseCode {
+    match result {
+        Ok(_) => ResponseCode::NoError,
+        Err(LookupError::ResponseCode(rc)) => *rc,
+        Err(LookupError::ResolveError(e)) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { response_code, .. } => *response_code,
+            _ => ResponseCode::ServFail,
+        },
+        Err(_) => ResponseCode::ServFail,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_blocked_answer() -> Result<(), Box<dyn std::error::Error>> {
+        let name = LowerName::from_str("blocked.example.com.")?;
+
+        let a = synthetic_answer(&name, RecordType::A, blocked_rdata(RecordType::A), 60);
+        let records: Vec<&Record> = a.0.record_iter().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data(), Some(&RData::A(Ipv4Addr::UNSPECIFIED)));
+        assert_eq!(records[0].ttl(), 60);
+        assert_eq!(response_code(&Ok(a)), ResponseCode::NoError);
+
+        let aaaa = synthetic_answer(&name, RecordType::AAAA, blocked_rdata(RecordType::AAAA), 60);
+        let records: Vec<&Record> = aaaa.0.record_iter().collect();
+        assert_eq!(records[0].data(), Some(&RData::AAAA(Ipv6Addr::UNSPECIFIED)));
+
+        let txt = synthetic_answer(&name, RecordType::TXT, blocked_rdata(RecordType::TXT), 60);
+        assert!(txt.0.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_soa() -> Result<(), Box<dyn std::error::Error>> {
+        let name = LowerName::from_str("blocked.example.com.")?;
+        let soa = negative_soa(&name, 60);
+
+        assert_eq!(soa.name(), &Name::from(&name));
+        assert_eq!(soa.ttl(), 60);
+        match soa.data() {
+            Some(RData::SOA(soa)) => assert_eq!(soa.minimum(), 60),
+            other => panic!("Expected a SOA, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_clamp_ttls() -> Result<(), Box<dyn std::error::Error>> {
+        let name = Name::from_str("example.com.")?;
+        let records: Vec<Record> = [5, 120, 86400]
+            .into_iter()
+            .map(|ttl| Record::from_rdata(name.clone(), ttl, RData::A(Ipv4Addr::new(192, 0, 2, 1))))
+            .collect();
+        let lookup = ForwardLookup(Lookup::new_with_deadline(
+            Query::query(name, RecordType::A),
+            Arc::from(records),
+            Instant::now(),
+        ));
+
+        let clamped = clamp_ttls(lookup, Some(30), Some(300));
+        let ttls: Vec<u32> = clamped.0.record_iter().map(|r| r.ttl()).collect();
+        assert_eq!(ttls, vec![30, 120, 300]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_resolver_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let name = Name::from_str("example.com.")?;
+        let records: Arc<[Record]> = Arc::from(vec![Record::from_rdata(
+            name.clone(),
+            300,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        )]);
+        let query = Query::query(name, RecordType::A);
+        let started = Instant::now();
+
+        let fresh = Lookup::new_with_deadline(
+            query.clone(),
+            records.clone(),
+            started + Duration::from_secs(300),
+        );
+        assert!(!from_resolver_cache(&fresh, started, None));
+
+        let cached = Lookup::new_with_deadline(
+            query.clone(),
+            records.clone(),
+            started + Duration::from_secs(200),
+        );
+        assert!(from_resolver_cache(&cached, started, None));
+
+        //The resolver stores with max_ttl when it is shorter than the records
+        let capped = Lookup::new_with_deadline(query, records, started + Duration::from_secs(60));
+        assert!(!from_resolver_cache(&capped, started, Some(60)));
+        Ok(())
     }
 }
@@ -0,0 +1,46 @@
+//Every answered query gets a row here so the admin can see what the filter actually did
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use strum::{Display, EnumString};
+use trust_dns_server::client::op::ResponseCode;
+use trust_dns_server::client::rr::{LowerName, RecordType};
+
+use super::db_writer::{Write, WriteQueue};
+use super::decider::client_label;
+use super::Decision;
+
+#[derive(Clone, Copy, Debug, Deserialize, Display, EnumString, Eq, PartialEq, Serialize)]
+pub enum DnssecStatus {
+    /// Validation is turned off
+    Unchecked,
+    /// The upstream answer validated
+    Secure,
+    /// A parent zone proved the answer's zone unsigned, it is answered without the AD bit
+    Insecure,
+    /// The upstream answer failed validation and was turned into a SERVFAIL
+    Bogus,
+}
+
+pub struct QueryLogEntry<'a> {
+    pub client: Option<&'a IpAddr>,
+    pub name: &'a LowerName,
+    pub record_type: RecordType,
+    pub decision: Decision,
+    pub dnssec_status: DnssecStatus,
+    pub response_code: ResponseCode,
+}
+
+//Like the decider, logging never holds up an answer. A full write queue drops the row.
+pub fn log_query(writer: &WriteQueue, entry: QueryLogEntry<'_>) {
+    writer.push(Write::Query {
+        query_time: Utc::now(),
+        client_ip: client_label(entry.client),
+        domain_name: entry.name.to_string(),
+        record_type: entry.record_type.to_string(),
+        decision: entry.decision.to_string(),
+        dnssec_status: entry.dnssec_status.to_string(),
+        response_code: u16::from(entry.response_code),
+    });
+}
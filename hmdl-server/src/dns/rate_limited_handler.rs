@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use trust_dns_server::{
+    authority::MessageResponseBuilder,
+    client::op::{Header, ResponseCode},
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+
+use super::{dns_config::RateLimitAction, RateLimiter};
+
+/// Sits in front of everything else so a flooding client is turned away before we
+/// spend an arp lookup or a database write on it.
+pub struct RateLimitedHandler<H: RequestHandler> {
+    inner: H,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl<H: RequestHandler> RateLimitedHandler<H> {
+    pub fn create(inner: H, rate_limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            inner,
+            rate_limiter,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: RequestHandler> RequestHandler for RateLimitedHandler<H> {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        if self.rate_limiter.check(&request.src().ip()).await {
+            return self.inner.handle_request(request, response_handle).await;
+        }
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_response_code(ResponseCode::Refused);
+
+        match self.rate_limiter.config().action {
+            RateLimitAction::Drop => header.into(),
+            RateLimitAction::Refuse => {
+                let response = MessageResponseBuilder::from_message_request(request)
+                    .error_msg(request.header(), ResponseCode::Refused);
+
+                match response_handle.send_response(response).await {
+                    Ok(info) => info,
+                    Err(e) => {
+                        tracing::error!("Unable to send the rate limited response |{}", e);
+                        header.into()
+                    }
+                }
+            }
+        }
+    }
+}
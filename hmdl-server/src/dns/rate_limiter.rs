@@ -0,0 +1,362 @@
+//Every query costs an arp fork and several SQLite writes, so a chatty device can tie
+//up the whole server. Token buckets per client and per client group keep that in check.
+
+use chrono::{DateTime, Utc};
+use hmdl_db::dao::memberships;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::dns_config::RateLimitConfig;
+
+const GROUP_REFRESH: Duration = Duration::from_secs(60);
+const GROUP_RETRY: Duration = Duration::from_secs(1);
+const THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    pool: SqlitePool,
+    state: Mutex<RateLimiterState>,
+}
+
+#[derive(Default)]
+struct RateLimiterState {
+    clients: BoundedMap<TokenBucket>,
+    groups: HashMap<String, TokenBucket>,
+    client_groups: HashMap<IpAddr, Vec<String>>,
+    next_group_refresh: Option<Instant>,
+    group_load_failures: u32,
+    throttled: BoundedMap<ThrottledClient>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ThrottledClient {
+    pub ip: String,
+    pub group: Option<String>,
+    pub first_throttled: DateTime<Utc>,
+    pub last_throttled: DateTime<Utc>,
+    pub queries_limited: u64,
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn create(pool: SqlitePool, config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            pool,
+            state: Mutex::new(RateLimiterState::default()),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Returns true if the client still has budget for another query
+    pub async fn check(&self, client: &IpAddr) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+
+        if self.config.group_queries_per_second.is_some() {
+            self.refresh_client_groups().await;
+        }
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let client_allowed = state
+            .clients
+            .get_or_insert_with(*client, || TokenBucket::new(self.config.client_burst))
+            .try_take(
+                self.config.client_queries_per_second,
+                self.config.client_burst,
+                now,
+            );
+
+        //A client over its own limit must not spend the budget its group shares
+        let mut limited_group = None;
+        if let (true, Some(group_rate)) = (client_allowed, self.config.group_queries_per_second) {
+            let groups = state.client_groups.get(client).cloned().unwrap_or_default();
+            for group in groups {
+                let group_allowed = state
+                    .groups
+                    .entry(group.clone())
+                    .or_insert_with(|| TokenBucket::new(self.config.group_burst))
+                    .try_take(group_rate, self.config.group_burst, now);
+                if !group_allowed {
+                    limited_group = Some(group);
+                    break;
+                }
+            }
+        }
+
+        if client_allowed && limited_group.is_none() {
+            return true;
+        }
+
+        let timestamp = Utc::now();
+        let throttled = state
+            .throttled
+            .get_or_insert_with(*client, || ThrottledClient {
+                ip: client.to_string(),
+                group: None,
+                first_throttled: timestamp,
+                last_throttled: timestamp,
+                queries_limited: 0,
+                last_seen: None,
+            });
+        if throttled.queries_limited == 0 {
+            tracing::warn!("Rate limiting client {}", client);
+        }
+        throttled.group = limited_group;
+        throttled.last_throttled = timestamp;
+        throttled.last_seen = Some(now);
+        throttled.queries_limited += 1;
+
+        false
+    }
+
+    /// Clients that have hit a limit within the last minute
+    pub fn throttled_clients(&self) -> Vec<ThrottledClient> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        state.throttled.retain(|t| match t.last_seen {
+            Some(l) => now.duration_since(l) < THROTTLE_WINDOW,
+            None => false,
+        });
+
+        let mut throttled: Vec<ThrottledClient> =
+            state.throttled.entries.values().cloned().collect();
+        throttled.sort_by(|a, b| a.ip.cmp(&b.ip));
+        throttled
+    }
+
+    async fn refresh_client_groups(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            if matches!(state.next_group_refresh, Some(next) if now < next) {
+                return;
+            }
+            //Claimed up front so queries arriving during the load don't start their own
+            state.next_group_refresh = Some(now + GROUP_REFRESH);
+        }
+
+        let loaded = self.load_client_groups().await;
+        let mut state = self.state.lock().unwrap();
+        match loaded {
+            Ok(client_groups) => {
+                state.client_groups = client_groups;
+                state.group_load_failures = 0;
+            }
+            Err(e) => {
+                //Limiting per client still works without the groups
+                tracing::error!("Unable to load client groups for rate limiting |{}", e);
+                state.group_load_failures += 1;
+                state.next_group_refresh =
+                    Some(Instant::now() + group_retry(state.group_load_failures));
+            }
+        }
+    }
+
+    async fn load_client_groups(&self) -> Result<HashMap<IpAddr, Vec<String>>, sqlx::Error> {
+        let rows = memberships::find_client_groups_by_ip(&self.pool).await?;
+
+        let mut client_groups: HashMap<IpAddr, Vec<String>> = HashMap::new();
+        for row in rows {
+            if let Ok(ip) = row.ip.parse() {
+                client_groups.entry(ip).or_default().push(row.group_name);
+            }
+        }
+
+        Ok(client_groups)
+    }
+}
+
+/// Forgets the longest tracked client once full, so a flood of spoofed sources can't
+/// grow it and making room never means walking every entry.
+struct BoundedMap<V> {
+    entries: HashMap<IpAddr, V>,
+    order: VecDeque<IpAddr>,
+    capacity: usize,
+}
+
+impl<V> Default for BoundedMap<V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: MAX_TRACKED_CLIENTS,
+        }
+    }
+}
+
+impl<V> BoundedMap<V> {
+    fn get_or_insert_with(&mut self, key: IpAddr, value: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            while self.entries.len() >= self.capacity {
+                match self.order.pop_front() {
+                    Some(oldest) => self.entries.remove(&oldest),
+                    None => break,
+                };
+            }
+            self.order.push_back(key);
+        }
+
+        self.entries.entry(key).or_insert_with(value)
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&V) -> bool) {
+        self.entries.retain(|_, v| keep(v));
+        let entries = &self.entries;
+        self.order.retain(|k| entries.contains_key(k));
+    }
+}
+
+/// Doubles with each failure in a row, never waiting longer than a normal refresh
+fn group_retry(failures: u32) -> Duration {
+    GROUP_RETRY
+        .checked_mul(1 << failures.saturating_sub(1).min(16))
+        .map_or(GROUP_REFRESH, |d| d.min(GROUP_REFRESH))
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: u32) -> Self {
+        Self {
+            tokens: f64::from(burst),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self, rate: f64, burst: u32, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(burst));
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_token_bucket() -> Result<(), Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 2.0,
+            last_refill: start,
+        };
+
+        assert!(bucket.try_take(1.0, 2, start));
+        assert!(bucket.try_take(1.0, 2, start));
+        assert!(!bucket.try_take(1.0, 2, start));
+
+        //Half a second only earns half a token
+        assert!(!bucket.try_take(1.0, 2, start + Duration::from_millis(500)));
+        assert!(bucket.try_take(1.0, 2, start + Duration::from_millis(1000)));
+
+        //Refills never go past the burst size
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.try_take(1.0, 2, later));
+        assert!(bucket.try_take(1.0, 2, later));
+        assert!(!bucket.try_take(1.0, 2, later));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_retry() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(group_retry(1), Duration::from_secs(1));
+        assert_eq!(group_retry(2), Duration::from_secs(2));
+        assert_eq!(group_retry(4), Duration::from_secs(8));
+        assert_eq!(group_retry(10), GROUP_REFRESH);
+        assert_eq!(group_retry(u32::MAX), GROUP_REFRESH);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_limit_spares_group() -> Result<(), Box<dyn std::error::Error>> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        let limiter = RateLimiter::create(
+            pool,
+            RateLimitConfig {
+                client_queries_per_second: 0.001,
+                client_burst: 1,
+                group_queries_per_second: Some(0.001),
+                group_burst: 2,
+                ..RateLimitConfig::default()
+            },
+        );
+        let chatty: IpAddr = "192.0.2.1".parse()?;
+        let quiet: IpAddr = "192.0.2.2".parse()?;
+        {
+            let mut state = limiter.state.lock().unwrap();
+            state.next_group_refresh = Some(Instant::now() + GROUP_REFRESH);
+            for ip in [chatty, quiet] {
+                state.client_groups.insert(ip, vec!["kids".to_string()]);
+            }
+        }
+
+        assert!(limiter.check(&chatty).await);
+        assert!(!limiter.check(&chatty).await);
+        assert!(!limiter.check(&chatty).await);
+
+        //Only the one query that got through came out of the group's budget
+        assert!(limiter.check(&quiet).await);
+        let throttled = limiter.throttled_clients();
+        assert_eq!(throttled.len(), 1);
+        assert_eq!(throttled[0].group, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tracking_is_bounded() -> Result<(), Box<dyn std::error::Error>> {
+        let pool = SqlitePool::connect("sqlite::memory:").await?;
+        let limiter = RateLimiter::create(
+            pool,
+            RateLimitConfig {
+                client_burst: 0,
+                ..RateLimitConfig::default()
+            },
+        );
+
+        //Every source is new and every one of them gets throttled
+        for i in 0..MAX_TRACKED_CLIENTS as u32 + 100 {
+            let source = IpAddr::from(Ipv4Addr::from(0x0a00_0000 + i));
+            assert!(!limiter.check(&source).await);
+        }
+
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.clients.entries.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(state.clients.order.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(state.throttled.entries.len(), MAX_TRACKED_CLIENTS);
+        assert!(!state
+            .clients
+            .entries
+            .contains_key(&IpAddr::from(Ipv4Addr::new(10, 0, 0, 0))));
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//Protection against DNS rebinding, a page on a public name gets its record swapped to
+//a private address so the browser will happily talk to devices inside the house.
+
+use hmdl_db::dao::settings;
+use sqlx::SqlitePool;
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+    sync::Arc,
+};
+use thiserror::Error;
+use trust_dns_server::client::rr::{LowerName, RData, Record};
+use trust_dns_server::resolver::lookup::Lookup;
+use trust_dns_server::store::forwarder::ForwardLookup;
+
+use crate::coordinator::IpProvderService;
+
+use super::decider::client_label;
+use super::DnsConfig;
+
+pub struct RebindingGuard {
+    allowlist: Vec<LowerName>,
+    pool: SqlitePool,
+}
+
+impl RebindingGuard {
+    pub fn create(pool: SqlitePool, config: &DnsConfig) -> Self {
+        let allowlist = config
+            .rebinding_allowlist
+            .iter()
+            .filter_map(|x| match LowerName::from_str(x) {
+                Ok(n) => Some(n),
+                Err(e) => {
+                    tracing::error!("Ignoring invalid rebinding allowlist entry {} |{}", x, e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { allowlist, pool }
+    }
+
+    /// Removes any A/AAAA answers that resolve a public name to a private address.
+    /// Like the decider, failures here let the answer through untouched.
+    pub async fn strip(&self, client: Option<&IpAddr>, lookup: ForwardLookup) -> ForwardLookup {
+        if !lookup.0.record_iter().any(is_private_answer) {
+            return lookup;
+        }
+
+        match self.full_allowlist().await {
+            Ok(allowlist) => strip_private(client, lookup, &allowlist),
+            Err(e) => {
+                tracing::error!("Unable to load the rebinding allowlist |{}", e);
+                lookup
+            }
+        }
+    }
+
+    async fn full_allowlist(&self) -> Result<Vec<LowerName>, RebindingGuardError> {
+        let mut allowlist = self.allowlist.clone();
+        if let Some(rec) = settings::find(&self.pool).await? {
+            allowlist.push(LowerName::from_str(&format!(
+                "{}.",
+                rec.application_domain.trim_end_matches('.')
+            ))?);
+        }
+
+        Ok(allowlist)
+    }
+}
+
+/// Only the name the client asked for decides, a public name that CNAMEs into an allowlisted
+/// zone is exactly how a rebinding page would reach a private address.
+fn strip_private(
+    client: Option<&IpAddr>,
+    lookup: ForwardLookup,
+    allowlist: &[LowerName],
+) -> ForwardLookup {
+    let query_name = LowerName::from(lookup.0.query().name());
+    if allowlist.iter().any(|zone| zone.zone_of(&query_name)) {
+        return lookup;
+    }
+
+    let records: Vec<Record> = lookup
+        .0
+        .record_iter()
+        .filter(|r| {
+            if is_private_answer(r) {
+                tracing::warn!(
+                    "Stripped rebinding answer {} -> {:?} for {} from client {}",
+                    r.name(),
+                    r.data(),
+                    query_name,
+                    client_label(client)
+                );
+                false
+            } else {
+                true
+            }
+        })
+        .cloned()
+        .collect();
+
+    ForwardLookup(Lookup::new_with_deadline(
+        lookup.0.query().clone(),
+        Arc::from(records),
+        lookup.0.valid_until(),
+    ))
+}
+
+fn is_private_answer(record: &Record) -> bool {
+    match record.data() {
+        Some(RData::A(ip)) => is_private_address(&IpAddr::V4(*ip)),
+        Some(RData::AAAA(ip)) => is_private_address(&IpAddr::V6(*ip)),
+        _ => false,
+    }
+}
+
+/// RFC1918, loopback, link-local and ULA space, basically anything that shouldn't come
+/// back from the public internet
+pub fn is_private_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified()
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_private_address(&IpAddr::V4(v4));
+            }
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || IpProvderService::has_unicast_link_local_scope(*v6)
+                || is_unique_local(v6)
+        }
+    }
+}
+
+// Another work around for feature "ip" not being stable yet
+const fn is_unique_local(addr: &Ipv6Addr) -> bool {
+    (addr.segments()[0] & 0xfe00) == 0xfc00
+}
+
+#[derive(Debug, Error)]
+pub enum RebindingGuardError {
+    #[error(transparent)]
+    Proto(#[from] trust_dns_server::proto::error::ProtoError),
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+    use trust_dns_server::client::op::Query;
+    use trust_dns_server::client::rr::{Name, RecordType};
+
+    #[test]
+    fn test_private_addresses() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(is_private_address(&IpAddr::from(Ipv4Addr::new(
+            10, 0, 1, 1
+        ))));
+        assert!(is_private_address(&IpAddr::from(Ipv4Addr::new(
+            192, 168, 1, 5
+        ))));
+        assert!(is_private_address(&IpAddr::from(Ipv4Addr::LOCALHOST)));
+        assert!(is_private_address(&IpAddr::from(Ipv4Addr::new(
+            169, 254, 3, 3
+        ))));
+        assert!(is_private_address(&IpAddr::from_str("fd12:3456::1")?));
+        assert!(is_private_address(&IpAddr::from_str("fe80::1")?));
+        assert!(is_private_address(&IpAddr::from_str("::ffff:192.168.0.1")?));
+
+        assert!(!is_private_address(&IpAddr::from(Ipv4Addr::new(
+            8, 8, 8, 8
+        ))));
+        assert!(!is_private_address(&IpAddr::from_str(
+            "2001:4860:4860::8888"
+        )?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cname_into_allowlist() -> Result<(), Box<dyn std::error::Error>> {
+        let public = Name::from_str("rebind.example.com.")?;
+        let router = Name::from_str("router.hmdl.example.net.")?;
+        let private = RData::A(Ipv4Addr::new(192, 168, 1, 1));
+        let records: Vec<Record> = vec![
+            Record::from_rdata(public.clone(), 60, RData::CNAME(router.clone())),
+            Record::from_rdata(router.clone(), 60, private.clone()),
+        ];
+        let allowlist = vec![LowerName::from_str("hmdl.example.net.")?];
+        let lookup = |name: &Name| {
+            ForwardLookup(Lookup::new_with_deadline(
+                Query::query(name.clone(), RecordType::A),
+                Arc::from(records.clone()),
+                Instant::now(),
+            ))
+        };
+
+        let stripped = strip_private(None, lookup(&public), &allowlist);
+        let answers: Vec<&RData> = stripped.0.iter().collect();
+        assert_eq!(answers, vec![&RData::CNAME(router.clone())]);
+
+        let kept = strip_private(None, lookup(&router), &allowlist);
+        assert!(kept.0.iter().any(|r| r == &private));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//Per client group rules on which record types get answered
+
+use hmdl_db::dao::record_type_policies;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use strum::{Display, EnumString};
+use trust_dns_server::client::rr::{LowerName, RecordType};
+
+/// Labels this long are almost never written by a person
+const MAX_LABEL_LENGTH: usize = 40;
+
+/// Shorter labels are only suspicious when they also look random
+const RANDOM_LABEL_LENGTH: usize = 20;
+
+/// Bits per character, long english words sit around 3.7 while base64 payloads sit above
+const RANDOM_LABEL_ENTROPY: f64 = 4.0;
+
+/// Hex and base32 payloads have a low entropy per character but are full of digits
+const RANDOM_LABEL_DIGITS: usize = 3;
+
+/// Ordered from weakest to strongest so a client in several groups gets the strictest rule
+#[derive(
+    Clone, Copy, Debug, Deserialize, Display, EnumString, Eq, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RecordTypeAction {
+    /// Only block queries whose names look like data is being smuggled through them
+    LimitTunnels,
+    /// Answer empty without asking upstream, used for HTTPS/SVCB so ECH can't hide the SNI
+    Strip,
+    /// Answer with REFUSED
+    Refuse,
+}
+
+/// Finds the strictest action the client's groups apply to this record type
+pub async fn record_type_action(
+    pool: &SqlitePool,
+    client: &IpAddr,
+    record_type: RecordType,
+) -> Result<Option<RecordTypeAction>, sqlx::Error> {
+    let actions =
+        record_type_policies::find_actions(pool, &client.to_string(), &record_type.to_string())
+            .await?;
+
+    Ok(actions
+        .iter()
+        .filter_map(|a| RecordTypeAction::from_str(a).ok())
+        .max())
+}
+
+/// Tunnels encode their payload into the labels, so look for labels that are very long
+/// or long and random looking.
+pub fn looks_like_tunnel(name: &LowerName) -> bool {
+    name.to_string().split('.').any(|label| {
+        label.len() > MAX_LABEL_LENGTH
+            || (label.len() >= RANDOM_LABEL_LENGTH && looks_random(label))
+    })
+}
+
+fn looks_random(label: &str) -> bool {
+    let digits = label.chars().filter(|c| c.is_ascii_digit()).count();
+    digits >= RANDOM_LABEL_DIGITS || entropy(label) >= RANDOM_LABEL_ENTROPY
+}
+
+/// Shannon entropy of the label in bits per character
+pub fn entropy(label: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in label.chars() {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+
+    let len = label.chars().count() as f64;
+    counts
+        .values()
+        .map(|&n| {
+            let p = n as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looks_like_tunnel() -> Result<(), Box<dyn std::error::Error>> {
+        let normal = LowerName::from_str("www.example.com.")?;
+        let words = LowerName::from_str("thisisaperfectlyfinename.example.com.")?;
+        let encoded = LowerName::from_str("mzxw6ytboi4tqmrtgq2dmnzy.tunnel.example.com.")?;
+        let long = LowerName::from_str(
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.tunnel.example.com.",
+        )?;
+
+        assert!(!looks_like_tunnel(&normal));
+        assert!(!looks_like_tunnel(&words));
+        assert!(looks_like_tunnel(&encoded));
+        assert!(looks_like_tunnel(&long));
+
+        assert_eq!(
+            RecordTypeAction::from_str("limit_tunnels")?,
+            RecordTypeAction::LimitTunnels
+        );
+        assert!(RecordTypeAction::Refuse > RecordTypeAction::Strip);
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+//Scores parent domains on how much their query stream looks like a DNS tunnel or a
+//domain generation algorithm
+
+use chrono::Utc;
+use hmdl_db::dao::{domains, memberships};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use strum::{Display, EnumString};
+
+use super::record_type_policy::entropy;
+
+/// Recorded as the last client on parent domains the analyzer had to create
+const ANALYZER_CLIENT: &str = "tunnel-analyzer";
+
+/// NXDOMAIN as stored in the query log
+const NXDOMAIN: i64 = 3;
+
+#[derive(Clone, Copy, Debug, Deserialize, Display, EnumString, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+#[strum(serialize_all = "UPPERCASE")]
+pub enum SuspicionStatus {
+    /// Waiting on an admin to look at it
+    Pending,
+    Blocked,
+    /// An admin decided it is fine, it won't be flagged again
+    Dismissed,
+}
+
+/// What a parent domain's subdomains looked like over one analysis window
+#[derive(Default)]
+pub struct DomainStats {
+    queries: usize,
+    nxdomains: usize,
+    subdomains: HashSet<String>,
+    total_entropy: f64,
+    total_length: usize,
+}
+
+#[derive(Debug)]
+pub struct Suspicion {
+    pub score: f64,
+    pub reason: String,
+}
+
+impl DomainStats {
+    pub fn observe(&mut self, subdomain: &str, response_code: i64) {
+        self.queries += 1;
+        if response_code == NXDOMAIN {
+            self.nxdomains += 1;
+        }
+
+        let label: String = subdomain.chars().filter(|c| *c != '.').collect();
+        self.total_entropy += entropy(&label);
+        self.total_length += label.len();
+        self.subdomains.insert(subdomain.to_string());
+    }
+
+    /// Each signal is scaled to 0..1 then weighted, nothing is scored until there is
+    /// enough traffic to say anything.
+    pub fn score(&self, window: Duration, min_queries: usize) -> Option<Suspicion> {
+        if self.queries < min_queries || self.queries == 0 {
+            return None;
+        }
+
+        let queries = self.queries as f64;
+        let minutes = (window.as_secs_f64() / 60.0).max(1.0);
+
+        let signals = [
+            (
+                "unique subdomain rate",
+                0.3,
+                (self.subdomains.len() as f64 / minutes / 10.0).min(1.0),
+            ),
+            (
+                "label entropy",
+                0.25,
+                ((self.total_entropy / queries - 2.5) / 1.5).clamp(0.0, 1.0),
+            ),
+            (
+                "label length",
+                0.2,
+                ((self.total_length as f64 / queries - 10.0) / 40.0).clamp(0.0, 1.0),
+            ),
+            ("nxdomain ratio", 0.25, self.nxdomains as f64 / queries),
+        ];
+
+        let score = signals.iter().map(|(_, w, s)| w * s).sum();
+        let reason = signals
+            .iter()
+            .filter(|(_, _, s)| *s >= 0.5)
+            .map(|(n, _, _)| *n)
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        Some(Suspicion { score, reason })
+    }
+}
+
+/// Mirrors the roll up `domains::resolve` does, the shortest known suffix wins, except
+/// that a parent is never shorter than the registrable domain. Names with no known
+/// parent fall back to their registrable domain so new tunnels still group together.
+/// Returns the parent and the subdomain part in front of it.
+pub fn split_parent(name: &str, known: &HashSet<String>) -> Option<(String, String)> {
+    let name = name.trim_end_matches('.');
+    let registrable = psl::domain_str(name)?;
+    let labels: Vec<&str> = name.split('.').collect();
+    let registrable_start = labels.len() - registrable.split('.').count();
+    if registrable_start == 0 {
+        return None;
+    }
+
+    let suffix = |i: usize| format!("{}.", labels[i..].join("."));
+    let start = (1..=registrable_start)
+        .rev()
+        .find(|i| known.contains(&suffix(*i)))
+        .unwrap_or(registrable_start);
+
+    Some((suffix(start), labels[..start].join(".")))
+}
+
+/// Whether a name is a public suffix like `com.` or `co.uk.`, blocking one would block
+/// every domain under it
+pub fn is_public_suffix(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    psl::suffix_str(name) == Some(name)
+}
+
+/// Parent domains can only be blocked through the existing model: a known domain in no
+/// domain group is blocked for everyone. Public suffixes and domains an admin put in a
+/// group by hand are left alone, returns whether the domain was blocked.
+pub async fn block_domain(pool: &SqlitePool, name: &str) -> Result<bool, sqlx::Error> {
+    if is_public_suffix(name) {
+        tracing::warn!("Not blocking {}, it is a public suffix", name);
+        return Ok(false);
+    }
+
+    let mut tx = pool.begin().await?;
+
+    if memberships::is_manually_set(&mut tx, name).await? {
+        tracing::warn!("Not blocking {}, an admin put it in a domain group", name);
+        return Ok(false);
+    }
+
+    domains::create_if_missing(&mut tx, name, Utc::now(), ANALYZER_CLIENT).await?;
+    domains::remove_from_group(&mut tx, name).await?;
+
+    let status = SuspicionStatus::Blocked.to_string();
+    query!(
+        r#"
+        UPDATE suspicious_domains SET status = ?2 WHERE name = ?1
+        "#,
+        name,
+        status
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Group the window's queries by parent domain
+pub fn collect_stats(
+    queries: &[(String, i64)],
+    known: &HashSet<String>,
+) -> HashMap<String, DomainStats> {
+    let mut stats: HashMap<String, DomainStats> = HashMap::new();
+    for (name, response_code) in queries {
+        if let Some((parent, subdomain)) = split_parent(name, known) {
+            stats
+                .entry(parent)
+                .or_default()
+                .observe(&subdomain, *response_code);
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_scoring() {
+        let known: HashSet<String> = ["tunnel.example.com.".to_string()].into_iter().collect();
+
+        assert_eq!(
+            split_parent("a1.b2.tunnel.example.com.", &known),
+            Some(("tunnel.example.com.".to_string(), "a1.b2".to_string()))
+        );
+        assert_eq!(
+            split_parent("www.unknown.org.", &known),
+            Some(("unknown.org.".to_string(), "www".to_string()))
+        );
+        assert_eq!(split_parent("unknown.org.", &known), None);
+
+        //The shortest known suffix wins like it does for decisions
+        let nested: HashSet<String> = [
+            "example.com.".to_string(),
+            "tunnel.example.com.".to_string(),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            split_parent("a1.tunnel.example.com.", &nested),
+            Some(("example.com.".to_string(), "a1.tunnel".to_string()))
+        );
+
+        //Public suffixes are never a parent, even when something put one in known_domains
+        let public: HashSet<String> = ["co.uk.".to_string()].into_iter().collect();
+        assert_eq!(
+            split_parent("a1.b2.example.co.uk.", &public),
+            Some(("example.co.uk.".to_string(), "a1.b2".to_string()))
+        );
+        assert_eq!(split_parent("example.co.uk.", &public), None);
+        assert!(is_public_suffix("co.uk."));
+        assert!(is_public_suffix("com."));
+        assert!(!is_public_suffix("example.co.uk."));
+
+        let mut queries = vec![];
+        for i in 0..200 {
+            queries.push((
+                format!("mzxw6ytboi4tq{:04}mrtgq2dmnzy.tunnel.example.com.", i),
+                0,
+            ));
+            queries.push(("www.normal.org.".to_string(), 0));
+        }
+        let stats = collect_stats(&queries, &known);
+        let window = Duration::from_secs(15 * 60);
+
+        let tunnel = stats["tunnel.example.com."].score(window, 30).unwrap();
+        let normal = stats["normal.org."].score(window, 30).unwrap();
+        assert!(tunnel.score > 0.5, "{:?}", tunnel);
+        assert!(normal.score < 0.2, "{:?}", normal);
+        assert!(stats["tunnel.example.com."].score(window, 1000).is_none());
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    io, iter,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use trust_dns_server::{
+    authority::{Catalog, MessageRequest, MessageResponse, MessageResponseBuilder},
+    client::{
+        op::{Edns, Header, Message, Query, ResponseCode},
+        rr::Record,
+    },
+    proto::{
+        error::ProtoResult,
+        serialize::binary::{BinDecodable, BinEncodable},
+    },
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+
+/// Identifies a request in flight by where it came from and its message id
+pub type RequestKey = (SocketAddr, u16);
+
+/// Requests whose answers weren't proven secure (blocks, unsigned zones and the like),
+/// those answers must never carry the AD bit since nothing upstream vouched for them.
+#[derive(Clone, Default)]
+pub struct SyntheticAnswers(Arc<Mutex<HashMap<RequestKey, Option<Record>>>>);
+
+impl SyntheticAnswers {
+    pub fn mark(&self, key: RequestKey) {
+        if let Ok(mut keys) = self.0.lock() {
+            keys.entry(key).or_default();
+        }
+    }
+
+    /// Empty answers carry a SOA in the authority section so clients cache them (RFC 2308)
+    pub fn mark_empty(&self, key: RequestKey, soa: Record) {
+        if let Ok(mut keys) = self.0.lock() {
+            keys.insert(key, Some(soa));
+        }
+    }
+
+    fn contains(&self, key: &RequestKey) -> bool {
+        self.0.lock().map(|k| k.contains_key(key)).unwrap_or(false)
+    }
+
+    fn soa(&self, key: &RequestKey) -> Option<Record> {
+        self.0.lock().ok()?.get(key).cloned().flatten()
+    }
+
+    fn clear(&self, key: &RequestKey) {
+        if let Ok(mut keys) = self.0.lock() {
+            keys.remove(key);
+        }
+    }
+}
+
+/// The authorities never get to touch the response header so this wraps the catalog
+/// to flag validated answers with the AD bit when DNSSEC validation is on.
+pub struct ValidatingHandler {
+    catalog: Catalog,
+    dnssec_validation: bool,
+    synthetic_answers: SyntheticAnswers,
+}
+
+impl ValidatingHandler {
+    pub fn create(
+        catalog: Catalog,
+        dnssec_validation: bool,
+        synthetic_answers: SyntheticAnswers,
+    ) -> Self {
+        Self {
+            catalog,
+            dnssec_validation,
+            synthetic_answers,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for ValidatingHandler {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        //Only clients that asked for DNSSEC information get the AD bit (RFC 6840 5.7)
+        let wants_ad = request.header().authentic_data()
+            || request.edns().map(|e| e.dnssec_ok()).unwrap_or(false);
+
+        let key = (request.src(), request.header().id());
+        let response_handle = AuthenticDataHandler {
+            inner: response_handle,
+            set_ad: self.dnssec_validation && wants_ad,
+            key,
+            query: request.query().original().clone(),
+            synthetic_answers: self.synthetic_answers.clone(),
+        };
+
+        let info = self.catalog.handle_request(request, response_handle).await;
+        self.synthetic_answers.clear(&key);
+        info
+    }
+}
+
+#[derive(Clone)]
+struct AuthenticDataHandler<R: ResponseHandler> {
+    inner: R,
+    set_ad: bool,
+    key: RequestKey,
+    query: Query,
+    synthetic_answers: SyntheticAnswers,
+}
+
+impl<R: ResponseHandler> AuthenticDataHandler<R> {
+    /// The catalog never fills the authority section for a forwarding zone, so empty
+    /// answers the forwarder made up are rebuilt around their SOA here
+    async fn send_with_soa(
+        &mut self,
+        header: Header,
+        edns: Option<Edns>,
+        soa: Record,
+    ) -> io::Result<ResponseInfo> {
+        let request = rebuild_request(header.id(), &self.query)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let mut builder = MessageResponseBuilder::from_message_request(&request);
+        if let Some(edns) = edns {
+            builder.edns(edns);
+        }
+        let response = builder.build(
+            header,
+            iter::empty(),
+            iter::empty(),
+            iter::once(&soa),
+            iter::empty(),
+        );
+
+        self.inner.send_response(response).await
+    }
+}
+
+/// The response builder only borrows queries from a request, the original one is long gone by now
+fn rebuild_request(id: u16, query: &Query) -> ProtoResult<MessageRequest> {
+    let mut message = Message::new();
+    message.set_id(id).add_query(query.clone());
+
+    MessageRequest::from_bytes(&message.to_bytes()?)
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for AuthenticDataHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        mut response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        //Bogus answers have already been turned into SERVFAIL by the forwarder
+        if self.set_ad
+            && response.header().response_code() == ResponseCode::NoError
+            && !self.synthetic_answers.contains(&self.key)
+        {
+            response.header_mut().set_authentic_data(true);
+        }
+
+        match self.synthetic_answers.soa(&self.key) {
+            Some(soa) => {
+                let header = *response.header();
+                let edns = response.get_edns().clone();
+                self.send_with_soa(header, edns, soa).await
+            }
+            None => self.inner.send_response(response).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use trust_dns_server::client::rr::{Name, RecordType};
+
+    #[test]
+    fn test_rebuild_request() -> Result<(), Box<dyn std::error::Error>> {
+        let query = Query::query(Name::from_str("blocked.example.com.")?, RecordType::TXT);
+        let request = rebuild_request(42, &query)?;
+
+        assert_eq!(request.id(), 42);
+        assert_eq!(request.query().original(), &query);
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use opentelemetry::{
+    global,
+    sdk::{trace, Resource},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use tracing_subscriber::{
+    filter::ParseError,
+    layer::SubscriberExt,
+    reload,
+    util::{SubscriberInitExt, TryInitError},
+    EnvFilter, Layer,
+};
+
+use crate::config::{LogConfig, LogFormat};
+
+type Reload = dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync;
+
+/// Lets the log level be changed while running, cloned into the admin endpoint
+#[derive(Clone)]
+pub struct LogHandle {
+    reload: Arc<Reload>,
+    directives: Arc<Mutex<String>>,
+}
+
+impl LogHandle {
+    pub fn directives(&self) -> String {
+        self.directives.lock().unwrap().clone()
+    }
+
+    pub fn set_directives(&self, directives: &str) -> Result<(), LoggingError> {
+        let filter = EnvFilter::try_new(directives)?;
+        (self.reload)(filter)?;
+        *self.directives.lock().unwrap() = directives.to_string();
+        Ok(())
+    }
+}
+
+/// The console layer keeps its own filter so tokio-console still works at any log level,
+/// everything else shares the reloadable one. Must be called from inside the runtime
+/// since the OTLP exporter spawns onto it.
+pub fn init(log: &LogConfig) -> Result<LogHandle, LoggingError> {
+    let fmt = tracing_subscriber::fmt::layer();
+    let output = match log.format {
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+        LogFormat::Journald => tracing_journald::layer()?.boxed(),
+    };
+
+    let otlp = match &log.otlp_endpoint {
+        Some(endpoint) => Some(tracing_opentelemetry::layer().with_tracer(otlp_tracer(endpoint)?)),
+        None => None,
+    };
+
+    let directives = log.directives();
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&directives)?);
+
+    tracing_subscriber::registry()
+        .with(console_subscriber::spawn())
+        .with(output.and_then(otlp).with_filter(filter))
+        .try_init()?;
+
+    Ok(LogHandle {
+        reload: Arc::new(move |filter| handle.reload(filter)),
+        directives: Arc::new(Mutex::new(directives)),
+    })
+}
+
+/// Flushes any traces still waiting to be exported
+pub async fn shutdown() {
+    if let Err(e) = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await {
+        tracing::error!("Unable to flush traces |{}", e);
+    }
+}
+
+fn otlp_tracer(endpoint: &str) -> Result<trace::Tracer, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "hmdl")])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+}
+
+#[derive(Debug, Error)]
+pub enum LoggingError {
+    #[error(transparent)]
+    Init(#[from] TryInitError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Reload(#[from] reload::Error),
+    #[error(transparent)]
+    Trace(#[from] TraceError),
+}
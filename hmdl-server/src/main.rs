@@ -1,34 +1,77 @@
+pub mod admin;
+pub mod backup;
 pub mod certificate;
+pub mod config;
 pub mod coordinator;
 pub mod dns;
+pub mod logging;
+pub mod metrics;
+pub mod policy_file;
 pub mod web;
 
-use std::env;
-
+use clap::Parser;
 use git_version::git_version;
+use std::{fmt::Display, path::Path};
+use tokio::runtime::{self, Runtime};
 
-use crate::coordinator::Coordinator;
+use crate::config::{Cli, HmdlConfig};
+use crate::coordinator::{drop_privileges, Coordinator, ServerSockets};
 pub const GIT_VERSION: &str = git_version!();
 
-#[tokio::main]
-async fn main() {
-    // initialize tracing/logging
-    console_subscriber::init();
-    tracing::warn!("Starting hmdl version {}", GIT_VERSION);
+fn main() {
+    let cli = Cli::parse();
 
-    let args: Vec<String> = env::args().collect();
+    let config = HmdlConfig::load(&cli).unwrap_or_else(exit_with);
 
-    if args.len() < 2 {
-        tracing::error!("Database path not provided, exiting");
+    if cli.check_config {
+        println!("Configuration is valid");
         return;
     }
 
-    let path = args.get(1).unwrap();
-    tracing::debug!("Database path is {}", path);
+    if let Some(command) = cli.command {
+        if let Err(e) = runtime().block_on(admin::run(&config, command)) {
+            exit_with(e);
+        }
+        return;
+    }
+
+    //Privileged ports are bound and privileges dropped before the runtime exists so none
+    //of its threads ever run as root or escape the sandbox
+    let sockets = Coordinator::bind(&config).unwrap_or_else(exit_with);
+    let mut writable_files = vec![Path::new(config.database_path().unwrap_or_else(exit_with))];
+    writable_files.extend(config.dns.dnstap.file.as_deref());
+    //Only the directory is kept writable, the name is never used
+    let backup_file = config.backup.directory.as_ref().map(|d| d.join("hmdl.db"));
+    writable_files.extend(backup_file.as_deref());
+    drop_privileges(&config.privileges, &writable_files).unwrap_or_else(exit_with);
+
+    runtime().block_on(run(config, sockets));
+}
+
+async fn run(config: HmdlConfig, sockets: ServerSockets) {
+    let log_handle = logging::init(&config.log).unwrap_or_else(exit_with);
+    tracing::warn!("Starting hmdl version {}", GIT_VERSION);
+    tracing::debug!("Database path is {:?}", config.database_path);
+    if let Some(user) = &config.privileges.user {
+        tracing::info!("Running as user {}", user);
+    }
 
-    let mut coordinator = Coordinator::create(path)
+    let coordinator = Coordinator::create(&config, sockets, log_handle)
         .await
         .expect("Unable to create the HMDL coordinator");
 
     coordinator.start().await.expect("The coordinator exited");
+    logging::shutdown().await;
+}
+
+fn runtime() -> Runtime {
+    runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Unable to start the tokio runtime")
+}
+
+fn exit_with<T>(e: impl Display) -> T {
+    eprintln!("{}", e);
+    std::process::exit(1);
 }
@@ -0,0 +1,237 @@
+use axum::{http::Request, middleware::Next, response::Response};
+use prometheus::{
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use trust_dns_server::client::rr::RecordType;
+
+use crate::coordinator::Health;
+use crate::dns::Decision;
+
+/// Scraping is only enabled once a token is set
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Sent by Prometheus as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+}
+
+/// Prometheus metrics for the whole server. Counters are updated as things happen, the
+/// gauges mirroring state kept elsewhere are refreshed on each scrape.
+pub struct Metrics {
+    registry: Registry,
+    dns_queries: IntCounterVec,
+    decider_seconds: Histogram,
+    upstream_seconds: Histogram,
+    upstream_errors: IntCounter,
+    answer_cache: IntCounterVec,
+    resolver_cache: IntCounterVec,
+    http_requests: IntCounterVec,
+    pool_connections: IntGaugeVec,
+    certificate_expiry: Gauge,
+    upstream_reachable: IntGaugeVec,
+    upstream_probe_seconds: GaugeVec,
+}
+
+impl Metrics {
+    pub fn create() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("hmdl".to_string()), None)?;
+
+        let dns_queries = IntCounterVec::new(
+            Opts::new("dns_queries_total", "DNS queries by decision and type"),
+            &["decision", "qtype"],
+        )?;
+        let decider_seconds = Histogram::with_opts(
+            HistogramOpts::new("decider_seconds", "Time spent deciding whether to filter").buckets(
+                vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25],
+            ),
+        )?;
+        //Answers from the resolver's own cache land in the lowest buckets
+        let upstream_seconds = Histogram::with_opts(
+            HistogramOpts::new("upstream_seconds", "Time taken by the upstream resolver").buckets(
+                vec![
+                    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+                ],
+            ),
+        )?;
+        let upstream_errors = IntCounter::new(
+            "upstream_errors_total",
+            "Lookups that failed to reach any upstream",
+        )?;
+        let answer_cache = IntCounterVec::new(
+            Opts::new(
+                "answer_cache_total",
+                "Answer cache fallbacks when upstream fails, by result",
+            ),
+            &["result"],
+        )?;
+        let resolver_cache = IntCounterVec::new(
+            Opts::new(
+                "resolver_cache_total",
+                "Upstream lookups answered from the resolver's cache, by result",
+            ),
+            &["result"],
+        )?;
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by method and status"),
+            &["method", "status"],
+        )?;
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "sqlite_pool_connections",
+                "SQLite pool connections by pool and state",
+            ),
+            &["pool", "state"],
+        )?;
+        let certificate_expiry = Gauge::new(
+            "certificate_expiry_timestamp_seconds",
+            "When the ACME certificate expires",
+        )?;
+        let upstream_reachable = IntGaugeVec::new(
+            Opts::new(
+                "upstream_reachable",
+                "Whether the last upstream health check worked",
+            ),
+            &["upstream"],
+        )?;
+        let upstream_probe_seconds = GaugeVec::new(
+            Opts::new(
+                "upstream_probe_seconds",
+                "Latency of the last upstream health check",
+            ),
+            &["upstream"],
+        )?;
+
+        registry.register(Box::new(dns_queries.clone()))?;
+        registry.register(Box::new(decider_seconds.clone()))?;
+        registry.register(Box::new(upstream_seconds.clone()))?;
+        registry.register(Box::new(upstream_errors.clone()))?;
+        registry.register(Box::new(answer_cache.clone()))?;
+        registry.register(Box::new(resolver_cache.clone()))?;
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(pool_connections.clone()))?;
+        registry.register(Box::new(certificate_expiry.clone()))?;
+        registry.register(Box::new(upstream_reachable.clone()))?;
+        registry.register(Box::new(upstream_probe_seconds.clone()))?;
+
+        Ok(Self {
+            registry,
+            dns_queries,
+            decider_seconds,
+            upstream_seconds,
+            upstream_errors,
+            answer_cache,
+            resolver_cache,
+            http_requests,
+            pool_connections,
+            certificate_expiry,
+            upstream_reachable,
+            upstream_probe_seconds,
+        })
+    }
+
+    pub fn observe_query(&self, decision: Decision, rtype: RecordType) {
+        self.dns_queries
+            .with_label_values(&[&decision.to_string().to_lowercase(), &rtype.to_string()])
+            .inc();
+    }
+
+    pub fn observe_decider(&self, elapsed: Duration) {
+        self.decider_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_upstream(&self, elapsed: Duration, failed: bool) {
+        self.upstream_seconds.observe(elapsed.as_secs_f64());
+        if failed {
+            self.upstream_errors.inc();
+        }
+    }
+
+    pub fn observe_stale(&self, hit: bool) {
+        let result = if hit { "stale_hit" } else { "stale_miss" };
+        self.answer_cache.with_label_values(&[result]).inc();
+    }
+
+    pub fn observe_resolver_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.resolver_cache.with_label_values(&[result]).inc();
+    }
+
+    /// Middleware counting every HTTP response
+    pub async fn track_http<B>(&self, request: Request<B>, next: Next<B>) -> Response {
+        let method = request.method().to_string();
+        let response = next.run(request).await;
+
+        self.http_requests
+            .with_label_values(&[&method, response.status().as_str()])
+            .inc();
+        response
+    }
+
+    /// Renders the Prometheus text format
+    pub fn encode(
+        &self,
+        writer: &SqlitePool,
+        reader: &SqlitePool,
+        health: &Health,
+    ) -> Result<String, prometheus::Error> {
+        for (name, pool) in [("writer", writer), ("reader", reader)] {
+            let size = pool.size() as i64;
+            let idle = pool.num_idle() as i64;
+            self.pool_connections
+                .with_label_values(&[name, "idle"])
+                .set(idle);
+            self.pool_connections
+                .with_label_values(&[name, "in_use"])
+                .set(size - idle);
+        }
+
+        if let Some(expires) = health.facts.facts().certificate_expires {
+            self.certificate_expiry.set(expires.timestamp() as f64);
+        }
+
+        for upstream in health.upstreams.statuses() {
+            let address = upstream.address.to_string();
+            self.upstream_reachable
+                .with_label_values(&[&address])
+                .set(upstream.reachable.into());
+            if let Some(latency) = upstream.latency_ms {
+                self.upstream_probe_seconds
+                    .with_label_values(&[&address])
+                    .set(latency as f64 / 1000.0);
+            }
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encode() -> Result<(), Box<dyn std::error::Error>> {
+        let metrics = Metrics::create()?;
+        metrics.observe_query(Decision::Block, RecordType::AAAA);
+        metrics.observe_query(Decision::Block, RecordType::AAAA);
+        metrics.observe_stale(true);
+        metrics.observe_resolver_cache(true);
+
+        let writer = SqlitePool::connect("sqlite::memory:").await?;
+        let reader = SqlitePool::connect("sqlite::memory:").await?;
+        let text = metrics.encode(&writer, &reader, &Health::default())?;
+
+        assert!(text.contains(r#"hmdl_dns_queries_total{decision="block",qtype="AAAA"} 2"#));
+        assert!(text.contains(r#"hmdl_answer_cache_total{result="stale_hit"} 1"#));
+        assert!(text.contains(r#"hmdl_resolver_cache_total{result="hit"} 1"#));
+        assert!(text.contains(r#"hmdl_sqlite_pool_connections{pool="reader",state="idle"}"#));
+        assert!(text.contains(r#"hmdl_sqlite_pool_connections{pool="writer",state="idle"}"#));
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use clap::ValueEnum;
+use hmdl_db::dao::policy::{Changes, Policy, PolicyDiff};
+use serde::Deserialize;
+use std::{fmt::Display, path::Path, str::FromStr};
+use thiserror::Error;
+use trust_dns_server::client::rr::RecordType;
+
+use crate::dns::RecordTypeAction;
+
+/// How a policy is written out, the layout is the same either way and is documented in
+/// `hmdl-policy.example.yaml`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyFormat {
+    Json,
+    Yaml,
+}
+
+impl PolicyFormat {
+    /// `.yaml` and `.yml` files are YAML, anything else is JSON
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+
+    /// Record types are normalized the same way the web interface does it
+    pub fn parse(self, text: &str) -> Result<Policy, PolicyFileError> {
+        let mut policy: Policy = match self {
+            Self::Json => serde_json::from_str(text)?,
+            Self::Yaml => serde_yaml::from_str(text)?,
+        };
+
+        for p in &mut policy.record_type_policies {
+            p.record_type = RecordType::from_str(&p.record_type.to_uppercase())
+                .map_err(|_| {
+                    PolicyFileError::Invalid(format!("unknown record type {}", p.record_type))
+                })?
+                .to_string();
+            RecordTypeAction::from_str(&p.action)
+                .map_err(|_| PolicyFileError::Invalid(format!("unknown action {}", p.action)))?;
+        }
+
+        Ok(policy)
+    }
+
+    pub fn render(self, policy: &Policy) -> Result<String, PolicyFileError> {
+        Ok(match self {
+            Self::Json => serde_json::to_string_pretty(policy)?,
+            Self::Yaml => serde_yaml::to_string(policy)?,
+        })
+    }
+}
+
+/// One line per change, `+` for what would be added and `-` for what would be removed
+pub fn describe(diff: &PolicyDiff) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut push = |kind: &str, changes: Vec<(bool, String)>| {
+        for (added, what) in changes {
+            lines.push(format!(
+                "{} {} {}",
+                if added { '+' } else { '-' },
+                kind,
+                what
+            ));
+        }
+    };
+
+    push("client group", flatten(&diff.client_groups, |g| g.clone()));
+    push(
+        "client",
+        flatten(&diff.client_members, |m| {
+            format!("{} in {}", m.name, m.group)
+        }),
+    );
+    push("domain group", flatten(&diff.domain_groups, |g| g.clone()));
+    push(
+        "domain",
+        flatten(&diff.domain_members, |m| {
+            format!("{} in {}", m.name, m.group)
+        }),
+    );
+    push(
+        "applied",
+        flatten(&diff.groups_applied, |a| {
+            format!("{} blocks {}", a.client_group_name, a.domain_group_name)
+        }),
+    );
+    push(
+        "record type",
+        flatten(&diff.record_type_policies, |p| {
+            format!("{} for {} {}", p.record_type, p.client_group_name, p.action)
+        }),
+    );
+    push(
+        "settings",
+        flatten(&diff.settings, |s| {
+            format!("{} {}", s.application_domain, s.acme_email)
+        }),
+    );
+
+    lines
+}
+
+fn flatten<T, D: Display>(changes: &Changes<T>, show: impl Fn(&T) -> D) -> Vec<(bool, String)> {
+    let removed = changes.removed.iter().map(|t| (false, show(t).to_string()));
+    let added = changes.added.iter().map(|t| (true, show(t).to_string()));
+    removed.chain(added).collect()
+}
+
+#[derive(Debug, Error)]
+pub enum PolicyFileError {
+    #[error("invalid policy: {0}")]
+    Invalid(String),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmdl_db::dao::policy;
+
+    #[test]
+    fn test_example_policy() -> Result<(), PolicyFileError> {
+        let example = PolicyFormat::Yaml.parse(include_str!("../../hmdl-policy.example.yaml"))?;
+        assert_eq!(example.client_groups.len(), 2);
+        assert_eq!(example.domain_groups[0].domains.len(), 2);
+        assert!(example.settings.is_some());
+
+        for format in [PolicyFormat::Json, PolicyFormat::Yaml] {
+            assert_eq!(format.parse(&format.render(&example)?)?, example);
+        }
+
+        let mut changed = example.clone();
+        changed.client_groups.pop();
+        changed.settings = None;
+        assert_eq!(
+            describe(&policy::diff(&example, &changed)),
+            vec!["- client group adults"]
+        );
+        assert!(policy::diff(&example, &example).is_empty());
+
+        let unknown_action = r#"
+            client_groups: []
+            domain_groups: []
+            groups_applied: []
+            record_type_policies:
+              - { client_group_name: kids, record_type: HTTPS, action: allow }
+            "#;
+        assert!(matches!(
+            PolicyFormat::Yaml.parse(unknown_action),
+            Err(PolicyFileError::Invalid(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,51 +1,98 @@
-use crate::coordinator::HmdlSetup;
-use axum::{handler::Handler, response::Redirect, Router};
+use crate::backup::Backups;
+use crate::coordinator::{
+    latest, serve_each, serve_on, BoundSockets, Health, HmdlSetup, ListenConfig, ListenError,
+    Shutdown,
+};
+use crate::dns::RateLimiter;
+use crate::logging::LogHandle;
+use crate::metrics::Metrics;
+use axum::{handler::Handler, middleware, response::Redirect, Router};
 use axum_server::tls_rustls::RustlsConfig;
 use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::settings;
 use ring::{
     error::Unspecified,
     rand::{SecureRandom, SystemRandom},
 };
-use sqlx::{query, SqlitePool};
-use std::{
-    io,
-    net::{IpAddr, Ipv6Addr, SocketAddr},
-    sync::Arc,
-};
+use sqlx::SqlitePool;
+use std::{collections::HashSet, io, net::IpAddr, sync::Arc};
 use thiserror::Error;
-use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::sync::watch::{self, error::RecvError};
 use url::{ParseError, Url};
 use webauthn_rs::{prelude::WebauthnError, Webauthn, WebauthnBuilder};
 
+pub mod audit;
 pub mod authentication;
+pub mod backup;
 pub mod client_groups;
 pub mod clients;
 pub mod domain_groups;
 pub mod domains;
 pub mod groups_applied;
 pub mod health;
+pub mod log_level;
+pub mod metrics;
+pub mod policy;
+pub mod query_log;
+pub mod record_type_policies;
 pub mod setup;
+pub mod snapshots;
+pub mod suspicious_domains;
+pub mod throttled_clients;
 pub mod users;
 
-pub const HTTPS_PORT: u16 = 443;
-
 pub struct Endpoints {
     pool: SqlitePool,
+    /// Read only, for the endpoints whose queries scan a lot of rows
+    reader: SqlitePool,
     rand_gen: SystemRandom,
+    rate_limiter: Arc<RateLimiter>,
+    health: Health,
+    metrics: Arc<Metrics>,
+    backups: Arc<Backups>,
+    metrics_token: Option<String>,
+    log_handle: LogHandle,
+    listen: ListenConfig,
+    bound: Option<BoundSockets>,
 }
 
 impl Endpoints {
-    pub fn create(pool: SqlitePool, rand_gen: SystemRandom) -> Result<Self, EndpointsError> {
-        Ok(Self { pool, rand_gen })
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        pool: SqlitePool,
+        reader: SqlitePool,
+        rand_gen: SystemRandom,
+        rate_limiter: Arc<RateLimiter>,
+        health: Health,
+        metrics: Arc<Metrics>,
+        backups: Arc<Backups>,
+        metrics_token: Option<String>,
+        log_handle: LogHandle,
+        listen: ListenConfig,
+        bound: Option<BoundSockets>,
+    ) -> Result<Self, EndpointsError> {
+        Ok(Self {
+            pool,
+            reader,
+            rand_gen,
+            rate_limiter,
+            health,
+            metrics,
+            backups,
+            metrics_token,
+            log_handle,
+            listen,
+            bound,
+        })
     }
 
     pub async fn start(
         &self,
-        mut tls_config_reciever: Receiver<(RustlsConfig, HmdlSetup)>,
+        mut tls_config_reciever: watch::Receiver<Option<(RustlsConfig, HmdlSetup)>>,
+        ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+        shutdown: Shutdown,
     ) -> Result<(), EndpointsError> {
-        let (config, setup) = tls_config_reciever.recv().await?;
-
-        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), HTTPS_PORT);
+        let (config, setup) = latest(&mut tls_config_reciever).await?;
 
         let mut secret: [u8; 64] = [0; 64];
         self.rand_gen.fill(&mut secret)?;
@@ -56,24 +103,40 @@ impl Endpoints {
         let app_serv = self
             .create_router(session_layer, Arc::new(webauthn))
             .into_make_service();
-        let builder = axum_server::bind_rustls(addr, config);
-
         //Update that we are starting the https server
-        let mut conn = self.pool.acquire().await?;
-        query!(
-            r#"
-            UPDATE hmdl_settings
-            SET https_started_once=true
-            WHERE lock_column=true
-            "#
-        )
-        .execute(&mut conn)
-        .await?;
-
-        tracing::info!("HTTPS Server listening on {}", addr);
-        builder.serve(app_serv).await?;
+        settings::mark_https_started(&self.pool).await?;
+
+        if let Some(bound) = &self.bound {
+            tracing::info!("HTTPS Server starting on its pre-bound listeners");
+            return serve_each(bound.try_clone()?.tcp, |listener| {
+                let builder = axum_server::from_tcp_rustls(listener, config.clone())
+                    .handle(shutdown.axum_handle());
+                let app_serv = app_serv.clone();
+                async move {
+                    builder.serve(app_serv).await?;
+                    Ok(())
+                }
+            })
+            .await;
+        }
 
-        Ok(())
+        tracing::info!("HTTPS Server starting on port {}", self.listen.https_port);
+        serve_on(
+            &self.listen,
+            self.listen.https_port,
+            ip_changed,
+            shutdown.clone(),
+            |addr| {
+                let builder =
+                    axum_server::bind_rustls(addr, config.clone()).handle(shutdown.axum_handle());
+                let app_serv = app_serv.clone();
+                async move {
+                    builder.serve(app_serv).await?;
+                    Ok(())
+                }
+            },
+        )
+        .await
     }
 
     fn create_router(
@@ -88,13 +151,58 @@ impl Endpoints {
             session_layer.clone(),
             webauthn,
         ));
-        app = app.merge(clients::router(self.pool.clone()));
-        app = app.merge(client_groups::router(self.pool.clone()));
-        app = app.merge(domains::router(self.pool.clone(), session_layer));
-        app = app.merge(domain_groups::router(self.pool.clone()));
-        app = app.merge(groups_applied::router(self.pool.clone()));
-        app = app.merge(health::router());
+        app = app.merge(audit::router(self.reader.clone(), session_layer.clone()));
+        app = app.merge(backup::router(
+            self.pool.clone(),
+            self.backups.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(clients::router(self.pool.clone(), session_layer.clone()));
+        app = app.merge(client_groups::router(
+            self.pool.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(domains::router(self.pool.clone(), session_layer.clone()));
+        app = app.merge(domain_groups::router(
+            self.pool.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(groups_applied::router(
+            self.pool.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(health::router(self.pool.clone(), self.health.clone()));
+        app = app.merge(log_level::router(
+            self.log_handle.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(metrics::router(
+            self.pool.clone(),
+            self.reader.clone(),
+            self.health.clone(),
+            self.metrics.clone(),
+            self.metrics_token.clone(),
+        ));
+        app = app.merge(policy::router(self.pool.clone(), session_layer.clone()));
+        app = app.merge(query_log::router(
+            self.reader.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(record_type_policies::router(
+            self.pool.clone(),
+            session_layer.clone(),
+        ));
         app = app.merge(setup::router(self.pool.clone()));
+        app = app.merge(snapshots::router(self.pool.clone(), session_layer.clone()));
+        app = app.merge(suspicious_domains::router(
+            self.pool.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(throttled_clients::router(
+            self.rate_limiter.clone(),
+            session_layer.clone(),
+        ));
+        app = app.merge(users::router(self.pool.clone(), session_layer));
 
         //Only enable embedded static content if we're in release mode
         #[cfg(debug_assertions)]
@@ -106,7 +214,11 @@ impl Endpoints {
             app = app.merge(crate::web::frontend::router());
         }
 
-        app
+        let metrics = self.metrics.clone();
+        app.layer(middleware::from_fn(move |request, next| {
+            let metrics = metrics.clone();
+            async move { metrics.track_http(request, next).await }
+        }))
     }
 }
 
@@ -115,6 +227,8 @@ pub enum EndpointsError {
     #[error(transparent)]
     Io(#[from] io::Error),
     #[error(transparent)]
+    Listen(#[from] ListenError),
+    #[error(transparent)]
     Recv(#[from] RecvError),
     #[error(transparent)]
     SqlxError(#[from] sqlx::Error),
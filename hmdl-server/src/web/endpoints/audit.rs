@@ -0,0 +1,28 @@
+use axum::{extract::Query, routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, AuditEntry, AuditFilter};
+use sqlx::SqlitePool;
+use tower::ServiceBuilder;
+
+use crate::web::util::{is_admin, ApiContext, ApiResult};
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new().route("/api/audit", get(list_entries)).layer(
+        ServiceBuilder::new()
+            .layer(Extension(ApiContext { pool }))
+            .layer(session_layer)
+            .layer(axum::middleware::from_fn(is_admin)),
+    )
+}
+
+/// Newest first, page back with `before_id` set to the last id seen
+async fn list_entries(
+    ctx: Extension<ApiContext>,
+    Query(filter): Query<AuditFilter>,
+) -> ApiResult<Json<Vec<AuditEntry>>> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let entries = audit::find_recent(&mut conn, &filter).await?;
+
+    Ok(Json(entries))
+}
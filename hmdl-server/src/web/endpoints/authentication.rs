@@ -1,4 +1,4 @@
-use crate::web::util::{ApiContextAuth, ApiResult};
+use crate::web::util::{ApiContextAuth, ApiError, ApiResult};
 use axum::extract::{Extension, Json};
 use axum::{routing::post, Router};
 use axum_sessions::{
@@ -8,6 +8,7 @@ use axum_sessions::{
 };
 use hmdl_db::dao::roles::Roles;
 use hmdl_db::dao::users::{self, User};
+use ring::digest::{digest, SHA256};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use std::sync::Arc;
@@ -44,15 +45,28 @@ pub fn router(
 #[derive(Debug, Deserialize, Serialize)]
 pub struct StartRegistration {
     username: String,
+    /// Printed by `hmdl admin users reset-passkey`, only needed to register a user again
+    #[serde(default)]
+    recovery_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct RegistrationData {
     username: String,
     id: Uuid,
+    recovery_token: Option<String>,
     state: PasskeyRegistration,
 }
 
+/// What `users::reset_keys` stores in place of the token itself
+pub fn recovery_token_hash(token: &str) -> String {
+    digest(&SHA256, token.trim().as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
 pub async fn start_register(
     Extension(ctx): Extension<ApiContextAuth>,
     Json(sr): Json<StartRegistration>,
@@ -95,6 +109,7 @@ pub async fn start_register(
             RegistrationData {
                 username: user.display_name,
                 id: user.id,
+                recovery_token: sr.recovery_token,
                 state: reg_state,
             },
         )
@@ -121,15 +136,43 @@ pub async fn finish_register(
         .webauthn
         .finish_passkey_registration(&cred.reg_pub_cred, &reg_data.state)?;
 
-    let mut new_user = User {
-        display_name: reg_data.username,
-        id: reg_data.id,
-        keys: vec![res],
-        role: Roles::Registered,
+    //Users whose passkeys were reset from the command line keep their id and role, so
+    //the name alone isn't enough or anyone could register it and take over the account
+    let new_user = match users::find_by_name(&ctx.pool, &reg_data.username).await? {
+        Some(mut existing) if existing.keys.is_empty() => {
+            let token = reg_data
+                .recovery_token
+                .as_deref()
+                .ok_or(ApiError::Forbidden)?;
+
+            let mut tran = ctx.pool.begin().await?;
+            if !users::take_recovery_token(
+                &mut tran,
+                &reg_data.username,
+                &recovery_token_hash(token),
+            )
+            .await?
+            {
+                return Err(ApiError::Forbidden);
+            }
+            existing.keys.push(res);
+            users::update(&mut tran, &reg_data.username, &existing).await?;
+            tran.commit().await?;
+
+            existing
+        }
+        _ => {
+            let mut new_user = User {
+                display_name: reg_data.username,
+                id: reg_data.id,
+                keys: vec![res],
+                role: Roles::Registered,
+            };
+            users::create(&ctx.pool, &mut new_user).await?;
+            new_user
+        }
     };
 
-    users::create(&ctx.pool, &mut new_user).await?;
-
     let role = new_user.role;
 
     session
@@ -0,0 +1,53 @@
+use axum::{
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::IntoResponse,
+    routing::post,
+    Extension, Router,
+};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, Change};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tower::ServiceBuilder;
+
+use crate::backup::Backups;
+use crate::web::util::{is_admin, Actor, ApiContext, ApiResult};
+
+pub fn router(
+    pool: SqlitePool,
+    backups: Arc<Backups>,
+    session_layer: SessionLayer<MemoryStore>,
+) -> Router {
+    Router::new()
+        .route("/api/backup", post(download_backup))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(Extension(backups))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+/// A POST since every call copies the whole database, restore it with
+/// `hmdl admin backup restore` while hmdl is stopped
+async fn download_backup(
+    ctx: Extension<ApiContext>,
+    Extension(backups): Extension<Arc<Backups>>,
+    Actor(user): Actor,
+) -> ApiResult<impl IntoResponse> {
+    let backup = backups.take().await.map_err(anyhow::Error::from)?;
+
+    let name = backups.file_name();
+    let disposition = format!("attachment; filename=\"{}\"", name);
+
+    audit::record(&ctx.pool, &user, &Change::new("backup.download", &name)).await?;
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/octet-stream".to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        backup,
+    ))
+}
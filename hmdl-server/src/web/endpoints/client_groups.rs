@@ -1,12 +1,16 @@
-use crate::web::util::{ApiContext, ApiResult};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiResult};
 
 use axum::{extract::Path, routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, Change};
+use hmdl_db::dao::client_groups;
+use hmdl_db::dao::clients::{self, Client};
+use hmdl_db::dao::snapshots;
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, SqlitePool};
+use sqlx::{Acquire, SqlitePool};
+use tower::ServiceBuilder;
 
-use super::clients::Client;
-
-pub fn router(pool: SqlitePool) -> Router {
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
     Router::new()
         .route("/api/client-groups", get(list_groups))
         .route(
@@ -16,22 +20,18 @@ pub fn router(pool: SqlitePool) -> Router {
                 .post(add_group)
                 .put(update_group),
         )
-        .layer(Extension(ApiContext { pool }))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
 }
 
 async fn list_groups(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<String>>> {
     let mut conn = ctx.pool.acquire().await?;
 
-    let groups = query!(
-        r#"
-        SELECT name
-        FROM client_groups
-        ORDER BY name
-        "#
-    )
-    .map(|x| x.name)
-    .fetch_all(&mut conn)
-    .await?;
+    let groups = client_groups::find_all(&mut conn).await?;
 
     Ok(Json(groups))
 }
@@ -48,32 +48,8 @@ async fn list_group_detail(
 ) -> ApiResult<Json<GroupDetail>> {
     let mut conn = ctx.pool.acquire().await?;
 
-    let clients = query_as!(
-        Client,
-        r#"
-        SELECT name, ip, mac
-        FROM clients
-        INNER JOIN 
-        client_group_member ON client_group_member.client_name = clients.name
-        WHERE group_name = ?1
-        ORDER BY name
-        "#,
-        name
-    )
-    .fetch_all(&mut conn)
-    .await?;
-
-    let domain_groups = query!(
-        r#"
-        SELECT domain_group_name
-        FROM groups_applied
-        WHERE client_group_name = ?1
-        "#,
-        name
-    )
-    .map(|x| x.domain_group_name)
-    .fetch_all(&mut conn)
-    .await?;
+    let clients = clients::find_by_group(&mut conn, &name).await?;
+    let domain_groups = client_groups::find_domain_groups(&mut conn, &name).await?;
 
     Ok(Json(GroupDetail {
         clients,
@@ -81,32 +57,40 @@ async fn list_group_detail(
     }))
 }
 
-async fn add_group(ctx: Extension<ApiContext>, Path(name): Path<String>) -> ApiResult<Json<()>> {
+async fn add_group(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Path(name): Path<String>,
+) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    client_groups::create(&mut tran, &name).await?;
 
-    query!(
-        r#"
-        INSERT INTO client_groups (name) VALUES (?1)
-        "#,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    audit::record(&mut tran, &user, &Change::new("client_group.create", &name)).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
-async fn delete_group(ctx: Extension<ApiContext>, Path(name): Path<String>) -> ApiResult<Json<()>> {
+async fn delete_group(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Path(name): Path<String>,
+) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        DELETE FROM client_groups where name = ?1
-        "#,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    snapshots::take(&mut tran, &format!("before deleting client group {}", name)).await?;
+    let before = GroupDetail {
+        clients: clients::find_by_group(&mut tran, &name).await?,
+        domain_groups: client_groups::find_domain_groups(&mut tran, &name).await?,
+    };
+    client_groups::delete(&mut tran, &name).await?;
+
+    let change = Change::new("client_group.delete", &name).before(before);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
@@ -118,22 +102,20 @@ struct UpdateGroup {
 
 async fn update_group(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
     Json(req): Json<UpdateGroup>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    client_groups::rename(&mut tran, &name, &req.name).await?;
 
-    query!(
-        r#"
-        UPDATE client_groups
-        SET name = ?1
-        WHERE name = ?2
-        "#,
-        req.name,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let change = Change::new("client_group.rename", &name)
+        .before(&name)
+        .after(&req.name);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
@@ -3,12 +3,16 @@ use axum::{
     routing::{delete, get},
     Extension, Json, Router,
 };
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, Change};
+use hmdl_db::dao::clients::{self, Client};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, SqlitePool};
+use sqlx::{Acquire, SqlitePool};
+use tower::ServiceBuilder;
 
-use crate::web::util::{ApiContext, ApiResult};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiResult};
 
-pub fn router(pool: SqlitePool) -> Router {
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
     Router::new()
         .route("/api/clients", get(list_uncat_clients))
         .route(
@@ -19,96 +23,75 @@ pub fn router(pool: SqlitePool) -> Router {
             "/api/clients/:name/group",
             delete(remove_client_from_group).put(update_client_group),
         )
-        .layer(Extension(ApiContext { pool }))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
 }
 
 async fn delete_client(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        DELETE FROM clients where name = ?1
-        "#,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let before = clients::find_by_name(&mut tran, &name).await?;
+    clients::delete(&mut tran, &name).await?;
 
-    Ok(Json(()))
-}
+    let change = Change::new("client.delete", &name).before(before);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
-#[derive(Deserialize, Serialize, sqlx::FromRow)]
-pub struct Client {
-    pub name: String,
-    pub ip: String,
-    pub mac: String,
+    Ok(Json(()))
 }
 
 async fn list_uncat_clients(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<Client>>> {
     let mut conn = ctx.pool.acquire().await?;
 
-    let clients = query_as!(
-        Client,
-        r#"
-        SELECT name, ip, mac
-        FROM clients
-        EXCEPT
-        SELECT name, ip, mac
-        FROM clients
-            INNER JOIN client_group_member on client_group_member.client_name = clients.name
-        ORDER BY name
-        "#
-    )
-    .fetch_all(&mut conn)
-    .await?;
+    let clients = clients::find_uncategorized(&mut conn).await?;
 
     Ok(Json(clients))
 }
 
 async fn update_client(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
     Json(req): Json<Client>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let before = clients::find_by_name(&mut tran, &name).await?;
+    clients::update(&mut tran, &name, &req).await?;
 
-    query!(
-        r#"
-        UPDATE clients
-        SET name = ?1,
-            ip = ?2,
-            mac = ?3
-        WHERE name = ?4
-        "#,
-        req.name,
-        req.ip,
-        req.mac,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let change = Change::new("client.update", &name)
+        .before(before)
+        .after(&req);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
 async fn remove_client_from_group(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        DELETE FROM client_group_member
-        WHERE client_name = ?1 
-        "#,
-        name,
-    )
-    .execute(&mut conn)
-    .await?;
+    let before = clients::find_group(&mut tran, &name).await?;
+    clients::remove_from_group(&mut tran, &name).await?;
+
+    let change = Change::new("client.remove_group", &name).before(before);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
@@ -120,38 +103,20 @@ struct UpdateClientGroup {
 
 async fn update_client_group(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
     Json(new_group_name): Json<UpdateClientGroup>,
 ) -> ApiResult<Json<()>> {
-    let mut tran = ctx.pool.begin().await?;
-
-    query!(
-        r#"
-        DELETE FROM client_group_member
-        WHERE client_name = ?1 
-        "#,
-        name,
-    )
-    .execute(&mut tran)
-    .await?;
-
-    query!(
-        r#"
-        INSERT INTO client_group_member(
-            client_name,
-            group_name
-        )
-        VALUES (
-            ?1,
-            ?2
-        )
-        "#,
-        name,
-        new_group_name.new_group_name,
-    )
-    .execute(&mut tran)
-    .await?;
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let before = clients::find_group(&mut tran, &name).await?;
+    clients::set_group(&mut tran, &name, &new_group_name.new_group_name).await?;
 
+    let change = Change::new("client.set_group", &name)
+        .before(before)
+        .after(&new_group_name.new_group_name);
+    audit::record(&mut tran, &user, &change).await?;
     tran.commit().await?;
 
     Ok(Json(()))
@@ -1,10 +1,15 @@
-use crate::web::util::{ApiContext, ApiResult};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiError, ApiResult};
 
 use axum::{extract::Path, routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, Change};
+use hmdl_db::dao::domain_groups::{self, DomainGroup};
+use hmdl_db::dao::snapshots;
 use serde::{Deserialize, Serialize};
-use sqlx::{query, SqlitePool};
+use sqlx::{Acquire, SqlitePool};
+use tower::ServiceBuilder;
 
-pub fn router(pool: SqlitePool) -> Router {
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
     Router::new()
         .route("/api/domain-groups", get(list_groups))
         .route(
@@ -14,22 +19,18 @@ pub fn router(pool: SqlitePool) -> Router {
                 .post(add_group)
                 .put(update_group),
         )
-        .layer(Extension(ApiContext { pool }))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
 }
 
 async fn list_groups(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<String>>> {
     let mut conn = ctx.pool.acquire().await?;
 
-    let groups = query!(
-        r#"
-        SELECT name
-        FROM domain_groups
-        ORDER BY name
-        "#
-    )
-    .map(|x| x.name)
-    .fetch_all(&mut conn)
-    .await?;
+    let groups = domain_groups::find_all(&mut conn).await?;
 
     Ok(Json(groups))
 }
@@ -47,93 +48,78 @@ async fn list_group_detail(
 ) -> ApiResult<Json<GroupDetail>> {
     let mut conn = ctx.pool.acquire().await?;
 
-    let (group_name, model_status) = query!(
-        r#"
-        SELECT name, model_status
-        FROM domain_groups
-        ORDER BY name
-        "#
-    )
-    .map(|x| (x.name, x.model_status))
-    .fetch_one(&mut conn)
-    .await?;
-
-    let domains = query!(
-        r#"
-        SELECT domain_name
-        FROM domain_group_member
-        WHERE group_name = ?1
-        ORDER BY domain_name
-        "#,
-        name
-    )
-    .map(|x| x.domain_name)
-    .fetch_all(&mut conn)
-    .await?;
+    let group = domain_groups::find_by_name(&mut conn, &name)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let domains = domain_groups::find_domains(&mut conn, &name).await?;
 
     Ok(Json(GroupDetail {
-        name: group_name,
-        model_status,
+        name: group.name,
+        model_status: group.model_status,
         domains,
     }))
 }
 
-async fn add_group(ctx: Extension<ApiContext>, Path(name): Path<String>) -> ApiResult<Json<()>> {
+async fn add_group(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Path(name): Path<String>,
+) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    domain_groups::create(&mut tran, &name).await?;
 
-    query!(
-        r#"
-        INSERT INTO domain_groups (name) VALUES (?1)
-        "#,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    audit::record(&mut tran, &user, &Change::new("domain_group.create", &name)).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
-async fn delete_group(ctx: Extension<ApiContext>, Path(name): Path<String>) -> ApiResult<Json<()>> {
+async fn delete_group(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Path(name): Path<String>,
+) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
-
-    query!(
-        r#"
-        DELETE FROM domain_groups where name = ?1
-        "#,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let mut tran = conn.begin().await?;
+
+    snapshots::take(&mut tran, &format!("before deleting domain group {}", name)).await?;
+    let before = match domain_groups::find_by_name(&mut tran, &name).await? {
+        Some(group) => Some(GroupDetail {
+            domains: domain_groups::find_domains(&mut tran, &name).await?,
+            name: group.name,
+            model_status: group.model_status,
+        }),
+        None => None,
+    };
+    domain_groups::delete(&mut tran, &name).await?;
+
+    let change = Change::new("domain_group.delete", &name).before(before);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
-#[derive(Deserialize)]
-struct UpdateGroup {
-    name: String,
-    model_status: String,
-}
-
 async fn update_group(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
-    Json(req): Json<UpdateGroup>,
+    Json(req): Json<DomainGroup>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let before = domain_groups::find_by_name(&mut tran, &name).await?;
+    domain_groups::update(&mut tran, &name, &req).await?;
 
-    query!(
-        r#"
-        UPDATE domain_groups
-        SET name = ?1,
-            model_status = ?2
-        WHERE name = ?3
-        "#,
-        req.name,
-        req.model_status,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let change = Change::new("domain_group.update", &name)
+        .before(before)
+        .after(&req);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
@@ -4,12 +4,13 @@ use axum::{
     Extension, Json, Router,
 };
 use axum_sessions::{async_session::MemoryStore, SessionLayer};
-use chrono::NaiveDateTime;
+use hmdl_db::dao::audit::{self, Change};
+use hmdl_db::dao::domains::{self, Domain};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as, SqlitePool};
+use sqlx::{Acquire, SqlitePool};
 use tower::ServiceBuilder;
 
-use crate::web::util::{is_admin, ApiContext, ApiResult};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiResult};
 
 pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
     Router::new()
@@ -32,93 +33,65 @@ pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Rou
 
 async fn delete_domain(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        DELETE FROM known_domains where name = ?1
-        "#,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let before = domains::find_by_name(&mut tran, &name).await?;
+    domains::delete(&mut tran, &name).await?;
 
-    Ok(Json(()))
-}
+    let change = Change::new("domain.delete", &name).before(before);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
-#[derive(Deserialize, Serialize, sqlx::FromRow)]
-pub struct Domain {
-    pub name: String,
-    pub last_seen: NaiveDateTime,
-    pub last_client: String,
+    Ok(Json(()))
 }
 
 async fn list_uncat_domains(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<Domain>>> {
     let mut conn = ctx.pool.acquire().await?;
 
-    let domains = query_as!(
-        Domain,
-        r#"
-        SELECT name, last_seen, last_client
-        FROM known_domains
-        EXCEPT
-        SELECT name, last_seen, last_client
-        FROM known_domains
-            INNER JOIN domain_group_member on domain_group_member.domain_name = known_domains.name
-        WHERE 
-            manually_set = true
-        ORDER BY name
-        "#
-    )
-    .fetch_all(&mut conn)
-    .await?;
+    let domains = domains::find_uncategorized(&mut conn).await?;
 
     Ok(Json(domains))
 }
 
 async fn update_domain(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
     Json(req): Json<Domain>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let before = domains::find_by_name(&mut tran, &name).await?;
+    domains::update(&mut tran, &name, &req).await?;
 
-    query!(
-        r#"
-        UPDATE known_domains
-        SET name = ?1,
-            last_seen = ?2,
-            last_client = ?3
-        WHERE name = ?4
-        "#,
-        req.name,
-        req.last_seen,
-        req.last_client,
-        name
-    )
-    .execute(&mut conn)
-    .await?;
+    let change = Change::new("domain.update", &name)
+        .before(before)
+        .after(&req);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
 async fn remove_domain_from_group(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        DELETE FROM domain_group_member
-        WHERE domain_name = ?1 
-        "#,
-        name,
-    )
-    .execute(&mut conn)
-    .await?;
+    let before = domains::find_group(&mut tran, &name).await?;
+    domains::remove_from_group(&mut tran, &name).await?;
+
+    let change = Change::new("domain.remove_group", &name).before(before);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
@@ -130,40 +103,20 @@ struct UpdateDomainGroup {
 
 async fn update_domain_group(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Path(name): Path<String>,
     Json(new_group_name): Json<UpdateDomainGroup>,
 ) -> ApiResult<Json<()>> {
-    let mut tran = ctx.pool.begin().await?;
-
-    query!(
-        r#"
-        DELETE FROM domain_group_member
-        WHERE domain_name = ?1 
-        "#,
-        name,
-    )
-    .execute(&mut tran)
-    .await?;
-
-    query!(
-        r#"
-        INSERT INTO domain_group_member(
-            domain_name,
-            group_name,
-            manually_set
-        )
-        VALUES (
-            ?1,
-            ?2,
-            true
-        )
-        "#,
-        name,
-        new_group_name.new_group_name,
-    )
-    .execute(&mut tran)
-    .await?;
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let before = domains::find_group(&mut tran, &name).await?;
+    domains::set_group(&mut tran, &name, &new_group_name.new_group_name).await?;
 
+    let change = Change::new("domain.set_group", &name)
+        .before(before)
+        .after(&new_group_name.new_group_name);
+    audit::record(&mut tran, &user, &change).await?;
     tran.commit().await?;
 
     Ok(Json(()))
@@ -1,19 +1,28 @@
-use crate::web::util::{ApiContext, ApiResult};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiResult};
 
 use axum::{routing::post, Extension, Json, Router};
-use serde::Deserialize;
-use sqlx::{query, SqlitePool};
-
-pub fn router(pool: SqlitePool) -> Router {
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, Change};
+use hmdl_db::dao::groups_applied;
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, SqlitePool};
+use tower::ServiceBuilder;
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
     Router::new()
         .route(
             "/api/groups-applied",
             post(add_domain_to_client).put(del_domain_from_client),
         )
-        .layer(Extension(ApiContext { pool }))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct DomainClient {
     client_group: String,
     domain_group: String,
@@ -21,41 +30,34 @@ struct DomainClient {
 
 async fn add_domain_to_client(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Json(req): Json<DomainClient>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        INSERT INTO groups_applied (client_group_name, domain_group_name) VALUES (?1, ?2)
-        "#,
-        req.client_group,
-        req.domain_group
-    )
-    .execute(&mut conn)
-    .await?;
+    groups_applied::create(&mut tran, &req.client_group, &req.domain_group).await?;
+
+    let change = Change::new("group_applied.create", &req.client_group).after(&req);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
 async fn del_domain_from_client(
     ctx: Extension<ApiContext>,
+    Actor(user): Actor,
     Json(req): Json<DomainClient>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    groups_applied::delete(&mut tran, &req.client_group, &req.domain_group).await?;
 
-    query!(
-        r#"
-        DELETE FROM groups_applied 
-        WHERE
-            client_group_name = ?1
-            and domain_group_name = ?2
-        "#,
-        req.client_group,
-        req.domain_group
-    )
-    .execute(&mut conn)
-    .await?;
+    let change = Change::new("group_applied.delete", &req.client_group).before(&req);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
@@ -1,11 +1,95 @@
-use axum::{routing::get, Json, Router};
+use axum::{http::StatusCode, routing::get, Extension, Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tokio::time::timeout;
 
-use crate::web::util::ApiResult;
+use crate::coordinator::{Health, ServiceState, ServiceStatus, UpstreamStatus};
+use crate::web::util::{ApiContext, ApiResult};
+use crate::GIT_VERSION;
 
-pub fn router() -> Router {
-    Router::new().route("/api/health", get(health))
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router(pool: SqlitePool, health: Health) -> Router {
+    Router::new()
+        .route("/api/health", get(liveness))
+        .route("/api/health/upstreams", get(upstreams))
+        .route("/api/ready", get(readiness))
+        .layer(Extension(ApiContext { pool }))
+        .layer(Extension(health))
+}
+
+#[derive(Serialize)]
+struct Liveness {
+    status: &'static str,
+    version: &'static str,
+}
+
+/// The process is up and serving HTTP, nothing else is checked
+async fn liveness() -> ApiResult<Json<Liveness>> {
+    Ok(Json(Liveness {
+        status: "ok",
+        version: GIT_VERSION,
+    }))
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    version: &'static str,
+    dns_listening: bool,
+    database_reachable: bool,
+    certificate_days_remaining: Option<i64>,
+    cloudflare_last_sync: Option<DateTime<Utc>>,
+    upstreams: Vec<UpstreamStatus>,
+    services: Vec<ServiceStatus>,
+}
+
+/// Ready once DNS is answering, the database responds, no service is stuck restarting
+/// and at least one upstream is reachable. Answers 503 with the same report otherwise.
+async fn readiness(
+    Extension(ctx): Extension<ApiContext>,
+    Extension(health): Extension<Health>,
+) -> (StatusCode, Json<Readiness>) {
+    let database_reachable = matches!(
+        timeout(DATABASE_TIMEOUT, sqlx::query("SELECT 1").execute(&ctx.pool)).await,
+        Ok(Ok(_))
+    );
+
+    let facts = health.facts.facts();
+    let upstreams = health.upstreams.statuses();
+    let services = health.services.statuses();
+
+    //Upstreams that haven't been checked yet don't count against readiness
+    let upstream_reachable = upstreams.is_empty() || upstreams.iter().any(|u| u.reachable);
+    let services_running = !services
+        .iter()
+        .any(|s| matches!(s.state, ServiceState::Restarting { .. }));
+
+    let ready = facts.dns_listening && database_reachable && upstream_reachable && services_running;
+
+    let report = Readiness {
+        ready,
+        version: GIT_VERSION,
+        dns_listening: facts.dns_listening,
+        database_reachable,
+        certificate_days_remaining: facts
+            .certificate_expires
+            .map(|expires| (expires - Utc::now()).num_days()),
+        cloudflare_last_sync: facts.cloudflare_last_sync,
+        upstreams,
+        services,
+    };
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
 }
 
-async fn health() -> ApiResult<Json<String>> {
-    Ok(Json("Ok".to_string()))
+async fn upstreams(Extension(health): Extension<Health>) -> ApiResult<Json<Vec<UpstreamStatus>>> {
+    Ok(Json(health.upstreams.statuses()))
 }
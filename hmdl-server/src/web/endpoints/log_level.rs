@@ -0,0 +1,48 @@
+use axum::{routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use serde::{Deserialize, Serialize};
+use tower::ServiceBuilder;
+
+use crate::logging::{LogHandle, LoggingError};
+use crate::web::util::{is_admin, ApiError, ApiResult};
+
+pub fn router(log_handle: LogHandle, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route("/api/log-level", get(current_level).put(set_level))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(log_handle))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+/// Same syntax as `log.level` in the configuration, e.g. `info,hmdl::dns=debug`
+#[derive(Deserialize, Serialize)]
+struct LogLevel {
+    directives: String,
+}
+
+async fn current_level(Extension(log_handle): Extension<LogHandle>) -> ApiResult<Json<LogLevel>> {
+    Ok(Json(LogLevel {
+        directives: log_handle.directives(),
+    }))
+}
+
+/// Lasts until the next restart, the configuration file is left alone
+async fn set_level(
+    Extension(log_handle): Extension<LogHandle>,
+    Json(req): Json<LogLevel>,
+) -> ApiResult<Json<LogLevel>> {
+    log_handle
+        .set_directives(&req.directives)
+        .map_err(|e| match e {
+            LoggingError::Parse(_) => {
+                ApiError::unprocessable_entity([("directives", "invalid filter directive")])
+            }
+            e => anyhow::Error::from(e).into(),
+        })?;
+    tracing::warn!("Log level changed to {}", req.directives);
+
+    Ok(Json(req))
+}
@@ -0,0 +1,64 @@
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    http::header::CONTENT_TYPE,
+    response::IntoResponse,
+    routing::get,
+    Extension, Router, TypedHeader,
+};
+use ring::constant_time::verify_slices_are_equal;
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+use crate::coordinator::Health;
+use crate::metrics::Metrics;
+use crate::web::util::{ApiContext, ApiError, ApiResult};
+
+#[derive(Clone)]
+struct MetricsToken(Arc<String>);
+
+#[derive(Clone)]
+struct ReaderPool(SqlitePool);
+
+/// Without a token configured there is nothing to route, the path falls through like any
+/// other unknown one
+pub fn router(
+    pool: SqlitePool,
+    reader: SqlitePool,
+    health: Health,
+    metrics: Arc<Metrics>,
+    token: Option<String>,
+) -> Router {
+    let token = match token {
+        Some(t) => MetricsToken(Arc::new(t)),
+        None => return Router::new(),
+    };
+
+    Router::new()
+        .route("/metrics", get(scrape))
+        .layer(Extension(ApiContext { pool }))
+        .layer(Extension(ReaderPool(reader)))
+        .layer(Extension(health))
+        .layer(Extension(metrics))
+        .layer(Extension(token))
+}
+
+async fn scrape(
+    Extension(ctx): Extension<ApiContext>,
+    Extension(ReaderPool(reader)): Extension<ReaderPool>,
+    Extension(health): Extension<Health>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(token): Extension<MetricsToken>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> ApiResult<impl IntoResponse> {
+    let authorized = bearer.map_or(false, |TypedHeader(Authorization(bearer))| {
+        verify_slices_are_equal(bearer.token().as_bytes(), token.0.as_bytes()).is_ok()
+    });
+    if !authorized {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let body = metrics
+        .encode(&ctx.pool, &reader, &health)
+        .map_err(anyhow::Error::from)?;
+    Ok(([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
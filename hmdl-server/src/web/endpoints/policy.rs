@@ -0,0 +1,88 @@
+use axum::{
+    extract::Query, http::header::CONTENT_TYPE, response::IntoResponse, routing::get, Extension,
+    Json, Router,
+};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::{
+    audit::{self, Change},
+    policy::{self, PolicyDiff},
+    snapshots,
+};
+use serde::Deserialize;
+use sqlx::{Acquire, SqlitePool};
+use tower::ServiceBuilder;
+
+use crate::policy_file::PolicyFormat;
+use crate::web::util::{is_admin, Actor, ApiContext, ApiError, ApiResult};
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route("/api/policy", get(export_policy).post(import_policy))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+#[derive(Deserialize)]
+struct ExportOptions {
+    format: Option<PolicyFormat>,
+}
+
+async fn export_policy(
+    ctx: Extension<ApiContext>,
+    Query(options): Query<ExportOptions>,
+) -> ApiResult<impl IntoResponse> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let format = options.format.unwrap_or(PolicyFormat::Json);
+    let exported = format
+        .render(&policy::export(&mut conn).await?)
+        .map_err(anyhow::Error::from)?;
+    let content_type = match format {
+        PolicyFormat::Json => "application/json",
+        PolicyFormat::Yaml => "application/yaml",
+    };
+
+    Ok(([(CONTENT_TYPE, content_type)], exported))
+}
+
+#[derive(Deserialize)]
+struct ImportOptions {
+    format: Option<PolicyFormat>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The body is the policy file itself, the response is what changed or would change
+async fn import_policy(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Query(options): Query<ImportOptions>,
+    body: String,
+) -> ApiResult<Json<PolicyDiff>> {
+    let imported = options
+        .format
+        .unwrap_or(PolicyFormat::Json)
+        .parse(&body)
+        .map_err(|e| ApiError::unprocessable_entity([("policy", e.to_string())]))?;
+
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let diff = policy::diff(&policy::export(&mut tran).await?, &imported);
+    if options.dry_run || diff.is_empty() {
+        return Ok(Json(diff));
+    }
+
+    let id = snapshots::take(&mut tran, "before import").await?;
+    policy::replace(&mut tran, &imported).await?;
+
+    let change = Change::new("policy.import", id.to_string()).after(&diff);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
+
+    Ok(Json(diff))
+}
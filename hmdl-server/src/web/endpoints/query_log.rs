@@ -0,0 +1,48 @@
+use axum::{routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, SqlitePool};
+use tower::ServiceBuilder;
+
+use crate::web::util::{is_admin, ApiContext, ApiResult};
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route("/api/query-log", get(list_queries))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct QueryLog {
+    pub query_time: NaiveDateTime,
+    pub client_ip: String,
+    pub domain_name: String,
+    pub record_type: String,
+    pub decision: String,
+    pub dnssec_status: String,
+    pub response_code: i64,
+}
+
+async fn list_queries(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<QueryLog>>> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let queries = query_as!(
+        QueryLog,
+        r#"
+        SELECT query_time, client_ip, domain_name, record_type, decision, dnssec_status, response_code
+        FROM query_log
+        ORDER BY query_time DESC
+        LIMIT 1000
+        "#
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(Json(queries))
+}
@@ -0,0 +1,96 @@
+use crate::dns::RecordTypeAction;
+use crate::web::util::{is_admin, Actor, ApiContext, ApiError, ApiResult};
+
+use axum::{routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::audit::{self, Change};
+use hmdl_db::dao::record_type_policies::{self, RecordTypePolicy};
+use serde::Deserialize;
+use sqlx::{Acquire, SqlitePool};
+use std::str::FromStr;
+use tower::ServiceBuilder;
+use trust_dns_server::client::rr::RecordType;
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route(
+            "/api/record-type-policies",
+            get(list_policies).post(add_policy).put(del_policy),
+        )
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+async fn list_policies(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<RecordTypePolicy>>> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let policies = record_type_policies::find_all(&mut conn).await?;
+
+    Ok(Json(policies))
+}
+
+#[derive(Deserialize)]
+struct AddPolicy {
+    client_group: String,
+    record_type: String,
+    action: RecordTypeAction,
+}
+
+async fn add_policy(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Json(req): Json<AddPolicy>,
+) -> ApiResult<Json<()>> {
+    let policy = RecordTypePolicy {
+        client_group_name: req.client_group,
+        record_type: parse_record_type(&req.record_type)?,
+        action: req.action.to_string(),
+    };
+
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    record_type_policies::upsert(&mut tran, &policy).await?;
+
+    let change = Change::new("record_type_policy.upsert", &policy.client_group_name).after(&policy);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct DelPolicy {
+    client_group: String,
+    record_type: String,
+}
+
+async fn del_policy(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Json(req): Json<DelPolicy>,
+) -> ApiResult<Json<()>> {
+    let record_type = parse_record_type(&req.record_type)?;
+
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    record_type_policies::delete(&mut tran, &req.client_group, &record_type).await?;
+
+    let change = Change::new("record_type_policy.delete", &req.client_group).before(&record_type);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
+
+    Ok(Json(()))
+}
+
+/// Normalizes the record type so it matches what the decider looks up
+fn parse_record_type(record_type: &str) -> Result<String, ApiError> {
+    RecordType::from_str(&record_type.to_uppercase())
+        .map(|r| r.to_string())
+        .map_err(|_| ApiError::unprocessable_entity([("record_type", "unknown record type")]))
+}
@@ -1,7 +1,8 @@
 use crate::web::util::{ApiContext, ApiResult};
 use axum::{routing::get, Extension, Json, Router};
+use hmdl_db::dao::settings;
 use serde::Serialize;
-use sqlx::{query, SqlitePool};
+use sqlx::SqlitePool;
 use tower::builder::ServiceBuilder;
 
 pub fn router(pool: SqlitePool) -> Router {
@@ -18,19 +19,7 @@ struct SetupStatusResp {
 
 // Api path confirming that the application is not setup
 async fn is_setup(ctx: Extension<ApiContext>) -> ApiResult<Json<SetupStatusResp>> {
-    let mut conn = ctx.pool.acquire().await?;
-
-    let setting_record = query!(
-        r#"
-        SELECT application_domain, https_started_once
-        FROM hmdl_settings
-        WHERE lock_column == true
-        "#
-    )
-    .fetch_optional(&mut conn)
-    .await?;
-
-    let status = match setting_record {
+    let status = match settings::find(&ctx.pool).await? {
         None => SetupStatusResp {
             status: "Not Setup".to_string(),
             domain: None,
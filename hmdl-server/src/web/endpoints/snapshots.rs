@@ -0,0 +1,116 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use hmdl_db::dao::{
+    audit::{self, Change},
+    policy::{self, PolicyDiff},
+    snapshots::{self, Snapshot},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, SqlitePool};
+use tower::ServiceBuilder;
+
+use crate::web::util::{is_admin, Actor, ApiContext, ApiError, ApiResult};
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route("/api/snapshots", get(list_snapshots).post(take_snapshot))
+        .route("/api/snapshots/:id/diff", get(diff_snapshot))
+        .route("/api/snapshots/:id/rollback", post(rollback))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+async fn list_snapshots(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<Snapshot>>> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let snapshots = snapshots::find_all(&mut conn).await?;
+
+    Ok(Json(snapshots))
+}
+
+#[derive(Serialize)]
+struct TakenSnapshot {
+    id: i64,
+}
+
+async fn take_snapshot(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+) -> ApiResult<Json<TakenSnapshot>> {
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let id = snapshots::take(&mut tran, "manual").await?;
+
+    audit::record(
+        &mut tran,
+        &user,
+        &Change::new("snapshot.take", id.to_string()),
+    )
+    .await?;
+    tran.commit().await?;
+
+    Ok(Json(TakenSnapshot { id }))
+}
+
+#[derive(Deserialize)]
+struct DiffTo {
+    /// Compared against the current policy when left out
+    to: Option<i64>,
+}
+
+/// What changed between the snapshot and `to`, so `added` is what a rollback would remove
+async fn diff_snapshot(
+    ctx: Extension<ApiContext>,
+    Path(id): Path<i64>,
+    Query(DiffTo { to }): Query<DiffTo>,
+) -> ApiResult<Json<PolicyDiff>> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let from = snapshots::find_policy(&mut conn, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let to = match to {
+        Some(to) => snapshots::find_policy(&mut conn, to)
+            .await?
+            .ok_or(ApiError::NotFound)?,
+        None => policy::export(&mut conn).await?,
+    };
+
+    Ok(Json(policy::diff(&from, &to)))
+}
+
+/// The current policy is snapshotted first so a rollback can itself be rolled back
+async fn rollback(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<PolicyDiff>> {
+    let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let restored = snapshots::find_policy(&mut tran, id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let current = policy::export(&mut tran).await?;
+
+    let before_id = snapshots::take(&mut tran, &format!("before rollback to {}", id)).await?;
+    policy::replace(&mut tran, &restored).await?;
+
+    let diff = policy::diff(&current, &restored);
+    let change = Change::new("snapshot.rollback", id.to_string())
+        .before(before_id)
+        .after(&diff);
+    audit::record(&mut tran, &user, &change).await?;
+    tran.commit().await?;
+
+    Ok(Json(diff))
+}
@@ -0,0 +1,101 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Extension, Json, Router,
+};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use chrono::NaiveDateTime;
+use hmdl_db::dao::audit::{self, Change};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, SqlitePool};
+use tower::ServiceBuilder;
+
+use crate::dns::tunnel_analyzer::{block_domain, SuspicionStatus};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiError, ApiResult};
+
+pub fn router(pool: SqlitePool, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route("/api/suspicious-domains", get(list_suspicious))
+        .route("/api/suspicious-domains/:name", put(review))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContext { pool }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+#[derive(Deserialize, Serialize, sqlx::FromRow)]
+pub struct SuspiciousDomain {
+    pub name: String,
+    pub score: f64,
+    pub reason: String,
+    pub first_flagged: NaiveDateTime,
+    pub last_flagged: NaiveDateTime,
+    pub status: String,
+}
+
+async fn list_suspicious(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<SuspiciousDomain>>> {
+    let mut conn = ctx.pool.acquire().await?;
+
+    let domains = query_as!(
+        SuspiciousDomain,
+        r#"
+        SELECT name, score, reason, first_flagged, last_flagged, status
+        FROM suspicious_domains
+        ORDER BY last_flagged DESC
+        "#
+    )
+    .fetch_all(&mut conn)
+    .await?;
+
+    Ok(Json(domains))
+}
+
+#[derive(Deserialize)]
+struct Review {
+    status: SuspicionStatus,
+}
+
+async fn review(
+    ctx: Extension<ApiContext>,
+    Actor(user): Actor,
+    Path(name): Path<String>,
+    Json(req): Json<Review>,
+) -> ApiResult<Json<()>> {
+    match req.status {
+        SuspicionStatus::Blocked => {
+            if !block_domain(&ctx.pool, &name).await? {
+                return Err(ApiError::unprocessable_entity([(
+                    "status",
+                    "public suffixes and domains in a group set by hand can't be blocked here",
+                )]));
+            }
+        }
+        SuspicionStatus::Dismissed => {
+            let mut conn = ctx.pool.acquire().await?;
+            let status = req.status.to_string();
+
+            query!(
+                r#"
+                UPDATE suspicious_domains SET status = ?2 WHERE name = ?1
+                "#,
+                name,
+                status
+            )
+            .execute(&mut conn)
+            .await?;
+        }
+        SuspicionStatus::Pending => {
+            return Err(ApiError::unprocessable_entity([(
+                "status",
+                "a review must block or dismiss",
+            )]))
+        }
+    }
+
+    let change = Change::new("suspicious_domain.review", &name).after(req.status.to_string());
+    audit::record(&ctx.pool, &user, &change).await?;
+
+    Ok(Json(()))
+}
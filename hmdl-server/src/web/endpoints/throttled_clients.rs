@@ -0,0 +1,24 @@
+use axum::{routing::get, Extension, Json, Router};
+use axum_sessions::{async_session::MemoryStore, SessionLayer};
+use std::sync::Arc;
+use tower::ServiceBuilder;
+
+use crate::dns::{RateLimiter, ThrottledClient};
+use crate::web::util::{is_admin, ApiContextRateLimit, ApiResult};
+
+pub fn router(rate_limiter: Arc<RateLimiter>, session_layer: SessionLayer<MemoryStore>) -> Router {
+    Router::new()
+        .route("/api/throttled-clients", get(list_throttled))
+        .layer(
+            ServiceBuilder::new()
+                .layer(Extension(ApiContextRateLimit { rate_limiter }))
+                .layer(session_layer)
+                .layer(axum::middleware::from_fn(is_admin)),
+        )
+}
+
+async fn list_throttled(
+    ctx: Extension<ApiContextRateLimit>,
+) -> ApiResult<Json<Vec<ThrottledClient>>> {
+    Ok(Json(ctx.rate_limiter.throttled_clients()))
+}
@@ -1,4 +1,4 @@
-use crate::web::util::{is_admin, ApiContext, ApiResult};
+use crate::web::util::{is_admin, Actor, ApiContext, ApiResult};
 use axum::{
     extract::Path,
     routing::{delete, get},
@@ -6,10 +6,12 @@ use axum::{
 };
 use axum_sessions::{async_session::MemoryStore, SessionLayer};
 use hmdl_db::dao::{
+    audit::{self, Change},
     roles::Roles,
     users::{self, User},
 };
-use sqlx::SqlitePool;
+use serde::Serialize;
+use sqlx::{Acquire, SqlitePool};
 use strum::IntoEnumIterator;
 use tower::ServiceBuilder;
 
@@ -38,22 +40,59 @@ async fn list_users(ctx: Extension<ApiContext>) -> ApiResult<Json<Vec<User>>> {
     Ok(Json(users))
 }
 
-async fn delete_user(ctx: Extension<ApiContext>, Path(name): Path<String>) -> ApiResult<Json<()>> {
+async fn delete_user(
+    ctx: Extension<ApiContext>,
+    Actor(actor): Actor,
+    Path(name): Path<String>,
+) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    users::delete(&mut conn, &name).await?;
+    let before = users::find_by_name(&mut tran, &name).await?;
+    users::delete(&mut tran, &name).await?;
+
+    let change = Change::new("user.delete", &name).before(before.as_ref().map(AuditedUser::from));
+    audit::record(&mut tran, &actor, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
 
 async fn update_user(
     ctx: Extension<ApiContext>,
+    Actor(actor): Actor,
     Path(name): Path<String>,
     Json(user): Json<User>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
+
+    let before = users::find_by_name(&mut tran, &name).await?;
+    users::update(&mut tran, &name, &user).await?;
 
-    users::update(&mut conn, &name, &user).await?;
+    let change = Change::new("user.update", &name)
+        .before(before.as_ref().map(AuditedUser::from))
+        .after(AuditedUser::from(&user));
+    audit::record(&mut tran, &actor, &change).await?;
+    tran.commit().await?;
 
     Ok(Json(()))
 }
+
+/// Passkeys are kept out of the audit log, only how many there were
+#[derive(Serialize)]
+struct AuditedUser<'a> {
+    display_name: &'a str,
+    role: &'a Roles,
+    passkeys: usize,
+}
+
+impl<'a> From<&'a User> for AuditedUser<'a> {
+    fn from(user: &'a User) -> Self {
+        Self {
+            display_name: &user.display_name,
+            role: &user.role,
+            passkeys: user.keys.len(),
+        }
+    }
+}
@@ -1,50 +1,84 @@
 use super::endpoints::health;
-use crate::coordinator::SetupStatus;
+use crate::coordinator::{
+    latest, serve_each, serve_on, BoundSockets, Health, ListenConfig, ListenError, SetupStatus,
+    Shutdown,
+};
 use axum::{handler::Handler, response::Redirect, BoxError, Router};
 use hyper::{StatusCode, Uri};
 use sqlx::SqlitePool;
-use std::{
-    io,
-    net::{IpAddr, Ipv6Addr, SocketAddr},
-};
+use std::{collections::HashSet, io, net::IpAddr};
 use thiserror::Error;
-use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
+use tokio::{
+    sync::{
+        broadcast::Sender,
+        watch::{self, error::RecvError},
+    },
+    task::JoinError,
+};
 
 pub mod setup;
 
-pub const HTTP_PORT: u16 = 80;
-
 pub struct InstallEndpoints {
     pool: SqlitePool,
+    health: Health,
+    listen: ListenConfig,
+    bound: Option<BoundSockets>,
 }
 
 impl InstallEndpoints {
-    pub fn create(pool: SqlitePool) -> Self {
-        Self { pool }
+    pub fn create(
+        pool: SqlitePool,
+        health: Health,
+        listen: ListenConfig,
+        bound: Option<BoundSockets>,
+    ) -> Self {
+        Self {
+            pool,
+            health,
+            listen,
+            bound,
+        }
     }
 
     pub async fn start(
         &self,
-        mut install_stat_reciever: Receiver<SetupStatus>,
+        mut install_stat_reciever: watch::Receiver<Option<SetupStatus>>,
         install_refresh_sender: Sender<()>,
+        ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+        mut shutdown: Shutdown,
     ) -> Result<(), InstallEndpointsError> {
         tracing::debug!("Checking for setup status.");
 
-        let mut status = install_stat_reciever.recv().await?;
+        let mut status = latest(&mut install_stat_reciever).await?;
 
         if matches!(status, SetupStatus::NotSetup | SetupStatus::InProgress(_)) {
-            tracing::info!("HTTP Install Server listening on {}", HTTP_PORT);
-            let app_service =
-                Self::create_router(self.pool.clone(), install_refresh_sender.clone());
+            tracing::info!(
+                "HTTP Install Server starting on port {}",
+                self.listen.http_port
+            );
+            let app_service = Self::create_router(
+                self.pool.clone(),
+                install_refresh_sender.clone(),
+                self.health.clone(),
+            );
 
-            let http_handle = tokio::spawn(async {
-                let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), HTTP_PORT);
-                let builder = axum_server::bind(addr);
-                builder.serve(app_service.into_make_service()).await
-            });
+            let http_handle = tokio::spawn(Self::serve(
+                self.listen.clone(),
+                self.bound_sockets()?,
+                ip_changed.clone(),
+                shutdown.clone(),
+                app_service,
+            ));
 
             loop {
-                status = install_stat_reciever.recv().await?;
+                tokio::select! {
+                    r = install_stat_reciever.changed() => r?,
+                    _ = shutdown.requested() => {
+                        http_handle.await??;
+                        return Ok(());
+                    }
+                }
+                status = latest(&mut install_stat_reciever).await?;
                 if matches!(status, SetupStatus::Setup(_)) {
                     http_handle.abort();
                     break;
@@ -55,14 +89,15 @@ impl InstallEndpoints {
         //Now we know the server is setup, switch to a redirect server
         if let SetupStatus::Setup(settings) = status {
             tracing::info!(
-                "HTTP Redirect Server listening on {} for {}",
-                HTTP_PORT,
+                "HTTP Redirect Server starting on port {} for {}",
+                self.listen.http_port,
                 settings.application_domain
             );
 
             let host = settings.application_domain.clone();
+            let https_port = self.listen.https_port;
             let redirect = move |uri: Uri| async move {
-                match Self::make_https(host, uri) {
+                match Self::make_https(host, https_port, uri) {
                     Ok(uri) => Ok(Redirect::permanent(&uri.to_string())),
                     Err(error) => {
                         tracing::warn!(%error, "failed to convert URI to HTTPS");
@@ -71,17 +106,68 @@ impl InstallEndpoints {
                 }
             };
 
-            let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), HTTP_PORT);
-            let builder = axum_server::bind(addr);
-            builder.serve(redirect.into_make_service()).await?;
+            let app = Router::new().fallback(redirect.into_service());
+            Self::serve(
+                self.listen.clone(),
+                self.bound_sockets()?,
+                ip_changed,
+                shutdown,
+                app,
+            )
+            .await?;
         }
         Ok(())
     }
 
-    fn create_router(pool: SqlitePool, install_refresh_sender: Sender<()>) -> Router {
+    /// The listeners are cloned since the install and redirect servers take turns on them
+    fn bound_sockets(&self) -> Result<Option<BoundSockets>, io::Error> {
+        self.bound.as_ref().map(|a| a.try_clone()).transpose()
+    }
+
+    async fn serve(
+        listen: ListenConfig,
+        bound: Option<BoundSockets>,
+        ip_changed: watch::Receiver<Option<HashSet<IpAddr>>>,
+        shutdown: Shutdown,
+        app: Router,
+    ) -> Result<(), InstallEndpointsError> {
+        if let Some(bound) = bound {
+            return serve_each(bound.tcp, |listener| {
+                let builder = axum_server::from_tcp(listener).handle(shutdown.axum_handle());
+                let app = app.clone();
+                async move {
+                    builder.serve(app.into_make_service()).await?;
+                    Ok(())
+                }
+            })
+            .await;
+        }
+
+        serve_on(
+            &listen,
+            listen.http_port,
+            ip_changed,
+            shutdown.clone(),
+            |addr| {
+                let builder = axum_server::bind(addr).handle(shutdown.axum_handle());
+                let app = app.clone();
+                async move {
+                    builder.serve(app.into_make_service()).await?;
+                    Ok(())
+                }
+            },
+        )
+        .await
+    }
+
+    fn create_router(
+        pool: SqlitePool,
+        install_refresh_sender: Sender<()>,
+        health: Health,
+    ) -> Router {
         let mut app = Router::new().fallback(fallback.into_service());
 
-        app = app.merge(health::router());
+        app = app.merge(health::router(pool.clone(), health));
         app = app.merge(setup::router(pool, install_refresh_sender));
 
         //Only enable embedded static content if we're in release mode
@@ -97,7 +183,8 @@ impl InstallEndpoints {
         app
     }
 
-    fn make_https(host: String, uri: Uri) -> Result<Uri, BoxError> {
+    /// The port is left off when it is the default so the URL stays the one people type
+    fn make_https(host: String, https_port: u16, uri: Uri) -> Result<Uri, BoxError> {
         let mut parts = uri.into_parts();
 
         parts.scheme = Some(axum::http::uri::Scheme::HTTPS);
@@ -106,7 +193,10 @@ impl InstallEndpoints {
             parts.path_and_query = Some("/".parse().unwrap());
         }
 
-        parts.authority = Some(host.parse()?);
+        parts.authority = Some(match https_port {
+            443 => host.parse()?,
+            port => format!("{}:{}", host, port).parse()?,
+        });
 
         Ok(Uri::from_parts(parts)?)
     }
@@ -121,6 +211,12 @@ pub enum InstallEndpointsError {
     #[error(transparent)]
     Io(#[from] io::Error),
 
+    #[error(transparent)]
+    Join(#[from] JoinError),
+
+    #[error(transparent)]
+    Listen(#[from] ListenError),
+
     #[error(transparent)]
     Recv(#[from] RecvError),
     /*#[error(transparent)]
@@ -147,10 +243,14 @@ mod tests {
     #[test]
     fn test_make_https() -> Result<(), Box<dyn std::error::Error>> {
         let test_uri = Uri::from_static("http://localhost/api/is-setup");
-        let new_uri = InstallEndpoints::make_https("https.pvt".to_string(), test_uri).unwrap();
+        let new_uri =
+            InstallEndpoints::make_https("https.pvt".to_string(), 443, test_uri.clone()).unwrap();
 
         assert_eq!("https://https.pvt/api/is-setup", new_uri.to_string());
 
+        let new_uri = InstallEndpoints::make_https("https.pvt".to_string(), 8443, test_uri)?;
+        assert_eq!("https://https.pvt:8443/api/is-setup", new_uri.to_string());
+
         Ok(())
     }
 }
@@ -3,8 +3,13 @@ use axum::{
     routing::{get, post},
     Extension, Json, Router,
 };
+use hmdl_db::dao::{
+    audit::{self, Change},
+    settings,
+};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, SqlitePool};
+use serde_json::json;
+use sqlx::{Acquire, SqlitePool};
 use tokio::sync::broadcast::Sender;
 use tower::builder::ServiceBuilder;
 
@@ -26,19 +31,7 @@ struct SetupStatusResp {
 
 // Api path confirming that the application is not setup
 async fn is_setup(ctx: Extension<ApiContextSetup>) -> ApiResult<Json<SetupStatusResp>> {
-    let mut conn = ctx.pool.acquire().await?;
-
-    let setting_record = query!(
-        r#"
-        SELECT application_domain, https_started_once
-        FROM hmdl_settings
-        WHERE lock_column == true
-        "#
-    )
-    .fetch_optional(&mut conn)
-    .await?;
-
-    let status = match setting_record {
+    let status = match settings::find(&ctx.pool).await? {
         None => SetupStatusResp {
             status: "Not Setup".to_string(),
             domain: None,
@@ -70,35 +63,24 @@ async fn add_setup(
     Json(setup): Json<HmdlSetup>,
 ) -> ApiResult<Json<()>> {
     let mut conn = ctx.pool.acquire().await?;
+    let mut tran = conn.begin().await?;
 
-    query!(
-        r#"
-        INSERT OR REPLACE INTO hmdl_settings (
-            application_domain, 
-            cloudflare_api_token,
-            acme_email,
-            https_started_once,
-            lock_column
-        ) VALUES (
-            ?1,
-            ?2,
-            ?3,
-            false,
-            true
-        ) ON CONFLICT (lock_column) 
-        DO UPDATE 
-        SET 
-            application_domain = ?1,
-            cloudflare_api_token = ?2,
-            acme_email = ?3
-        "#,
-        setup.application_domain,
-        setup.cloudflare_api_token,
-        setup.acme_email
+    settings::save(
+        &mut tran,
+        &setup.application_domain,
+        &setup.cloudflare_api_token,
+        &setup.acme_email,
     )
-    .execute(&mut conn)
     .await?;
 
+    // No one is logged in before setup, and the api token stays out of the log
+    let change = Change::new("setup.save", &setup.application_domain).after(json!({
+        "application_domain": setup.application_domain,
+        "acme_email": setup.acme_email,
+    }));
+    audit::record(&mut tran, "installer", &change).await?;
+    tran.commit().await?;
+
     tracing::info!("Setup Complete, switching into in progress mode");
     ctx.install_refresh_sender.send(())?;
 
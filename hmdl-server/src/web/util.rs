@@ -1,6 +1,10 @@
+mod actor;
+pub use actor::Actor;
+
 mod api_context;
 pub use api_context::ApiContext;
 pub use api_context::ApiContextAuth;
+pub use api_context::ApiContextRateLimit;
 pub use api_context::ApiContextSetup;
 
 mod api_error;
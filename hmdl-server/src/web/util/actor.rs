@@ -0,0 +1,23 @@
+use axum::extract::{FromRequest, RequestParts};
+use axum_sessions::extractors::ReadableSession;
+use hmdl_db::dao::users::User;
+
+use super::ApiError;
+use crate::web::endpoints::authentication::USER;
+
+/// The signed in user making a change, recorded in the audit log. Routes using it sit
+/// behind `is_admin` so a missing user only happens if the session went away mid request.
+pub struct Actor(pub String);
+
+#[axum::async_trait]
+impl<B: Send> FromRequest<B> for Actor {
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let session = ReadableSession::from_request(req)
+            .await
+            .map_err(|_| ApiError::Unauthorized)?;
+        let user: User = session.get(USER).ok_or(ApiError::Unauthorized)?;
+        Ok(Self(user.display_name))
+    }
+}
@@ -1,3 +1,4 @@
+use crate::dns::RateLimiter;
 use sqlx::SqlitePool;
 use std::sync::Arc;
 use tokio::sync::broadcast::Sender;
@@ -19,3 +20,8 @@ pub struct ApiContextAuth {
     pub pool: SqlitePool,
     pub webauthn: Arc<Webauthn>,
 }
+
+#[derive(Clone)]
+pub struct ApiContextRateLimit {
+    pub rate_limiter: Arc<RateLimiter>,
+}
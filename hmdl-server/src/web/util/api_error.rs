@@ -6,7 +6,7 @@ use axum::{
     response::IntoResponse,
     Json,
 };
-use hmdl_db::dao::users::UserError;
+use hmdl_db::dao::{snapshots::SnapshotError, users::UserError};
 use sqlx::error::DatabaseError;
 use std::{borrow::Cow, collections::HashMap};
 use tokio::sync::broadcast::error::SendError;
@@ -47,6 +47,8 @@ pub enum ApiError {
     #[error("Had an internal server error: Send")]
     Send(#[from] SendError<()>),
     #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
     User(#[from] UserError),
     #[error("Had an internal server error: WebAuth")]
     WebAuthn(#[from] WebauthnError),
@@ -116,6 +118,7 @@ impl ApiError {
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Authenticaion(_)
             | Self::Send(_)
+            | Self::Snapshot(_)
             | Self::User(_)
             | Self::WebAuthn(_)
             | Self::Sqlx(_)